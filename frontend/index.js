@@ -1,4 +1,4 @@
-import { Console } from "wasm-chip-8";
+import { Console, Lockstep, MachineStatus } from "wasm-chip-8";
 import { memory } from "wasm-chip-8/chip_8_wasm_bg"
 
 
@@ -22,7 +22,6 @@ const ROMS = [
     'IBM',
     'KALEID',
     'MERLIN',
-    'PONG',
     'PUZZLE',
     'TANK',
     'TICTAC',
@@ -30,29 +29,18 @@ const ROMS = [
     'WIPEOFF'
 ];
 
-// Maps 4 keys for each row from 1 to v
-const KEYMAP = {
-    49: 0x0, // 1
-    50: 0x1, // 2
-    51: 0x2, // 3
-    52: 0x3, // 4
-    81: 0x4, // Q
-    87: 0x5, // W
-    69: 0x6, // E
-    82: 0x7, // R
-    65: 0x8, // A
-    83: 0x9, // S
-    68: 0xa, // D
-    70: 0xb, // F
-    90: 0xc, // Z
-    88: 0xd, // X
-    67: 0xe, // C
-    86: 0xf  // V
-}
+// Key bindings are kept in Rust, saved here between visits
+const INPUT_CONFIG_KEY = 'chip-8-input';
+
+// Speed multipliers, 1 is real time
+const SPEEDS = [0.25, 0.5, 1, 2, 4, 10];
 
-const PIXEL_SIZE = 8;
-const DISPLAY_WIDTH = 64;
-const DISPLAY_HEIGHT = 32;
+// Both netplay peers seed CXNN alike and schedule input three frames ahead
+const NETPLAY_SEED = 42n;
+const NETPLAY_INPUT_DELAY = 3;
+
+// Canvas size in screen pixels, hi-res platforms get smaller pixels
+const SCREEN_WIDTH = 512;
 const PIXEL_ON_COLOR = "#FFFFFF";
 const PIXEL_OFF_COLOR = "#000000";
 
@@ -61,29 +49,37 @@ async function run() {
 
     const machine = Console.new();
     let machineRunning = false;
+    let lastFrameTime = null;
+    let pixelOnColor = PIXEL_ON_COLOR;
+    let pixelOffColor = PIXEL_OFF_COLOR;
+    let lastRom = null;  // Bytes of the last ROM or cartridge, for Reset
+    let netplay = null;  // Relay socket and lockstep session while connected
 
     function updateDisplay() {
+        const width = machine.width();
+        const height = machine.height();
         const displayMemory = new Uint8Array(
             memory.buffer,
             machine.get_vram(),
-            2048
+            width * height
         );
 
-        draw(displayMemory);
+        draw(displayMemory, width, height);
     }
 
-    function draw(displayMemory) {
+    function draw(displayMemory, width, height) {
+        const PIXEL_SIZE = SCREEN_WIDTH / width;
         const canvas = document.getElementById("chip-8-screen");
-        canvas.width = PIXEL_SIZE * DISPLAY_WIDTH;
-        canvas.height = PIXEL_SIZE * DISPLAY_HEIGHT;
+        canvas.width = PIXEL_SIZE * width;
+        canvas.height = PIXEL_SIZE * height;
         const ctx = canvas.getContext('2d');
         ctx.beginPath();
 
-        for (let y = 0; y < DISPLAY_HEIGHT; y++) {
-            for (let x = 0; x < DISPLAY_WIDTH; x++ ) {
-                const idx = y * DISPLAY_WIDTH + x;
+        for (let y = 0; y < height; y++) {
+            for (let x = 0; x < width; x++ ) {
+                const idx = y * width + x;
 
-                ctx.fillStyle = displayMemory[idx] === 1 ? PIXEL_ON_COLOR : PIXEL_OFF_COLOR;
+                ctx.fillStyle = displayMemory[idx] === 1 ? pixelOnColor : pixelOffColor;
                 ctx.fillRect(
                     x * PIXEL_SIZE,
                     y * PIXEL_SIZE,
@@ -96,27 +92,60 @@ async function run() {
         ctx.stroke();
     }
 
+    function updateKeypad() {
+        const keys = machine.pressed_keys();
+
+        for (let key = 0; key < 16; key++) {
+            const held = (keys >> key) & 1;
+            const keyHtml = document.getElementById("keycode-" + String(key));
+            keyHtml.style.backgroundColor = held ? "black" : null;
+            keyHtml.style.color = held ? "white" : null;
+        }
+    }
+
+    // The Gamepad API has no events for buttons, read them every frame
+    function pollGamepads() {
+        for (const gamepad of navigator.getGamepads()) {
+            if (!gamepad) continue;
+
+            const buttons = Float64Array.from(gamepad.buttons, b => b.value);
+            machine.update_gamepad(gamepad.index, buttons, Float64Array.from(gamepad.axes));
+        }
+        updateKeypad();
+    }
+
     function initKeypad() {
+        const saved = window.localStorage.getItem(INPUT_CONFIG_KEY);
+        if (saved !== null) {
+            try {
+                machine.load_input_config(saved);
+            } catch (e) {
+                console.warn("Ignoring saved key bindings: " + e);
+            }
+        }
+
         window.addEventListener('keydown', e => {
-            let machineKey = KEYMAP[e.keyCode]
-
-            if (machineKey !== null) {
-                machine.press_key(machineKey)
-                let keyHtml = document.getElementById("keycode-" + String(machineKey))
-                keyHtml.style.backgroundColor = "black";
-                keyHtml.style.color = "white";
-           }
+            machine.host_key_down("keyboard", e.code);
+            updateKeypad();
         })
 
         window.addEventListener('keyup', e => {
-            let machineKey = KEYMAP[e.keyCode]
+            machine.host_key_up("keyboard", e.code);
+            updateKeypad();
+        })
 
-            if (machineKey !== null) {
-                machine.release_key(KEYMAP[e.keyCode])
-                let keyHtml = document.getElementById("keycode-" + String(machineKey))
-                keyHtml.style.backgroundColor = null;
-                keyHtml.style.color = null;
-            }
+        window.addEventListener('gamepaddisconnected', e => {
+            machine.disconnect_gamepad(e.gamepad.index);
+            updateKeypad();
+        })
+
+        window.addEventListener('blur', () => {
+            machine.release_host_keys();
+            updateKeypad();
+        })
+
+        window.addEventListener('beforeunload', () => {
+            window.localStorage.setItem(INPUT_CONFIG_KEY, machine.input_config());
         })
     }
 
@@ -138,19 +167,64 @@ async function run() {
             loadRom(romSelector.value)
         });
 
+        let romFile = document.getElementById('rom-file');
+
+        romFile.addEventListener('change', () => {
+            const file = romFile.files[0];
+            if (!file) return
+
+            machineRunning = false;
+            machine.reset();
+            file.arrayBuffer().then(buffer => {
+                const cartridge = file.name.toLowerCase().endsWith('.gif');
+                loadBytes(new Uint8Array(buffer), cartridge);
+            });
+        });
+
+        let speedSelector = document.getElementById('speed-select');
+
+        SPEEDS.forEach(s => {
+            let opt = document.createElement('option');
+            opt.value = s;
+            opt.innerHTML = `${s}x`;
+            opt.selected = s === 1;
+            speedSelector.appendChild(opt)
+        });
+
+        speedSelector.addEventListener('change', () => {
+            machine.set_speed_multiplier(Number(speedSelector.value));
+        });
+
         let playPauseButton = document.getElementById('run');
         let stepButton = document.getElementById('step');
+        let frameButton = document.getElementById('frame');
         let resetButton = document.getElementById('reset');
 
         stepButton.addEventListener("click", e => {if (!machineRunning) {tick()}})
-        resetButton.addEventListener("click", e => {machine.reset()})
+        frameButton.addEventListener("click", e => {if (!machineRunning) {advanceFrame()}})
+        resetButton.addEventListener("click", e => {
+            machine.reset();
+            if (lastRom) {
+                loadBytes(lastRom.bytes, lastRom.cartridge);
+            }
+            updateStatus();
+        })
 
         playPauseButton.addEventListener("click", e => {
             if (machineRunning) {
                 machineRunning = false
             } else {
-                machineRunning = true;
-                window.requestAnimationFrame(runLoop);
+                startRunning();
+            }
+        });
+
+        let netplayButton = document.getElementById('netplay');
+
+        netplayButton.addEventListener("click", e => {
+            if (netplay) {
+                netplay.socket.close();
+            } else {
+                startNetplay(document.getElementById('relay-address').value);
             }
         });
 
@@ -163,15 +237,100 @@ async function run() {
 
         fetch(`roms/${rom_name}`)
         .then(i => i.arrayBuffer())
-        .then(buffer => {
-            let rom = new Uint8Array(buffer)
-            machine.load_rom(rom)
+        .then(buffer => loadBytes(new Uint8Array(buffer), false));
+    }
+
+    // Load a ROM or an Octo cartridge GIF and remember it for Reset
+    function loadBytes(bytes, cartridge) {
+        lastRom = { bytes, cartridge };
+
+        try {
+            if (cartridge) {
+                machine.load_cartridge(bytes)
+            } else {
+                machine.load_rom(bytes)
+            }
+        } catch (e) {
+            document.getElementById('status').innerHTML = e;
+        }
+
+        applyRomMetadata();
+    }
+
+    // Use the ROM database's colours when it has them
+    function applyRomMetadata() {
+        const metadata = JSON.parse(machine.rom_metadata() || 'null');
+        const pixels = metadata && metadata.colors ? metadata.colors.pixels : [];
+
+        pixelOffColor = pixels[0] || PIXEL_OFF_COLOR;
+        pixelOnColor = pixels[1] || PIXEL_ON_COLOR;
+        updateDisplay();
+    }
+
+    function startRunning() {
+        machineRunning = true;
+        lastFrameTime = null;
+        window.requestAnimationFrame(runLoop);
+    }
+
+    // Play the current ROM against whoever connects to the relay next,
+    // both players have to pick the same ROM
+    function startNetplay(address) {
+        const socket = new WebSocket(address);
+        socket.binaryType = 'arraybuffer';
+        const button = document.getElementById('netplay');
+        button.innerHTML = 'DISCONNECT';
+
+        socket.addEventListener('open', () => {
+            machine.reset();
+            if (lastRom) {
+                loadBytes(lastRom.bytes, lastRom.cartridge);
+            }
+            machine.seed_rng(NETPLAY_SEED);
+            netplay = { socket, session: Lockstep.new(NETPLAY_INPUT_DELAY) };
+            startRunning();
+        });
+
+        socket.addEventListener('message', e => {
+            try {
+                netplay.session.receive(new Uint8Array(e.data));
+            } catch (err) {
+                console.warn("Ignoring netplay packet: " + err);
+            }
+        });
+
+        socket.addEventListener('close', () => {
+            if (netplay) {
+                netplay.session.free();
+                netplay = null;
+                machineRunning = false;
+            }
+            button.innerHTML = 'CONNECT';
+            updateStatus();
         });
     }
 
-    function runLoop() {
+    // Frames run once the peer's input for them is in, at most one per
+    // animation frame
+    function runNetplayFrame() {
+        const packet = netplay.session.local_input(machine.host_keys());
+        if (packet) {
+            netplay.socket.send(packet);
+        }
+        return netplay.session.advance(machine);
+    }
+
+    function runLoop(timestamp) {
         if (machineRunning) {
-            tick()
+            const elapsed = lastFrameTime === null ? 0 : timestamp - lastFrameTime;
+            lastFrameTime = timestamp;
+
+            pollGamepads();
+            if (netplay ? runNetplayFrame() : machine.run(elapsed) > 0) {
+                updateDisplay();
+            }
+
+            updateStatus();
             window.requestAnimationFrame(runLoop)
         }
     }
@@ -179,6 +338,39 @@ async function run() {
     function tick() {
         machine.tick()
         updateDisplay();
+        updateStatus();
+    }
+
+    function updateStatus() {
+        const status = document.getElementById('status');
+
+        const desync = netplay ? netplay.session.desync_frame() : undefined;
+        if (desync !== undefined) {
+            status.innerHTML = `DESYNCED AT FRAME ${desync}`;
+            return;
+        }
+
+        switch (machine.status()) {
+            case MachineStatus.WaitingForKey:
+                status.innerHTML = 'WAITING FOR KEY';
+                break;
+            case MachineStatus.WaitingForTimer:
+                status.innerHTML = 'WAITING FOR TIMER';
+                break;
+            case MachineStatus.Halted:
+                status.innerHTML = 'HALTED';
+                break;
+            case MachineStatus.Errored:
+                status.innerHTML = machine.error_message();
+                break;
+            default:
+                status.innerHTML = '';
+        }
+    }
+
+    function advanceFrame() {
+        machine.advance_frame()
+        updateDisplay();
     }
 
     init();
@@ -0,0 +1,389 @@
+//! Static control flow analysis of ROMs
+//!
+//! Follows every path from the entry point through jumps, calls, skips
+//! and returns to split a ROM into code and data. BNNN targets depend on
+//! V0 at run time so they end the walk and are reported instead
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+use crate::cpu::PROG_START;
+use crate::instruction::Instruction;
+
+
+// How control leaves a basic block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    Fallthrough(u16),                    // Next instruction starts a new block
+    Jump(u16),
+    Call { target: u16, ret: u16 },
+    Skip { next: u16, skipped: u16 },
+    Return,
+    DynamicJump { base: u16 },           // BNNN, target unknown
+    Halt,                                // Jump to self
+    Invalid(u16),                        // Opcode this interpreter can't run
+    EndOfRom,                            // Ran off the end of the ROM
+}
+
+
+impl Exit {
+
+    // Addresses control can reach in the same function
+    pub fn successors(&self) -> Vec<u16> {
+        match *self {
+            Exit::Fallthrough(next) | Exit::Jump(next) => vec![next],
+            Exit::Call { ret, .. } => vec![ret],
+            Exit::Skip { next, skipped } => vec![next, skipped],
+            _ => Vec::new(),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, u16)>,  // Address and opcode
+    pub exit: Exit,
+}
+
+
+impl BasicBlock {
+
+    // First address after the block, 0x10000 for a block that runs to
+    // the end of memory
+    pub fn end(&self) -> u32 {
+        self.instructions.last().map_or(self.start as u32, |&(addr, _)| addr as u32 + 2)
+    }
+}
+
+
+// A subroutine, or the main program, and the blocks reachable from it
+// without following calls
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub entry: u16,
+    pub blocks: Vec<u16>,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cfg {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub functions: Vec<Function>,
+    pub unreachable: Vec<RangeInclusive<u16>>,  // Bytes never reached, likely data
+    pub dynamic_jumps: Vec<u16>,       // Addresses of unresolved BNNN
+}
+
+
+// Analyse a ROM loaded at PROG_START
+pub fn analyze(rom: &[u8]) -> Cfg {
+    analyze_at(rom, PROG_START)
+}
+
+
+pub fn analyze_at(rom: &[u8], load_address: u16) -> Cfg {
+    let end = load_address as usize + rom.len();
+    let opcode_at = |addr: u16| -> Option<u16> {
+        let offset = (addr as usize).checked_sub(load_address as usize)?;
+        if addr as usize + 2 > end {
+            return None;
+        }
+        Some(u16::from_be_bytes([rom[offset], rom[offset + 1]]))
+    };
+
+    // Find every reachable instruction and where each can go next
+    let mut exits: BTreeMap<u16, Exit> = BTreeMap::new();
+    let mut opcodes: BTreeMap<u16, u16> = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut function_entries = BTreeSet::new();
+    let mut dynamic_jumps = Vec::new();
+    let mut worklist = vec![load_address];
+
+    leaders.insert(load_address);
+    function_entries.insert(load_address);
+
+    while let Some(addr) = worklist.pop() {
+        if opcodes.contains_key(&addr) || exits.contains_key(&addr) {
+            continue;
+        }
+
+        let opcode = match opcode_at(addr) {
+            Some(opcode) => opcode,
+            None => {
+                exits.insert(addr, Exit::EndOfRom);
+                continue;
+            }
+        };
+        opcodes.insert(addr, opcode);
+
+        let next = addr.wrapping_add(2);
+        let exit = match Instruction::decode(opcode) {
+            Instruction::Op1nnn(nnn) if nnn == addr => Exit::Halt,
+            Instruction::Op1nnn(nnn) => Exit::Jump(nnn),
+            Instruction::Op2nnn(nnn) => {
+                function_entries.insert(nnn);
+                worklist.push(nnn);
+                Exit::Call { target: nnn, ret: next }
+            }
+            Instruction::Op00ee => Exit::Return,
+            Instruction::Op3xnn(..) | Instruction::Op4xnn(..)
+            | Instruction::Op5xy0(..) | Instruction::Op9xy0(..)
+            | Instruction::Opex9e(_) | Instruction::Opexa1(_)
+            | Instruction::Opexf2(_) | Instruction::Opexf5(_) =>
+                Exit::Skip { next, skipped: next.wrapping_add(2) },
+            Instruction::Opbnnn(_, nnn) => {
+                dynamic_jumps.push(addr);
+                Exit::DynamicJump { base: nnn }
+            }
+            Instruction::Unknown(_) => Exit::Invalid(opcode),
+            // Machine code calls are skipped over like the Cpu does
+            _ => Exit::Fallthrough(next),
+        };
+
+        let successors = exit.successors();
+        if exit != Exit::Fallthrough(next) {
+            leaders.extend(successors.iter().copied());
+            if let Exit::Call { target, .. } = exit {
+                leaders.insert(target);
+            }
+        }
+        worklist.extend(successors);
+        exits.insert(addr, exit);
+    }
+
+    // Cut the instruction stream into blocks at leaders and branches
+    let mut blocks = BTreeMap::new();
+    let mut starts: Vec<u16> = leaders.iter().copied()
+        .filter(|addr| exits.contains_key(addr))
+        .collect();
+
+    while let Some(start) = starts.pop() {
+        if blocks.contains_key(&start) {
+            continue;
+        }
+
+        let mut block = BasicBlock { start, instructions: Vec::new(), exit: Exit::EndOfRom };
+        let mut addr = start;
+        loop {
+            let exit = exits[&addr];
+            if let Some(&opcode) = opcodes.get(&addr) {
+                block.instructions.push((addr, opcode));
+            }
+
+            match exit {
+                Exit::Fallthrough(next) if !leaders.contains(&next) => addr = next,
+                Exit::Fallthrough(next) => {
+                    block.exit = exit;
+                    starts.push(next);
+                    break;
+                }
+                _ => {
+                    block.exit = exit;
+                    break;
+                }
+            }
+        }
+
+        blocks.insert(start, block);
+    }
+
+    let functions = function_entries.iter()
+        .filter(|entry| blocks.contains_key(entry))
+        .map(|&entry| Function { entry, blocks: reachable_blocks(&blocks, entry) })
+        .collect();
+
+    // Bytes of the ROM no reachable instruction covers
+    let mut covered = vec![false; rom.len()];
+    for &addr in opcodes.keys() {
+        for byte in addr as usize..addr as usize + 2 {
+            covered[byte - load_address as usize] = true;
+        }
+    }
+
+    let mut unreachable = Vec::new();
+    let mut offset = 0;
+    while offset < covered.len() {
+        if covered[offset] {
+            offset += 1;
+            continue;
+        }
+        let run_start = offset;
+        while offset < covered.len() && !covered[offset] {
+            offset += 1;
+        }
+        // The last run can end on 0xFFFF, keep the sums out of u16
+        let first = load_address as usize + run_start;
+        let last = load_address as usize + offset - 1;
+        unreachable.push(first as u16..=last.min(0xFFFF) as u16);
+    }
+
+    dynamic_jumps.sort_unstable();
+
+    Cfg { entry: load_address, blocks, functions, unreachable, dynamic_jumps }
+}
+
+
+fn reachable_blocks(blocks: &BTreeMap<u16, BasicBlock>, entry: u16) -> Vec<u16> {
+    let mut seen = BTreeSet::new();
+    let mut worklist = vec![entry];
+
+    while let Some(start) = worklist.pop() {
+        if let Some(block) = blocks.get(&start) {
+            if seen.insert(start) {
+                worklist.extend(block.exit.successors());
+            }
+        }
+    }
+
+    seen.into_iter().collect()
+}
+
+
+impl Cfg {
+
+    // Whether addr holds the first byte of a reachable instruction
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.block_containing(addr)
+            .is_some_and(|block| block.instructions.iter().any(|&(a, _)| a == addr))
+    }
+
+    pub fn block_containing(&self, addr: u16) -> Option<&BasicBlock> {
+        self.blocks.range(..=addr)
+            .rev()
+            .map(|(_, block)| block)
+            .find(|block| (addr as u32) < block.end())
+    }
+
+    // Graphviz DOT, one box per block with calls drawn dashed
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for &(addr, opcode) in &block.instructions {
+                let _ = write!(label, "{:03X}  {:04X}  {:?}\\l", addr, opcode,
+                               Instruction::decode(opcode));
+            }
+            match block.exit {
+                Exit::DynamicJump { base } => {
+                    let _ = write!(label, "-> V0 + {:03X}\\l", base);
+                }
+                Exit::EndOfRom => label.push_str("end of ROM\\l"),
+                _ => {}
+            }
+            let _ = writeln!(dot, "    b{:03X} [label=\"{}\"];", block.start, label);
+        }
+
+        for block in self.blocks.values() {
+            let from = block.start;
+            match block.exit {
+                Exit::Fallthrough(to) | Exit::Jump(to) => {
+                    let _ = writeln!(dot, "    b{:03X} -> b{:03X};", from, to);
+                }
+                Exit::Call { target, ret } => {
+                    let _ = writeln!(dot, "    b{:03X} -> b{:03X} [style=dashed label=call];", from, target);
+                    let _ = writeln!(dot, "    b{:03X} -> b{:03X};", from, ret);
+                }
+                Exit::Skip { next, skipped } => {
+                    let _ = writeln!(dot, "    b{:03X} -> b{:03X};", from, next);
+                    let _ = writeln!(dot, "    b{:03X} -> b{:03X} [label=skip];", from, skipped);
+                }
+                _ => {}
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_functions_and_data() {
+        let rom = [
+            0x22, 0x08,  // 200: call 208
+            0x30, 0x01,  // 202: skip if v0 == 1
+            0x12, 0x00,  // 204: jump 200
+            0x12, 0x06,  // 206: halt
+            0x60, 0x01,  // 208: v0 = 1
+            0x00, 0xEE,  // 20A: return
+            0xAB, 0xCD,  // 20C: data
+        ];
+        let cfg = analyze(&rom);
+
+        let starts: Vec<u16> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0x200, 0x202, 0x204, 0x206, 0x208]);
+        assert_eq!(cfg.blocks[&0x200].exit, Exit::Call { target: 0x208, ret: 0x202 });
+        assert_eq!(cfg.blocks[&0x202].exit, Exit::Skip { next: 0x204, skipped: 0x206 });
+        assert_eq!(cfg.blocks[&0x208].instructions.len(), 2);
+        assert_eq!(cfg.blocks[&0x208].exit, Exit::Return);
+
+        let entries: Vec<u16> = cfg.functions.iter().map(|f| f.entry).collect();
+        assert_eq!(entries, vec![0x200, 0x208]);
+        assert_eq!(cfg.functions[1].blocks, vec![0x208]);
+
+        assert_eq!(cfg.unreachable, vec![0x20C..=0x20D]);
+        assert!(cfg.is_code(0x20A));
+        assert!(!cfg.is_code(0x20C));
+    }
+
+    #[test]
+    fn test_machine_code_call_falls_through() {
+        let rom = [
+            0x01, 0x23,  // 200: call machine code at 123
+            0x12, 0x02,  // 202: halt
+        ];
+        let cfg = analyze(&rom);
+
+        assert!(cfg.unreachable.is_empty());
+        assert_eq!(cfg.blocks[&0x200].exit, Exit::Halt);
+        assert!(cfg.is_code(0x202));
+    }
+
+    #[test]
+    fn test_dynamic_jump_and_dot() {
+        let rom = [
+            0x60, 0x02,  // 200: v0 = 2
+            0xB2, 0x04,  // 202: jump 204 + v0
+            0x12, 0x04,  // 204: jump table
+            0x12, 0x06,
+        ];
+        let cfg = analyze(&rom);
+
+        assert_eq!(cfg.dynamic_jumps, vec![0x202]);
+        assert_eq!(cfg.unreachable, vec![0x204..=0x207]);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("-> V0 + 204"));
+    }
+
+    #[test]
+    fn test_data_to_the_end_of_memory() {
+        // A full size XO-CHIP ROM whose code is one jump over its data
+        let mut rom = vec![0xFF; 0x10000 - PROG_START as usize];
+        rom[..2].copy_from_slice(&[0x12, 0x02]);
+        rom[2..4].copy_from_slice(&[0x12, 0x02]);
+        let cfg = analyze(&rom);
+
+        assert_eq!(cfg.unreachable, vec![0x204..=0xFFFF]);
+        assert!(cfg.is_code(0x202));
+        assert!(!cfg.is_code(0xFFFE));
+
+        // Code right up to the last byte
+        let rom: Vec<u8> = [0x60, 0x00].iter().copied().cycle().take(rom.len()).collect();
+        let cfg = analyze(&rom);
+
+        assert!(cfg.unreachable.is_empty());
+        assert_eq!(cfg.blocks[&0x200].end(), 0x10000);
+        assert!(cfg.is_code(0xFFFE));
+        assert_eq!(cfg.block_containing(0xFFFF).map(|b| b.start), Some(0x200));
+    }
+}
@@ -0,0 +1,57 @@
+//! GDB stub
+//!
+//! Loads a ROM, or an Octo cartridge GIF, and waits for a debugger that
+//! speaks the GDB remote serial protocol, one connection at a time. The
+//! program doesn't run until a debugger continues it.
+//! Usage: gdbstub <rom> [address], 127.0.0.1:1234 by default
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process;
+
+use chip_8_wasm::cartridge::Cartridge;
+use chip_8_wasm::gdb::{self, GdbStub};
+use chip_8_wasm::machine::Console;
+
+
+fn fail(message: String) -> ! {
+    eprintln!("gdbstub: {}", message);
+    process::exit(1);
+}
+
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 2 {
+        fail("usage: gdbstub <rom> [address]".to_string());
+    }
+    let rom_path = &args[0];
+    let address = args.get(1).map(String::as_str).unwrap_or(gdb::DEFAULT_ADDRESS);
+
+    let rom = fs::read(rom_path).unwrap_or_else(|e| fail(format!("can't read {}: {}", rom_path, e)));
+    let mut console = Console::new();
+    let loaded = if rom_path.ends_with(".gif") {
+        Cartridge::decode(&rom)
+            .map_err(|e| e.to_string())
+            .and_then(|cartridge| console.insert_cartridge(&cartridge).map_err(|e| e.to_string()))
+    } else {
+        console.load_rom(&rom).map_err(|e| e.to_string())
+    };
+    if let Err(e) = loaded {
+        fail(format!("can't load {}: {}", rom_path, e));
+    }
+
+    let listener = TcpListener::bind(address)
+        .unwrap_or_else(|e| fail(format!("can't listen on {}: {}", address, e)));
+    println!("gdbstub: waiting for a debugger on {}", address);
+
+    let mut stub = GdbStub::new(console);
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| gdb::serve(stream, &mut stub));
+        match result {
+            Ok(()) => println!("gdbstub: debugger detached"),
+            Err(e) => eprintln!("gdbstub: {}", e),
+        }
+    }
+}
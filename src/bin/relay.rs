@@ -0,0 +1,28 @@
+//! Netplay relay
+//!
+//! Pairs up netplay peers as they connect and passes their input packets
+//! between them, so a lockstep session can be tried on one machine.
+//! Native peers connect over TCP, the web frontend at ws://address.
+//! Usage: relay [address], 127.0.0.1:7878 by default
+
+use std::env;
+use std::net::TcpListener;
+use std::process;
+
+use chip_8_wasm::netplay::relay;
+
+
+fn main() {
+    let address = env::args().nth(1).unwrap_or_else(|| relay::DEFAULT_ADDRESS.to_string());
+
+    let listener = TcpListener::bind(&address).unwrap_or_else(|e| {
+        eprintln!("relay: can't listen on {}: {}", address, e);
+        process::exit(1);
+    });
+    println!("relay: listening on {}", address);
+
+    if let Err(e) = relay::serve(&listener) {
+        eprintln!("relay: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,52 @@
+//! Script runner
+//!
+//! Loads a ROM, or an Octo cartridge GIF, and runs a Rhai test scenario
+//! against it, see `scripting` for what scripts can call. Exits with 1 when
+//! the script fails. Usage: chip8-script <rom> <script>
+
+use std::env;
+use std::fs;
+use std::process;
+
+use chip_8_wasm::cartridge::Cartridge;
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::scripting::Script;
+
+
+fn fail(message: String) -> ! {
+    eprintln!("chip8-script: {}", message);
+    process::exit(1);
+}
+
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        fail("usage: chip8-script <rom> <script>".to_string());
+    }
+    let (rom_path, script_path) = (&args[0], &args[1]);
+
+    let rom = fs::read(rom_path).unwrap_or_else(|e| fail(format!("can't read {}: {}", rom_path, e)));
+    let source = fs::read_to_string(script_path)
+        .unwrap_or_else(|e| fail(format!("can't read {}: {}", script_path, e)));
+
+    let mut console = Console::new();
+    let loaded = if rom_path.ends_with(".gif") {
+        Cartridge::decode(&rom)
+            .map_err(|e| e.to_string())
+            .and_then(|cartridge| console.insert_cartridge(&cartridge).map_err(|e| e.to_string()))
+    } else {
+        console.load_rom(&rom).map_err(|e| e.to_string())
+    };
+    if let Err(e) = loaded {
+        fail(format!("can't load {}: {}", rom_path, e));
+    }
+    // Same random numbers every run
+    console.seed_rng(0);
+
+    let script = Script::new(console);
+    if let Err(e) = script.run(&source) {
+        fail(format!("{}: {}", script_path, e));
+    }
+    println!("chip8-script: {} passed", script_path);
+}
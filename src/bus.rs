@@ -1,27 +1,119 @@
-/// BUS communicates between CPU, IO devices and Memory
+//! BUS communicates between CPU, IO devices and Memory
+//!
+//! The cpu only talks to the `Bus` trait, `SystemBus` is the standard
+//! machine of RAM, keypad and monochrome display. Other machines, memory
+//! mapped I/O or test doubles implement the trait instead
 
-use crate::memory::Ram;
+use crate::font::{DEFAULT_FONT_ADDR, FONT_SIZE};
+use crate::memory::{Ram, MEM_SIZE};
+use crate::instruction::Instruction;
 use crate::keypad::Keypad;
 use crate::display::Display;
+use crate::utils::{fnv1a, FNV_OFFSET};
 
 
-pub struct Bus {
+// What the cpu needs from the machine around it
+pub trait Bus {
+    fn memread(&self, addr: u16) -> u8;
+
+    fn memwrite(&mut self, addr: u16, val: u8);
+
+    // Bytes of RAM, addresses wrap around at this size
+    fn memory_size(&self) -> usize {
+        MEM_SIZE
+    }
+
+    // Fetch and decode the instruction at addr, opcodes are big endian
+    fn fetch(&mut self, addr: u16) -> Instruction {
+        let hi = self.memread(addr) as u16;
+        let lo = self.memread(addr.wrapping_add(1)) as u16;
+        Instruction::decode((hi << 8) | lo)
+    }
+
+    // Draw sprite_len bytes from addr at (x, y), true on collision
+    fn draw(&mut self, x: u8, y: u8, addr: u16, sprite_len: u16, clip: bool) -> bool;
+
+    fn clrs(&mut self);
+
+    // Where FX29 finds the hex font
+    fn font_address(&self) -> u16 {
+        DEFAULT_FONT_ADDR
+    }
+
+    fn is_key_pressed(&self, keycode: u8) -> bool;
+
+    fn get_pressed_key(&self) -> Option<u8>;
+
+    fn is_sound_on(&self) -> bool;
+
+    fn set_sound_on(&mut self);
+
+    fn set_sound_off(&mut self);
+
+    // CHIP-8X hardware, machines without it ignore these
+
+    fn cycle_background(&mut self) {}
+
+    // Foreground colour for columns of 8 pixels by rows of 1
+    fn set_foreground(&mut self, _x: usize, _y: usize, _columns: usize, _rows: usize, _color: u8) {}
+
+    // Second keypad
+    fn is_key2_pressed(&self, _keycode: u8) -> bool {
+        false
+    }
+
+    fn port_out(&mut self, _value: u8) {}
+
+    // Byte waiting on the input port, consumed by the read
+    fn port_in(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+
+pub struct SystemBus {
     ram: Ram,
     sound: bool,
     keypad: Keypad,
+    keypad2: Keypad,  // CHIP-8X second keypad
     display: Display,
+    port_output: u8,
+    port_input: Option<u8>,
+    decoded: Vec<Option<Instruction>>,  // Decode cache keyed by address, empty until a fetch
+}
+
+
+// Clones leave the decode cache behind, save states and environment
+// branches only copy the machine and decode again as they run
+impl Clone for SystemBus {
+    fn clone(&self) -> SystemBus {
+        SystemBus {
+            ram: self.ram.clone(),
+            sound: self.sound,
+            keypad: self.keypad.clone(),
+            keypad2: self.keypad2.clone(),
+            display: self.display.clone(),
+            port_output: self.port_output,
+            port_input: self.port_input,
+            decoded: Vec::new(),
+        }
+    }
 }
 
 
-impl Bus {
+impl SystemBus {
 
     // New
-    pub fn new() -> Bus {
-        Bus {
+    pub fn new() -> SystemBus {
+        SystemBus {
             sound: false,
             ram: Ram::new(),
             keypad: Keypad::new(),
+            keypad2: Keypad::new(),
             display: Display::new(),
+            port_output: 0,
+            port_input: None,
+            decoded: Vec::new(),
         }
     }
 
@@ -29,69 +121,286 @@ impl Bus {
     pub fn reset(&mut self) {
         self.ram.reset();
         self.display.clrs();
+        self.display.reset_colors();
+        self.port_output = 0;
+        self.port_input = None;
+        self.decoded.clear();
     }
 
     //Memory:
-    pub fn memread(&self, addr: u16) -> u8 {
+    pub fn memory_size(&self) -> usize {
+        self.ram.size()
+    }
+
+    // Swap in blank memory of a different size, keeping the font. The
+    // font goes back to its default address if it no longer fits
+    pub fn set_memory_size(&mut self, size: usize) {
+        if size != self.ram.size() {
+            let mut ram = Ram::with_size(size);
+            ram.set_font(self.ram.font());
+            if Ram::font_fits(self.ram.font_address(), size) {
+                ram.set_font_address(self.ram.font_address());
+            }
+            self.ram = ram;
+            self.decoded = Vec::new();
+        }
+    }
+
+    // Take on a saved bus's state in place. Decoded instructions stay
+    // cached unless the restored bytes under them differ
+    pub fn restore(&mut self, saved: &SystemBus) {
+        let size = self.ram.size();
+        if saved.ram.size() != size {
+            self.decoded = Vec::new();
+        } else if !self.decoded.is_empty() {
+            let current = self.ram.as_bytes();
+            for (idx, _) in current.iter().zip(saved.ram.as_bytes()).enumerate().filter(|(_, (a, b))| a != b) {
+                self.decoded[idx] = None;
+                self.decoded[(idx + size - 1) % size] = None;
+            }
+        }
+
+        self.ram.copy_from(&saved.ram);
+        self.sound = saved.sound;
+        self.keypad = saved.keypad.clone();
+        self.keypad2 = saved.keypad2.clone();
+        self.display.clone_from(&saved.display);
+        self.port_output = saved.port_output;
+        self.port_input = saved.port_input;
+    }
+
+    // Zero program memory from addr up, dropping stale decoded instructions
+    pub fn clear_memory_from(&mut self, addr: u16) {
+        self.ram.clear_from(addr);
+        self.decoded.clear();
+    }
+
+    // Font:
+    pub fn set_font(&mut self, font: &[u8; FONT_SIZE]) {
+        self.ram.set_font(font);
+        self.decoded.clear();
+    }
+
+    // False if the font doesn't fit at addr
+    pub fn set_font_address(&mut self, addr: u16) -> bool {
+        if !Ram::font_fits(addr, self.ram.size()) {
+            return false;
+        }
+        self.ram.set_font_address(addr);
+        self.decoded.clear();
+        true
+    }
+
+    // Display
+    pub fn display_size(&self) -> (usize, usize) {
+        (self.display.width(), self.display.height())
+    }
+
+    // Swap in a blank display of a different resolution
+    pub fn set_display_size(&mut self, width: usize, height: usize) {
+        if (width, height) != self.display_size() {
+            self.display = Display::with_size(width, height);
+        }
+    }
+
+    pub fn get_vram(&self) -> &[u8] {
+        self.display.get_vram()
+    }
+
+    // Hash of RAM and the display, equal on machines in the same state
+    pub fn state_hash(&self) -> u64 {
+        let hash = fnv1a(FNV_OFFSET, self.ram.as_bytes());
+        fnv1a(hash, self.display.get_vram())
+    }
+
+    // Palette index per pixel for colour output
+    pub fn get_color_vram(&mut self) -> &[u8] {
+        self.display.get_color_vram()
+    }
+
+    // Keypad
+    pub fn press_key(&mut self, keycode: u8) {
+        self.keypad.press(keycode);
+    }
+
+    pub fn release_key(&mut self, keycode: u8) {
+        self.keypad.release(keycode);
+    }
+
+    // Bit n set to hold key n, replacing whatever was held
+    pub fn set_pressed_keys(&mut self, keys: u16) {
+        self.keypad.set_pressed_keys(keys);
+    }
+
+    pub fn pressed_keys(&self) -> u16 {
+        self.keypad.pressed_keys()
+    }
+
+    pub fn press_key2(&mut self, keycode: u8) {
+        self.keypad2.press(keycode);
+    }
+
+    pub fn release_key2(&mut self, keycode: u8) {
+        self.keypad2.release(keycode);
+    }
+
+    pub fn set_pressed_keys2(&mut self, keys: u16) {
+        self.keypad2.set_pressed_keys(keys);
+    }
+
+    // Ports
+    pub fn port_output(&self) -> u8 {
+        self.port_output
+    }
+
+    pub fn set_port_input(&mut self, value: u8) {
+        self.port_input = Some(value);
+    }
+
+}
+
+
+impl Bus for SystemBus {
+
+    fn memread(&self, addr: u16) -> u8 {
         self.ram.memread(addr)
     }
 
-    pub fn memwrite(&mut self, addr: u16, val: u8) {
+    fn memory_size(&self) -> usize {
+        self.ram.size()
+    }
+
+    // Writes invalidate any cached instruction overlapping the address
+    fn memwrite(&mut self, addr: u16, val: u8) {
+        let size = self.ram.size();
+        let idx = addr as usize % size;
+        if !self.decoded.is_empty() {
+            self.decoded[idx] = None;
+            self.decoded[(idx + size - 1) % size] = None;
+        }
         self.ram.memwrite(addr, val)
     }
 
-    // Display
-    pub fn draw(&mut self, x: u8, y: u8, addr: u16, sprite_len: u16) -> bool{
-        let mut sprite: Vec<u8> = vec![];
+    // Decoded instructions are cached until their bytes are written
+    fn fetch(&mut self, addr: u16) -> Instruction {
+        let idx = addr as usize % self.ram.size();
+        if self.decoded.is_empty() {
+            self.decoded.resize(self.ram.size(), None);
+        }
 
-        for i in 0..sprite_len {
-            let idx  = addr + i as u16;
-            let byte = self.memread(idx);
-            sprite.push(byte)
+        if let Some(instruction) = self.decoded[idx] {
+            return instruction;
         }
 
-        self.display.draw_sprite(x, y, &sprite)
+        let hi = self.memread(addr) as u16;
+        let lo = self.memread(addr.wrapping_add(1)) as u16;
+        let instruction = Instruction::decode((hi << 8) | lo);
+        self.decoded[idx] = Some(instruction);
+        instruction
     }
 
-    pub fn clrs(&mut self) {
+    fn draw(&mut self, x: u8, y: u8, addr: u16, sprite_len: u16, clip: bool) -> bool {
+        let sprite = self.ram.slice(addr, sprite_len);
+        self.display.draw_sprite(x, y, sprite, clip)
+    }
+
+    fn clrs(&mut self) {
         self.display.clrs()
     }
 
-    pub fn get_vram(&self) -> Vec<u8> {
-        self.display.get_vram()
+    fn font_address(&self) -> u16 {
+        self.ram.font_address()
+    }
+
+    fn is_key_pressed(&self, keycode: u8) -> bool {
+        self.keypad.is_key_pressed(keycode)
     }
 
-    // Sound
-    pub fn is_sound_on(&self) -> bool {
+    fn get_pressed_key(&self) -> Option<u8> {
+        self.keypad.get_pressed_key()
+    }
+
+    fn is_sound_on(&self) -> bool {
         self.sound
     }
 
-    pub fn set_sound_on(&mut self) {
+    fn set_sound_on(&mut self) {
         self.sound = true;
     }
 
-    pub fn set_sound_off(&mut self) {
+    fn set_sound_off(&mut self) {
         self.sound = false;
     }
 
-    // Keypad
-    pub fn press_key(&mut self, key: Option<u8>) {
-        self.keypad.set_pressed_key(key);
+    fn cycle_background(&mut self) {
+        self.display.cycle_background();
     }
 
-    pub fn release_key(&mut self, keycode: u8) {
-        if self.keypad.is_key_pressed(keycode) {
-            self.keypad.set_pressed_key(None)
-        }
+    fn set_foreground(&mut self, x: usize, y: usize, columns: usize, rows: usize, color: u8) {
+        self.display.set_foreground(x, y, columns, rows, color);
     }
 
-    pub fn get_pressed_key(&self) -> Option<u8> {
-        self.keypad.get_pressed_key()
+    fn is_key2_pressed(&self, keycode: u8) -> bool {
+        self.keypad2.is_key_pressed(keycode)
     }
 
-    pub fn is_key_pressed(&self, keycode: u8) -> bool {
-        self.keypad.is_key_pressed(keycode)
+    fn port_out(&mut self, value: u8) {
+        self.port_output = value;
     }
 
+    fn port_in(&mut self) -> Option<u8> {
+        self.port_input.take()
+    }
 }
 
+
+impl Default for SystemBus {
+    fn default() -> SystemBus {
+        SystemBus::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::XO_MEM_SIZE;
+
+    #[test]
+    fn test_clones_leave_the_decode_cache_behind() {
+        let mut bus = SystemBus::new();
+        bus.set_memory_size(XO_MEM_SIZE);
+        bus.memwrite(0x200, 0x60);
+        bus.memwrite(0x201, 0x01);
+        assert_eq!(bus.fetch(0x200), Instruction::Op6xnn(0, 1));
+        assert_eq!(bus.decoded.len(), XO_MEM_SIZE);
+
+        let mut copy = bus.clone();
+        assert_eq!(copy.decoded.capacity(), 0);
+        assert_eq!(copy.fetch(0x200), Instruction::Op6xnn(0, 1));
+
+        // The cache comes back on the next fetch and writes still reach it
+        bus.memwrite(0x201, 0x02);
+        assert_eq!(bus.fetch(0x200), Instruction::Op6xnn(0, 2));
+        assert_eq!(copy.fetch(0x200), Instruction::Op6xnn(0, 1));
+    }
+
+    #[test]
+    fn test_restore_keeps_the_decode_cache() {
+        let mut bus = SystemBus::new();
+        for (addr, byte) in (0x200..).zip(&[0x60, 0x01, 0x61, 0x02]) {
+            bus.memwrite(addr, *byte);
+        }
+        let saved = bus.clone();
+        bus.fetch(0x200);
+        bus.fetch(0x202);
+        bus.memwrite(0x203, 0x05);
+        assert_eq!(bus.fetch(0x202), Instruction::Op6xnn(1, 5));
+
+        bus.restore(&saved);
+        assert_eq!(bus.decoded.len(), MEM_SIZE);
+        assert_eq!(bus.decoded[0x200], Some(Instruction::Op6xnn(0, 1)));
+        assert_eq!(bus.decoded[0x202], None);
+        assert_eq!(bus.fetch(0x202), Instruction::Op6xnn(1, 2));
+    }
+}
@@ -0,0 +1,254 @@
+//! Byte-literal Octo cartridge loader
+//!
+//! An Octo cartridge is a GIF whose first frame hides a payload in the
+//! low two bits of each pixel's palette index, four pixels per byte with
+//! the most significant bits first. The payload is a 32 bit big endian
+//! length followed by that many bytes of UTF-8 JSON:
+//!
+//! ```text
+//! {"program": "...", "options": {"tickrate": 20, ...}}
+//! ```
+//!
+//! Octo stores the program as source text. Only byte-literal cartridges
+//! are supported: the ones Octo makes from binary ROMs, whose program is
+//! a list of numeric byte literals with an optional `: main` label and
+//! comments. There is no Octo assembler here, so a cartridge holding
+//! real Octo source (instructions, labels, macros) is rejected with
+//! `CartridgeError::NotByteLiterals`
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::quirks::Quirks;
+use crate::romdb::{Colors, QuirkOverrides};
+
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Gif(gif::DecodingError),
+    NoImage,
+    Truncated { expected: usize, found: usize },
+    Payload(serde_json::Error),
+    NotByteLiterals(String),  // First token that isn't a byte literal
+}
+
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Gif(e) => write!(f, "not a valid GIF: {}", e),
+            CartridgeError::NoImage => write!(f, "GIF has no image data"),
+            CartridgeError::Truncated { expected, found } =>
+                write!(f, "payload is {} bytes but only {} are stored", expected, found),
+            CartridgeError::Payload(e) => write!(f, "malformed cartridge payload: {}", e),
+            CartridgeError::NotByteLiterals(token) =>
+                write!(f, "only byte-literal cartridges are supported, the program is Octo source \
+                           that needs assembling (found `{}`)", token),
+        }
+    }
+}
+
+
+impl std::error::Error for CartridgeError {}
+
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: OctoOptions,
+}
+
+
+// The subset of Octo's options that maps onto this emulator
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoOptions {
+    pub tickrate: Option<u32>,
+    pub fill_color: Option<String>,
+    pub background_color: Option<String>,
+    pub buzz_color: Option<String>,
+    pub quiet_color: Option<String>,
+    pub shift_quirks: Option<bool>,
+    pub load_store_quirks: Option<bool>,
+    pub clip_quirks: Option<bool>,
+    pub jump_quirks: Option<bool>,
+    pub v_blank_quirks: Option<bool>,
+    pub logic_quirks: Option<bool>,
+    #[serde(default)]
+    pub keys: HashMap<String, u8>,
+}
+
+
+impl OctoOptions {
+
+    pub fn quirk_overrides(&self) -> QuirkOverrides {
+        QuirkOverrides {
+            shift: self.shift_quirks,
+            memory_leave_i_unchanged: self.load_store_quirks,
+            wrap: self.clip_quirks.map(|clip| !clip),
+            jump: self.jump_quirks,
+            vblank: self.v_blank_quirks,
+            logic: self.logic_quirks,
+            ..QuirkOverrides::default()
+        }
+    }
+
+    // Apply the cartridge's quirks on top of base
+    pub fn quirks(&self, base: Quirks) -> Quirks {
+        let mut quirks = base;
+        self.quirk_overrides().apply(&mut quirks);
+        quirks
+    }
+
+    pub fn colors(&self) -> Option<Colors> {
+        if self.background_color.is_none() && self.fill_color.is_none() {
+            return None;
+        }
+
+        let background = self.background_color.clone().unwrap_or_else(|| "#000000".into());
+        let fill = self.fill_color.clone().unwrap_or_else(|| "#FFFFFF".into());
+
+        Some(Colors {
+            pixels: vec![background, fill],
+            buzzer: self.buzz_color.clone(),
+            silence: self.quiet_color.clone(),
+        })
+    }
+}
+
+
+pub struct Cartridge {
+    pub rom: Vec<u8>,
+    pub options: OctoOptions,
+}
+
+
+impl Cartridge {
+
+    pub fn decode(gif_bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif_bytes).map_err(CartridgeError::Gif)?;
+
+        let frame = decoder.read_next_frame()
+            .map_err(CartridgeError::Gif)?
+            .ok_or(CartridgeError::NoImage)?;
+
+        let bytes: Vec<u8> = frame.buffer.chunks_exact(4)
+            .map(|px| px.iter().fold(0u8, |byte, i| (byte << 2) | (i & 0b11)))
+            .collect();
+
+        if bytes.len() < 4 {
+            return Err(CartridgeError::Truncated { expected: 4, found: bytes.len() });
+        }
+
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let data = &bytes[4..];
+        if data.len() < len {
+            return Err(CartridgeError::Truncated { expected: len, found: data.len() });
+        }
+
+        let payload: Payload = serde_json::from_slice(&data[..len])
+            .map_err(CartridgeError::Payload)?;
+
+        Ok(Cartridge {
+            rom: parse_byte_literals(&payload.program)?,
+            options: payload.options,
+        })
+    }
+}
+
+
+// The program of a byte-literal cartridge: numeric byte literals, with
+// an optional `: main` label and # comments
+fn parse_byte_literals(source: &str) -> Result<Vec<u8>, CartridgeError> {
+    let tokens = source.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split_whitespace());
+
+    let mut rom = Vec::new();
+    let mut after_colon = false;
+
+    for token in tokens {
+        if token == ":" {
+            after_colon = true;
+            continue;
+        }
+
+        if after_colon && token == "main" {
+            after_colon = false;
+            continue;
+        }
+
+        let value = if let Some(hex) = token.strip_prefix("0x") {
+            u8::from_str_radix(hex, 16)
+        } else if let Some(bin) = token.strip_prefix("0b") {
+            u8::from_str_radix(bin, 2)
+        } else {
+            token.parse::<u8>()
+        };
+
+        match value {
+            Ok(byte) if !after_colon => rom.push(byte),
+            _ => return Err(CartridgeError::NotByteLiterals(token.to_string())),
+        }
+    }
+
+    Ok(rom)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a cartridge the way Octo lays one out
+    fn encode(payload: &str) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload.as_bytes());
+
+        let mut pixels: Vec<u8> = data.iter()
+            .flat_map(|b| vec![b >> 6, (b >> 4) & 3, (b >> 2) & 3, b & 3])
+            .map(|bits| 0x10 | bits)
+            .collect();
+        let width = 64u16;
+        pixels.resize(pixels.len().div_ceil(64) * 64, 0);
+        let height = (pixels.len() / 64) as u16;
+
+        let palette: Vec<u8> = (0..=255u8).flat_map(|i| vec![i, i, i]).collect();
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, width, height, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(width, height, pixels, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        gif
+    }
+
+    #[test]
+    fn test_decode_byte_literal_cartridge() {
+        let gif = encode(r##"{"program": ": main\n0x12 0x00 # loop\n96",
+            "options": {"tickrate": 20, "clipQuirks": false, "fillColor": "#FF0000"}}"##);
+        let cart = Cartridge::decode(&gif).unwrap();
+        assert_eq!(cart.rom, vec![0x12, 0x00, 96]);
+        assert_eq!(cart.options.tickrate, Some(20));
+        assert!(!cart.options.quirks(Quirks::chip8()).clipping);
+        assert_eq!(cart.options.colors().unwrap().pixels[1], "#FF0000");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(Cartridge::decode(b"not a gif"), Err(CartridgeError::Gif(_))));
+
+        let gif = encode(r#"{"program": ": main\nloop 0x12 again"}"#);
+        let error = Cartridge::decode(&gif).err().unwrap();
+        assert!(matches!(error, CartridgeError::NotByteLiterals(ref t) if t == "loop"));
+        assert!(error.to_string().starts_with("only byte-literal cartridges are supported"));
+
+        let gif = encode("{}");
+        assert!(matches!(Cartridge::decode(&gif), Err(CartridgeError::Payload(_))));
+    }
+}
@@ -0,0 +1,219 @@
+//! Cheat engine
+//!
+//! A RAM search in the style of classic cheat finders: snapshot memory,
+//! play a little, then keep only the addresses whose value compares the
+//! way you expect. Cheats found this way freeze a byte every frame and
+//! are stored per ROM hash so they come back when the ROM is reloaded
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::bus::{Bus, SystemBus};
+use crate::cpu::Cpu;
+
+
+// How a candidate's current value must relate to the last snapshot
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    EqualTo,    // Equal to a given value
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+}
+
+
+impl Comparison {
+
+    fn matches(self, previous: u8, current: u8, value: u8) -> bool {
+        match self {
+            Comparison::EqualTo => current == value,
+            Comparison::Unchanged => current == previous,
+            Comparison::Changed => current != previous,
+            Comparison::Increased => current > previous,
+            Comparison::Decreased => current < previous,
+        }
+    }
+}
+
+
+pub struct MemorySearch {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+
+impl MemorySearch {
+
+    // Snapshot all of memory, every address starts as a candidate
+    pub fn start(bus: &SystemBus) -> MemorySearch {
+        let size = bus.memory_size();
+        MemorySearch {
+            snapshot: (0..size).map(|addr| bus.memread(addr as u16)).collect(),
+            candidates: (0..size).map(|addr| addr as u16).collect(),
+        }
+    }
+
+    // Drop candidates that don't match, then snapshot again.
+    // value is only used by EqualTo
+    pub fn filter(&mut self, bus: &SystemBus, comparison: Comparison, value: u8) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            comparison.matches(snapshot[addr as usize], bus.memread(addr), value)
+        });
+
+        for addr in 0..self.snapshot.len() {
+            self.snapshot[addr] = bus.memread(addr as u16);
+        }
+
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CheatTarget {
+    Memory(u16),
+    Register(usize),  // V0 - VF
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cheat {
+    pub name: String,
+    pub target: CheatTarget,
+    pub value: u8,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+
+impl Cheat {
+
+    pub fn new(name: &str, target: CheatTarget, value: u8) -> Cheat {
+        Cheat { name: name.to_string(), target, value, enabled: true }
+    }
+
+    fn apply(&self, cpu: &mut Cpu, bus: &mut impl Bus) {
+        match self.target {
+            CheatTarget::Memory(addr) if bus.memread(addr) != self.value =>
+                bus.memwrite(addr, self.value),
+            CheatTarget::Register(x) if x < 16 => cpu.set_register(x, self.value),
+            _ => {}
+        }
+    }
+}
+
+
+// Cheats for every ROM, keyed by the ROM's SHA-1
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheatBook {
+    roms: HashMap<String, Vec<Cheat>>,
+}
+
+
+impl CheatBook {
+
+    pub fn new() -> CheatBook {
+        CheatBook::default()
+    }
+
+    pub fn from_json(json: &str) -> Result<CheatBook, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("cheats serialize")
+    }
+
+    pub fn cheats(&self, hash: &str) -> &[Cheat] {
+        self.roms.get(hash).map_or(&[], |cheats| cheats.as_slice())
+    }
+
+    // Add a cheat, replacing any with the same name
+    pub fn add(&mut self, hash: &str, cheat: Cheat) {
+        let cheats = self.roms.entry(hash.to_string()).or_default();
+        cheats.retain(|c| c.name != cheat.name);
+        cheats.push(cheat);
+    }
+
+    pub fn remove(&mut self, hash: &str, name: &str) {
+        if let Some(cheats) = self.roms.get_mut(hash) {
+            cheats.retain(|c| c.name != name);
+        }
+    }
+
+    pub fn set_enabled(&mut self, hash: &str, name: &str, enabled: bool) {
+        let cheats = self.roms.get_mut(hash).into_iter().flatten();
+        cheats.filter(|c| c.name == name).for_each(|c| c.enabled = enabled);
+    }
+
+    // Write every enabled cheat for the ROM, called once per frame
+    pub fn apply(&self, hash: &str, cpu: &mut Cpu, bus: &mut impl Bus) {
+        for cheat in self.cheats(hash).iter().filter(|c| c.enabled) {
+            cheat.apply(cpu, bus);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_narrows_to_counter() {
+        let mut bus = SystemBus::new();
+        bus.memwrite(0x300, 3);
+        bus.memwrite(0x301, 3);
+
+        let mut search = MemorySearch::start(&bus);
+        assert_eq!(search.filter(&bus, Comparison::EqualTo, 3), 2);
+
+        // Lose a life, the other 3 was a coincidence
+        bus.memwrite(0x300, 2);
+        bus.memwrite(0x400, 9);
+        assert_eq!(search.filter(&bus, Comparison::Decreased, 0), 1);
+        assert_eq!(search.candidates(), &[0x300]);
+
+        assert_eq!(search.filter(&bus, Comparison::Unchanged, 0), 1);
+        bus.memwrite(0x300, 1);
+        assert_eq!(search.filter(&bus, Comparison::Increased, 0), 0);
+    }
+
+    #[test]
+    fn test_frozen_values_and_json() {
+        let (mut cpu, mut bus) = (Cpu::new(), SystemBus::new());
+        let mut book = CheatBook::new();
+        book.add("abc", Cheat::new("lives", CheatTarget::Memory(0x300), 5));
+        book.add("abc", Cheat::new("score", CheatTarget::Register(3), 0x99));
+        book.add("def", Cheat::new("other", CheatTarget::Memory(0x300), 1));
+
+        book.apply("abc", &mut cpu, &mut bus);
+        assert_eq!(bus.memread(0x300), 5);
+        assert_eq!(cpu.register(3), 0x99);
+
+        book.set_enabled("abc", "lives", false);
+        bus.memwrite(0x300, 0);
+        book.apply("abc", &mut cpu, &mut bus);
+        assert_eq!(bus.memread(0x300), 0);
+
+        let restored = CheatBook::from_json(&book.to_json()).unwrap();
+        assert_eq!(restored, book);
+        assert_eq!(restored.cheats("abc").len(), 2);
+        assert!(restored.cheats("missing").is_empty());
+    }
+}
@@ -1,20 +1,70 @@
-/// CHIP 8 CPU
+//! CHIP 8 CPU
 
 use crate::utils;
+use crate::utils::SeededRng;
 use crate::bus::Bus;
+use crate::error::EmulatorError;
+use crate::font::GLYPH_SIZE;
+use crate::instruction::Instruction;
+use crate::profiler::Profiler;
+use crate::quirks::Quirks;
+use wasm_bindgen::prelude::*;
 
 
 // Mem from 0 to 0x199 is reserved
 pub const PROG_START: u16 = 0x200u16;
 
+// The CHIP-8X interpreter is larger, programs start after it
+pub const CHIP8X_PROG_START: u16 = 0x300;
 
+// HIRES programs open with 1260, a jump into a patch of VIP machine
+// code that switches the display to 64x64. The program proper follows
+pub const HIRES_PATCH: [u8; 2] = [0x12, 0x60];
+pub const HIRES_ENTRY: u16 = 0x2C0;
+
+// Whether a ROM loaded at load_address starts with the HIRES patch
+pub fn is_hires(rom: &[u8], load_address: u16) -> bool {
+    load_address == PROG_START && rom.starts_with(&HIRES_PATCH)
+}
+
+// The VIP interpreter kept its stack below 0xED0, growing down to 0xEA0
+pub const VIP_STACK_TOP: u16 = 0xED0;
+pub const VIP_STACK_SIZE: usize = 24;
+
+
+// Longest loop, in instructions, checked for idling on the delay timer
+const IDLE_LOOP_LEN: u16 = 4;
+
+
+// What the program is doing, lets runners stop or throttle idle ROMs
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineStatus {
+    Running,
+    WaitingForKey,  // Blocked on FX0A, or FXFB's input port
+    WaitingForTimer,  // Polling the delay timer in a loop until it changes
+    Halted,         // Jumped to itself, nothing can change state
+    Errored,        // Stopped on an emulator error
+}
+
+
+#[derive(Clone)]
 pub struct Cpu {
     st: u8,           // Sound timer
     dt: u8,           // delay timer
     pc: u16,          // Program counter
+    entry: u16,       // Where the program counter starts
     idx: u16,         // Memory index
     v: [u8; 16],      // data registers
-    stack: Vec<u16>   // Stack for macros and procedures
+    stack: Vec<u16>,  // Stack for macros and procedures
+    quirks: Quirks,   // Platform specific instruction behaviour
+    status: MachineStatus,
+    error: Option<EmulatorError>,
+    awaiting_vblank: bool,  // A draw is waiting for the next frame
+    hires: bool,            // The HIRES patch is loaded and handles 0230
+    awaited_key: Option<u8>,  // Key FX0A saw go down, taken once it's released
+    profiler: Option<Profiler>,
+    rng: Option<SeededRng>,  // CXNN source when seeded, host entropy otherwise
 }
 
 
@@ -35,7 +85,16 @@ impl Cpu {
             idx: 0,
             v: [0; 16],
             pc: PROG_START,
-            stack: Vec::<u16>::new()
+            entry: PROG_START,
+            stack: Vec::<u16>::new(),
+            quirks: Quirks::default(),
+            status: MachineStatus::Running,
+            error: None,
+            awaiting_vblank: false,
+            hires: false,
+            awaited_key: None,
+            profiler: None,
+            rng: None,
         }
 
     }
@@ -46,28 +105,221 @@ impl Cpu {
         self.dt = 0u8;
         self.idx = 0u16;
         self.v  = [0u8; 16];
-        self.pc = PROG_START;
+        self.pc = self.entry;
         self.stack = Vec::<u16>::new();
+        self.status = MachineStatus::Running;
+        self.error = None;
+        self.awaiting_vblank = false;
+        self.awaited_key = None;
+        if let Some(profiler) = &mut self.profiler {
+            *profiler = Profiler::new(self.entry);
+        }
+    }
+
+    // Start a fresh profile, or stop profiling and drop the data
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = if enabled { Some(Profiler::new(self.entry)) } else { None };
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Quirks survive a reset, they describe the platform not the program
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // The HIRES patch's machine code answers 0230 by clearing the 64x64
+    // display, without it 0230 is just another machine code call
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+    }
+
+    // Start address used by reset, for ROMs not loaded at PROG_START
+    pub fn set_entry_point(&mut self, addr: u16) {
+        self.entry = addr;
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        self.status
+    }
+
+    pub fn error(&self) -> Option<EmulatorError> {
+        self.error
+    }
+
+    // Make CXNN reproducible, the generator is part of the cpu's state
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Some(SeededRng::new(seed));
+    }
+
+    // Address of the next instruction
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // Data register Vx
+    pub fn register(&self, x: usize) -> u8 {
+        self.v[x]
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+        self.resume();
+    }
+
+    // Moving the pc also steps away from whatever stopped the cpu
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+        self.status = MachineStatus::Running;
+        self.error = None;
+    }
+
+    // Memory index register I
+    pub fn index(&self) -> u16 {
+        self.idx
+    }
+
+    pub fn set_index(&mut self, addr: u16) {
+        self.idx = addr;
+        self.resume();
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.dt = value;
+        self.resume();
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.st = value;
+        self.resume();
+    }
+
+    // Return addresses on the stack
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // With the display wait quirk a draw uses up the rest of the frame
+    pub fn is_awaiting_vblank(&self) -> bool {
+        self.awaiting_vblank
+    }
+
+    // A halted loop may exit once a debugger changes its state
+    fn resume(&mut self) {
+        if self.status == MachineStatus::Halted {
+            self.status = MachineStatus::Running;
+        }
+    }
+
+    // Stop the cpu, it won't execute again until reset or the pc moves
+    fn fail(&mut self, error: EmulatorError) -> ProgramCounterKind {
+        self.status = MachineStatus::Errored;
+        self.error = Some(error);
+        ProgramCounterKind::Jump(self.pc)
     }
 
     // Execute one cpu cycle
-    pub fn tick(&mut self, bus: &mut Bus) {
-        utils::log_str("REGISTERS V0 - v16");
-        utils::log_u8_array(&self.v);
-        utils::log_str("MEMORY INDEX");
-        utils::log_u16(self.idx);
-        utils::log_str("MEMORY BLOCK");
-        utils::log_u8(bus.memread(self.idx));
-        utils::log_str("STACK");
-        utils::log_u16_array(&self.stack);
-
-        let hi = bus.memread(self.pc) as u16;
-        let lo = bus.memread(self.pc + 1) as u16;
-
-        // Addresses are stored big endian
-        let opcode = (hi << 8) | lo;
-        utils::log_str("OPCODE");
-        utils::log_u16(opcode);
+    pub fn tick(&mut self, bus: &mut impl Bus) {
+        if let MachineStatus::Halted | MachineStatus::Errored = self.status {
+            return;
+        }
+
+        #[cfg(feature = "trace")]
+        {
+            utils::log_str("REGISTERS V0 - v16");
+            utils::log_u8_array(&self.v);
+            utils::log_str("MEMORY INDEX");
+            utils::log_u16(self.idx);
+            utils::log_str("MEMORY BLOCK");
+            utils::log_u8(bus.memread(self.idx));
+            utils::log_str("STACK");
+            utils::log_u16_array(&self.stack);
+        }
+
+        let instruction = bus.fetch(self.pc);
+        trace!("OPCODE {:?}", instruction);
+        self.status = MachineStatus::Running;
+
+        let pc = self.pc;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, &instruction);
+        }
+
+        match self.execute_instruction(instruction, bus) {
+            ProgramCounterKind::Next => self.advance(bus, 2),
+            ProgramCounterKind::Skip => self.advance(bus, 4),
+            ProgramCounterKind::Jump(n) => self.pc = n
+        };
+
+        if let Some(profiler) = &mut self.profiler {
+            if self.status != MachineStatus::Errored {
+                match instruction {
+                    Instruction::Op2nnn(nnn) => profiler.enter(nnn),
+                    Instruction::Op00ee => profiler.leave(),
+                    Instruction::Opdxyn(..) => profiler.record_draw(self.v[0xF] == 1),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Step over len bytes. Running past the last address wraps the pc
+    // round to 0 and stops the cpu, no program means to do that
+    fn advance(&mut self, bus: &impl Bus, len: usize) {
+        let next = self.pc as usize + len;
+        if next >= bus.memory_size() {
+            self.fail(EmulatorError::EndOfMemory { addr: self.pc });
+        }
+        self.pc = wrap_address(bus, next);
+    }
+
+    // One 60Hz frame of emulated time, the rest of the frame's
+    // instructions are skipped once the program idles, stops or
+    // draws with the display wait quirk
+    pub fn run_frame(&mut self, bus: &mut impl Bus, instructions: u32) {
+        self.run_frame_with(bus, instructions, |cpu, bus| cpu.tick(bus));
+    }
+
+    // run_frame with each instruction executed by step, for callers that
+    // wrap instructions in hooks. step should end by calling tick
+    pub fn run_frame_with<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        instructions: u32,
+        mut step: impl FnMut(&mut Cpu, &mut B),
+    ) {
+        for _ in 0..instructions {
+            step(self, bus);
+
+            if self.status != MachineStatus::Running || self.awaiting_vblank {
+                break;
+            }
+        }
+
+        self.tick_timers(bus);
+    }
+
+    // Count timers down, called at 60Hz of emulated time
+    pub fn tick_timers(&mut self, bus: &mut impl Bus) {
+        self.awaiting_vblank = false;
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
 
         // Decrement delay timer
         if self.dt > 0 {
@@ -85,137 +337,237 @@ impl Cpu {
         } else {
             bus.set_sound_off();
         }
-
-        match self.execute_instruction(opcode, bus) {
-            ProgramCounterKind::Next => self.pc += 2,
-            ProgramCounterKind::Skip => self.pc += 4,
-            ProgramCounterKind::Jump(n) => self.pc = n
-        };
     }
 
-    // Legend:
-    // NNN - 2-byte (used for mem address);
-    // NN - 1-byte constant (used for data)
-    // N - 4-bit constant
-    // X, Y - 4-bit register id
-    // PC: Program counter
-    // I: Instruction pointer
-    // VN: One of V0 to VF data register
     fn execute_instruction(
         &mut self,
-        opcode: u16,
-        bus: &mut Bus
+        instruction: Instruction,
+        bus: &mut impl Bus
     ) -> ProgramCounterKind {
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-
-        let n = (opcode & 0x000F) as u8;
-        let nn = (opcode & 0x00FF) as u8;
-        let nnn = (opcode & 0x0FFF) as u16;
-
-        let p1 = opcode & 0x000F;
-        let p2 = (opcode & 0x00F0) >> 4;
-        let p3 = (opcode & 0x0F00) >> 8;
-        let p4 = (opcode & 0xF000) >> 12;
-
-        match (p4, p3, p2, p1) {
-            (0, 0, 0xE, 0) => self.call_00e0(bus),
-            (0, 0, 0xE, 0xE) => self.call_00ee(),
-            (0x1, _, _, _) => self.call_1nnn(nnn),
-            (0x2, _, _, _) => self.call_2nnn(nnn),
-            (0x3, _, _, _) => self.call_3xnn(x, nn),
-            (0x4, _, _, _) => self.call_4xnn(x, nn),
-            (0x5, _, _, 0) => self.call_5xy0(x, y),
-            (0x6, _, _, _) => self.call_6xnn(x, nn),
-            (0x7, _, _, _) => self.call_7xnn(x, nn),
-            (0x8, _, _, 0) => self.call_8xy0(x, y),
-            (0x8, _, _, 1) => self.call_8xy1(x, y),
-            (0x8, _, _, 2) => self.call_8xy2(x, y),
-            (0x8, _, _, 3) => self.call_8xy3(x, y),
-            (0x8, _, _, 4) => self.call_8xy4(x, y),
-            (0x8, _, _, 5) => self.call_8xy5(x, y),
-            (0x8, _, _, 6) => self.call_8xy6(x, y),
-            (0x8, _, _, 7) => self.call_8xy7(x, y),
-            (0x8, _, _, 0xE) => self.call_8xye(x, y),
-            (0x9, _, _, 0) => self.call_9xy0(x, y),
-            (0xA, _, _, _) => self.call_annn(nnn),
-            (0xB, _, _, _) => self.call_bnnn(nnn),
-            (0xC, _, _, _) => self.call_cxnn(x, nn),
-            (0xD, _, _, _) => self.call_dxyn(bus, x, y, n),
-            (0xE, _, 9, 0xE) => self.call_ex9e(bus, x),
-            (0xE, _, 0xA, 1) => self.call_exa1(bus, x),
-            (0xF, _, 0, 7) => self.call_fx07(x),
-            (0xF, _, 0, 0xA) => self.call_fx0a(bus, x),
-            (0xF, _, 1, 5) => self.call_fx15(x),
-            (0xF, _, 1, 8) => self.call_fx18(x),
-            (0xF, _, 1, 0xE) => self.call_fx1e(x),
-            (0xF, _, 2, 9) => self.call_fx29(x),
-            (0xF, _, 3, 3) => self.call_fx33(bus, x),
-            (0xF, _, 5, 5) => self.call_fx55(bus, x),
-            (0xF, _, 6, 5) => self.call_fx65(bus, x),
-            (_, _, _, _) => ProgramCounterKind::Next
+        match instruction {
+            // Only a machine code call anywhere but CHIP-8X
+            Instruction::Op02a0 if !self.quirks.chip8x => self.call_0nnn(),
+            _ if instruction.is_chip8x() && !self.quirks.chip8x => {
+                let opcode = (bus.memread(self.pc) as u16) << 8
+                    | bus.memread(self.pc.wrapping_add(1)) as u16;
+                self.fail(EmulatorError::UnknownOpcode { addr: self.pc, opcode })
+            }
+            Instruction::Op00e0 => self.call_00e0(bus),
+            Instruction::Op00ee => self.call_00ee(bus),
+            // The HIRES patch's clear is 00E0 on the taller display
+            Instruction::Op0230 if self.hires => self.call_00e0(bus),
+            Instruction::Op0230 => self.call_0nnn(),
+            Instruction::Op02a0 => self.call_02a0(bus),
+            Instruction::Op0nnn(_) => self.call_0nnn(),
+            Instruction::Op1nnn(nnn) => self.call_1nnn(bus, nnn),
+            Instruction::Op2nnn(nnn) => self.call_2nnn(bus, nnn),
+            Instruction::Op3xnn(x, nn) => self.call_3xnn(x, nn),
+            Instruction::Op4xnn(x, nn) => self.call_4xnn(x, nn),
+            Instruction::Op5xy0(x, y) => self.call_5xy0(x, y),
+            Instruction::Op5xy1(x, y) => self.call_5xy1(x, y),
+            Instruction::Op6xnn(x, nn) => self.call_6xnn(x, nn),
+            Instruction::Op7xnn(x, nn) => self.call_7xnn(x, nn),
+            Instruction::Op8xy0(x, y) => self.call_8xy0(x, y),
+            Instruction::Op8xy1(x, y) => self.call_8xy1(x, y),
+            Instruction::Op8xy2(x, y) => self.call_8xy2(x, y),
+            Instruction::Op8xy3(x, y) => self.call_8xy3(x, y),
+            Instruction::Op8xy4(x, y) => self.call_8xy4(x, y),
+            Instruction::Op8xy5(x, y) => self.call_8xy5(x, y),
+            Instruction::Op8xy6(x, y) => self.call_8xy6(x, y),
+            Instruction::Op8xy7(x, y) => self.call_8xy7(x, y),
+            Instruction::Op8xye(x, y) => self.call_8xye(x, y),
+            Instruction::Op9xy0(x, y) => self.call_9xy0(x, y),
+            Instruction::Opannn(nnn) => self.call_annn(nnn),
+            Instruction::Opbnnn(x, nnn) if self.quirks.chip8x => self.call_bxyn(
+                bus, x, ((nnn >> 4) & 0xF) as usize, (nnn & 0xF) as u8
+            ),
+            Instruction::Opbnnn(x, nnn) => self.call_bnnn(bus, x, nnn),
+            Instruction::Opcxnn(x, nn) => self.call_cxnn(x, nn),
+            Instruction::Opdxyn(x, y, n) => self.call_dxyn(bus, x, y, n),
+            Instruction::Opex9e(x) => self.call_ex9e(bus, x),
+            Instruction::Opexa1(x) => self.call_exa1(bus, x),
+            Instruction::Opexf2(x) => self.call_exf2(bus, x),
+            Instruction::Opexf5(x) => self.call_exf5(bus, x),
+            Instruction::Opfx07(x) => self.call_fx07(x),
+            Instruction::Opfx0a(x) => self.call_fx0a(bus, x),
+            Instruction::Opfx15(x) => self.call_fx15(x),
+            Instruction::Opfx18(x) => self.call_fx18(x),
+            Instruction::Opfx1e(x) => self.call_fx1e(bus, x),
+            Instruction::Opfx29(x) => self.call_fx29(bus, x),
+            Instruction::Opfx33(x) => self.call_fx33(bus, x),
+            Instruction::Opfx55(x) => self.call_fx55(bus, x),
+            Instruction::Opfx65(x) => self.call_fx65(bus, x),
+            Instruction::Opfxf8(x) => self.call_fxf8(bus, x),
+            Instruction::Opfxfb(x) => self.call_fxfb(bus, x),
+            Instruction::Unknown(opcode) => self.fail(
+                EmulatorError::UnknownOpcode { addr: self.pc, opcode }
+            )
         }
     }
 
+    // Step the background colour
+    fn call_02a0(&mut self, bus: &mut impl Bus) -> ProgramCounterKind {
+        trace!("BGCOL");
+        bus.cycle_background();
+        ProgramCounterKind::Next
+    }
+
     // CLRS
-    fn call_00e0(&mut self, bus: &mut Bus) -> ProgramCounterKind {
-        utils::log_str("CLRS");
+    fn call_00e0(&mut self, bus: &mut impl Bus) -> ProgramCounterKind {
+        trace!("CLRS");
         bus.clrs();
         ProgramCounterKind::Next
     }
 
     // RET
-    fn call_00ee(&mut self) -> ProgramCounterKind {
-        let addr = self.stack.pop().unwrap();
-        utils::log_str(&format!("RET {:x}", addr));
-        ProgramCounterKind::Jump(addr)
+    fn call_00ee(&mut self, bus: &impl Bus) -> ProgramCounterKind {
+        match self.pop(bus) {
+            Some(addr) => {
+                trace!("RET {:x}", addr);
+                ProgramCounterKind::Jump(addr)
+            }
+            None => self.fail(EmulatorError::StackUnderflow { addr: self.pc }),
+        }
+    }
+
+    // SYS, a call into the host's machine code, which isn't emulated
+    fn call_0nnn(&mut self) -> ProgramCounterKind {
+        trace!("SYS");
+        ProgramCounterKind::Next
     }
 
     // JMP
-    fn call_1nnn(&mut self, nnn: u16) -> ProgramCounterKind {
-        utils::log_str(&format!("JMP {:x}", nnn));
+    fn call_1nnn(&mut self, bus: &mut impl Bus, nnn: u16) -> ProgramCounterKind {
+        trace!("JMP {:x}", nnn);
+        if nnn == self.pc {
+            self.status = MachineStatus::Halted;
+        } else if let Some(reads_timer) = self.idle_loop(bus, nnn) {
+            self.status = if reads_timer && self.dt > 0 {
+                MachineStatus::WaitingForTimer
+            } else {
+                MachineStatus::Halted
+            };
+        }
         ProgramCounterKind::Jump(nnn)
     }
 
+    // A jump back to start over a few instructions that only read the
+    // delay timer and test registers, which would run again leaving every
+    // register as it is. Such a loop can only end once the timer changes,
+    // the result says whether it reads the timer at all
+    fn idle_loop(&self, bus: &mut impl Bus, start: u16) -> Option<bool> {
+        if start > self.pc || self.pc - start > 2 * IDLE_LOOP_LEN {
+            return None;
+        }
+
+        let mut v = self.v;
+        let mut reads_timer = false;
+        let mut addr = start;
+        while addr < self.pc {
+            let skip = match bus.fetch(addr) {
+                Instruction::Opfx07(x) => {
+                    v[x] = self.dt;
+                    reads_timer = true;
+                    false
+                }
+                Instruction::Op3xnn(x, nn) => v[x] == nn,
+                Instruction::Op4xnn(x, nn) => v[x] != nn,
+                Instruction::Op5xy0(x, y) => v[x] == v[y],
+                Instruction::Op9xy0(x, y) => v[x] != v[y],
+                _ => return None,
+            };
+            addr += if skip { 4 } else { 2 };
+        }
+
+        if addr == self.pc && v == self.v { Some(reads_timer) } else { None }
+    }
+
     // CALL
-    fn call_2nnn(&mut self, nnn: u16) -> ProgramCounterKind {
+    fn call_2nnn(&mut self, bus: &mut impl Bus, nnn: u16) -> ProgramCounterKind {
         let curr = self.pc + 2;
-        self.stack.push(curr);
-        utils::log_str(&format!("CALL {:x}", nnn));
-        ProgramCounterKind::Jump(nnn)
+        trace!("CALL {:x}", nnn);
+
+        if self.push(bus, curr) {ProgramCounterKind::Jump(nnn)}
+        else {self.fail(EmulatorError::StackOverflow { addr: self.pc })}
+    }
+
+    // Push a return address, false if the stack is full
+    fn push(&mut self, bus: &mut impl Bus, addr: u16) -> bool {
+        let mut limit = self.quirks.stack_depth.limit();
+
+        if self.quirks.stack_in_ram {
+            let size = limit.unwrap_or(VIP_STACK_SIZE).min(VIP_STACK_SIZE);
+            limit = Some(size);
+        }
+
+        if limit.is_some_and(|l| self.stack.len() >= l) {
+            return false;
+        }
+
+        if self.quirks.stack_in_ram {
+            let slot = VIP_STACK_TOP - 2 * (self.stack.len() as u16 + 1);
+            bus.memwrite(slot, (addr >> 8) as u8);
+            bus.memwrite(slot + 1, addr as u8);
+        }
+
+        self.stack.push(addr);
+        true
+    }
+
+    // Pop a return address, read back from RAM when the stack lives there
+    fn pop(&mut self, bus: &impl Bus) -> Option<u16> {
+        let addr = self.stack.pop()?;
+
+        if self.quirks.stack_in_ram {
+            let slot = VIP_STACK_TOP - 2 * (self.stack.len() as u16 + 1);
+            let hi = bus.memread(slot) as u16;
+            let lo = bus.memread(slot + 1) as u16;
+            return Some((hi << 8) | lo);
+        }
+
+        Some(addr)
     }
 
     // SE Vx KK
     fn call_3xnn(&mut self, x: usize, nn: u8) -> ProgramCounterKind {
-        utils::log_str(&format!("SE v{:x} {:x}", x, nn));
+        trace!("SE v{:x} {:x}", x, nn);
         if self.v[x] == nn {ProgramCounterKind::Skip}
         else {ProgramCounterKind::Next}
     }
 
     // SNE Vx KK
     fn call_4xnn( &mut self, x: usize, nn: u8) -> ProgramCounterKind {
-        utils::log_str(&format!("SNE v{:x} {:x}", x, nn));
+        trace!("SNE v{:x} {:x}", x, nn);
         if self.v[x] == nn {ProgramCounterKind::Next}
          else {ProgramCounterKind::Skip}
     }
 
     // SE Vx Vy
     fn call_5xy0(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("SE v{:x} v{:x}", x, y));
+        trace!("SE v{:x} v{:x}", x, y);
         if self.v[x] == self.v[y] {ProgramCounterKind::Skip}
         else {ProgramCounterKind::Next}
     }
 
+    // Vx = Vx + Vy, each nibble separately modulo 8
+    fn call_5xy1(&mut self, x: usize, y: usize) -> ProgramCounterKind {
+        trace!("ADD.N v{:x} v{:x}", x, y);
+        let hi = ((self.v[x] >> 4) + (self.v[y] >> 4)) & 7;
+        let lo = ((self.v[x] & 0xF) + (self.v[y] & 0xF)) & 7;
+        self.v[x] = (hi << 4) | lo;
+        ProgramCounterKind::Next
+    }
+
     // LD Vx NN
     fn call_6xnn(&mut self, x: usize, nn: u8) -> ProgramCounterKind {
-        utils::log_str(&format!("LD v{:x} {:x}", x, nn));
+        trace!("LD v{:x} {:x}", x, nn);
         self.v[x] = nn;
         ProgramCounterKind::Next
     }
 
     // ADD Vx NN
     fn call_7xnn(&mut self, x: usize, nn: u8) -> ProgramCounterKind {
-        utils::log_str(&format!("ADD v{:x} {:x}", x, nn));
+        trace!("ADD v{:x} {:x}", x, nn);
         let (sum, _) = self.v[x].overflowing_add(nn);
         self.v[x] = sum;
         ProgramCounterKind::Next
@@ -223,37 +575,47 @@ impl Cpu {
 
     // LD Vx = Vy
     fn call_8xy0(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD v{:x} v{:x}", x, y));
+        trace!("LD v{:x} v{:x}", x, y);
         self.v[x] = self.v[y] ;
         ProgramCounterKind::Next
     }
 
     // Vx = Vx | Vy
-     fn call_8xy1(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("OR v{:x} v{:x}", x, y));
-        self.v[x] = self.v[x] | self.v[y];
+    fn call_8xy1(&mut self, x: usize, y: usize) -> ProgramCounterKind {
+        trace!("OR v{:x} v{:x}", x, y);
+        self.v[x] |= self.v[y];
+        self.reset_vf();
         ProgramCounterKind::Next
     }
 
     // Vx = Vx & Vy
     fn call_8xy2(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("AND v{:x} v{:x}", x, y));
-        self.v[x] = self.v[x] & self.v[y];
+        trace!("AND v{:x} v{:x}", x, y);
+        self.v[x] &= self.v[y];
+        self.reset_vf();
         ProgramCounterKind::Next
     }
 
     // Vx = Vx ^ Vy
     fn call_8xy3(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("XOR v{:x} v{:x}", x, y));
-        self.v[x] = self.v[x] ^ self.v[y];
+        trace!("XOR v{:x} v{:x}", x, y);
+        self.v[x] ^= self.v[y];
+        self.reset_vf();
         ProgramCounterKind::Next
     }
 
+    // VIP logic ops clobber VF as a side effect
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
+    }
+
     // Vx = Vx + Vy; Vf = carry
     fn call_8xy4(&mut self, x: usize, y: usize) -> ProgramCounterKind {
         let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
-        utils::log_str(&format!("ADD v{:x} v{:x}", x, y));
-        utils::log_str(&format!("LD vf {:x}", carry as u8));
+        trace!("ADD v{:x} v{:x}", x, y);
+        trace!("LD vf {:x}", carry as u8);
         self.v[x] = sum;
         self.v[0xf] = if carry {1} else {0};
         ProgramCounterKind::Next
@@ -262,154 +624,199 @@ impl Cpu {
     // Vx = Vx - Vy; Vf = borrow
     fn call_8xy5(&mut self, x: usize, y: usize) -> ProgramCounterKind {
         let (sub, borrow) = self.v[x].overflowing_sub(self.v[y]);
-        utils::log_str(&format!("SUB v{:x} v{:x}", x, y));
-        utils::log_str(&format!("LD vf {:x}", borrow as u8));
+        trace!("SUB v{:x} v{:x}", x, y);
+        trace!("LD vf {:x}", borrow as u8);
         self.v[x] = sub;
         self.v[0xf] = if borrow {0} else {1};
         ProgramCounterKind::Next
     }
 
     // SHR Vx
-    fn call_8xy6(&mut self, x: usize, _y: usize) -> ProgramCounterKind {
-        let lsb = self.v[x] & 0x1;
-        utils::log_str(&format!("SHR v{:x}", x));
-        utils::log_str(&format!("LD vf {:x}", lsb));
+    fn call_8xy6(&mut self, x: usize, y: usize) -> ProgramCounterKind {
+        let src = if self.quirks.shifting {self.v[x]} else {self.v[y]};
+        let lsb = src & 0x1;
+        trace!("SHR v{:x}", x);
+        trace!("LD vf {:x}", lsb);
+        self.v[x] = src >> 1;
         self.v[0xF] = lsb;
-        self.v[x] >>= 1;
         ProgramCounterKind::Next
     }
 
     // Vx = Vy - Vx; Vf = Borrow
     fn call_8xy7(&mut self, x: usize, y: usize) -> ProgramCounterKind {
         let (sub, borrow) = self.v[y].overflowing_sub(self.v[x]);
-        utils::log_str(&format!("SUB v{:x} v{:x}", y, x));
-        utils::log_str(&format!("LD vf {:x}", borrow as u8));
+        trace!("SUB v{:x} v{:x}", y, x);
+        trace!("LD vf {:x}", borrow as u8);
         self.v[x] = sub;
         self.v[0xf] = if borrow {0} else {1};
         ProgramCounterKind::Next
    }
 
     // SHL Vx
-    fn call_8xye(&mut self, x: usize, _y: usize) -> ProgramCounterKind {
-        let msb = self.v[x] & 0x80;
-        utils::log_str(&format!("SHL v{:x}", x));
-        utils::log_str(&format!("LD vf {:x}", msb));
+    fn call_8xye(&mut self, x: usize, y: usize) -> ProgramCounterKind {
+        let src = if self.quirks.shifting {self.v[x]} else {self.v[y]};
+        let msb = src >> 7;
+        trace!("SHL v{:x}", x);
+        trace!("LD vf {:x}", msb);
+        self.v[x] = src << 1;
         self.v[0xF] = msb;
-        self.v[x] <<= 1;
         ProgramCounterKind::Next
     }
 
     // SNE Vx = Vy
     fn call_9xy0(&mut self, x: usize, y: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("SNE v{:x} v{:x}", x, y));
+        trace!("SNE v{:x} v{:x}", x, y);
         if self.v[x] == self.v[y] {ProgramCounterKind::Next}
         else {ProgramCounterKind::Skip}
     }
 
     // i = nnn
     fn call_annn(&mut self, nnn: u16) -> ProgramCounterKind {
-        utils::log_str(&format!("LD idx {:x}", nnn));
+        trace!("LD idx {:x}", nnn);
         self.idx = nnn;
         ProgramCounterKind::Next
     }
 
     // JMP V0 + nnn
-    fn call_bnnn(&mut self, nnn: u16) -> ProgramCounterKind {
-        utils::log_str(&format!("JMP v0 + {:x}", nnn));
-        ProgramCounterKind::Jump(self.v[0] as u16 + nnn)
+    fn call_bnnn(&mut self, bus: &impl Bus, x: usize, nnn: u16) -> ProgramCounterKind {
+        let offset = if self.quirks.jumping {self.v[x]} else {self.v[0]};
+        trace!("JMP v0 + {:x}", nnn);
+        ProgramCounterKind::Jump(wrap_address(bus, offset as usize + nnn as usize))
+    }
+
+    // COL Vx Vy N. With N = 0 Vx and Vx+1 give the 8x4 zones to colour,
+    // low nibble the first zone and high nibble how many more. Otherwise
+    // N rows from (Vx, Vx+1) in the 8 pixel column containing Vx
+    fn call_bxyn(&mut self, bus: &mut impl Bus, x: usize, y: usize, n: u8) -> ProgramCounterKind {
+        trace!("COL v{:x} v{:x} {:x}", x, y, n);
+        let horizontal = self.v[x] as usize;
+        let vertical = self.v[(x + 1) & 0xF] as usize;
+        let color = self.v[y] & 7;
+
+        if n == 0 {
+            let (column, columns) = (horizontal & 0xF, (horizontal >> 4) + 1);
+            let (zone, zones) = (vertical & 0xF, (vertical >> 4) + 1);
+            bus.set_foreground(column, zone * 4, columns, zones * 4, color);
+        } else {
+            bus.set_foreground(horizontal / 8, vertical, 1, n as usize, color);
+        }
+        ProgramCounterKind::Next
     }
 
     // Vx = RND & nnn
     fn call_cxnn(&mut self, x: usize, nn: u8) -> ProgramCounterKind {
-        let v = utils::get_random_u8();
-        utils::log_str(&format!("LD v{:x} RND + {:x}", x, nn));
+        let v = match &mut self.rng {
+            Some(rng) => rng.next_u8(),
+            None => utils::get_random_u8(),
+        };
+        trace!("LD v{:x} RND + {:x}", x, nn);
         self.v[x] = v & nn;
         ProgramCounterKind::Next
     }
 
     // DRAW x y n
-    fn call_dxyn(&mut self, bus: &mut Bus, x: usize, y: usize, n: u8) -> ProgramCounterKind {
-        utils::log_str(&format!("DRAW v{:x} v{:x} {:x}", x, y, n));
-        let vx = self.v[x as usize];
-        let vy = self.v[y as usize];
-        let collision = bus.draw(vx, vy, self.idx, n as u16);
+    fn call_dxyn(&mut self, bus: &mut impl Bus, x: usize, y: usize, n: u8) -> ProgramCounterKind {
+        trace!("DRAW v{:x} v{:x} {:x}", x, y, n);
+        let vx = self.v[x];
+        let vy = self.v[y];
+        let collision = bus.draw(vx, vy, self.idx, n as u16, self.quirks.clipping);
         self.v[0xf] = if collision {1} else {0};
+        self.awaiting_vblank = self.quirks.display_wait;
         ProgramCounterKind::Next
     }
 
     // SKIP if Keypressed
-    fn call_ex9e(&self, bus: &Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("SE KEYPRESS v{:x}", x));
-
-        match bus.get_pressed_key() {
-            None => ProgramCounterKind::Next,
-            Some(k) => if k == self.v[x] {ProgramCounterKind::Skip}
-                                    else {ProgramCounterKind::Next}
-        }
+    fn call_ex9e(&self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("SE KEYPRESS v{:x}", x);
+        if bus.is_key_pressed(self.v[x]) {ProgramCounterKind::Skip}
+        else {ProgramCounterKind::Next}
     }
 
     // SKIP if !Keypressed
-    fn call_exa1(&mut self, bus: &Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("SNE KEYPRESS v{:x}", x));
-        match bus.get_pressed_key() {
-            None => ProgramCounterKind::Next,
-            Some(k) => if k == self.v[x] {ProgramCounterKind::Next}
-                                    else {ProgramCounterKind::Skip}
-        }
+    fn call_exa1(&self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("SNE KEYPRESS v{:x}", x);
+        if bus.is_key_pressed(self.v[x]) {ProgramCounterKind::Next}
+        else {ProgramCounterKind::Skip}
+    }
+
+    // SKIP if Keypressed on the second keypad
+    fn call_exf2(&self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("SE KEYPRESS2 v{:x}", x);
+        if bus.is_key2_pressed(self.v[x]) {ProgramCounterKind::Skip}
+        else {ProgramCounterKind::Next}
+    }
+
+    // SKIP if !Keypressed on the second keypad
+    fn call_exf5(&self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("SNE KEYPRESS2 v{:x}", x);
+        if bus.is_key2_pressed(self.v[x]) {ProgramCounterKind::Next}
+        else {ProgramCounterKind::Skip}
     }
 
     // Vx = Delay
     fn call_fx07(&mut self, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD v{:x} dt", x));
+        trace!("LD v{:x} dt", x);
         self.v[x] = self.dt;
         ProgramCounterKind::Next
     }
 
-    // Await Vx keypress
-    fn call_fx0a(&self, bus: &Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("AWAIT KEYPRESS v{:x}", x));
-        match bus.get_pressed_key() {
-            None => ProgramCounterKind::Jump(self.pc), // Loop
-            Some(k) => if k == self.v[x] {ProgramCounterKind::Next}
-                                    else {ProgramCounterKind::Jump(self.pc)}
+    // Vx = Await keypress. Like the VIP the key is taken when it's
+    // released, not while it's still held
+    fn call_fx0a(&mut self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("AWAIT KEYPRESS v{:x}", x);
+        match self.awaited_key {
+            Some(k) if !bus.is_key_pressed(k) => {
+                self.awaited_key = None;
+                self.v[x] = k;
+                ProgramCounterKind::Next
+            }
+            Some(_) => {
+                self.status = MachineStatus::WaitingForKey;
+                ProgramCounterKind::Jump(self.pc) // Loop
+            }
+            None => {
+                self.awaited_key = bus.get_pressed_key();
+                self.status = MachineStatus::WaitingForKey;
+                ProgramCounterKind::Jump(self.pc) // Loop
+            }
         }
     }
 
     // Delay = Vx
     fn call_fx15(&mut self, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD dt v{:x}", x));
+        trace!("LD dt v{:x}", x);
         self.dt = self.v[x];
         ProgramCounterKind::Next
     }
 
     // Sound = Vx
     fn call_fx18(&mut self, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD st v{:x}", x));
+        trace!("LD st v{:x}", x);
         self.st = self.v[x];
         ProgramCounterKind::Next
     }
 
     // i = ADD Vx i
-    fn call_fx1e(&mut self, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("ADD v{:x} i", x));
-        let (sum, _) = (self.v[x] as u16).overflowing_add(self.idx);
-        self.idx = sum;
+    fn call_fx1e(&mut self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("ADD v{:x} i", x);
+        self.idx = wrap_address(bus, self.idx as usize + self.v[x] as usize);
         ProgramCounterKind::Next
     }
 
     // I = Sprite_addr
-    fn call_fx29(&mut self, x: usize) -> ProgramCounterKind {
-        let sprite_addr = (self.v[x] * 5) as u16;
-        utils::log_str(&format!("LD i SPRITE_ADDR {:x}", sprite_addr));
+    // Only the low nibble of Vx selects a glyph
+    fn call_fx29(&mut self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        let sprite_addr = bus.font_address() + (self.v[x] & 0xF) as u16 * GLYPH_SIZE;
+        trace!("LD i SPRITE_ADDR {:x}", sprite_addr);
         self.idx = sprite_addr;
         ProgramCounterKind::Next
     }
 
     // I..i + 2 = BCD(Vx)
-    fn call_fx33(&self, bus: &mut Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD I v{:x} / 100", x));
-        utils::log_str(&format!("LD I + 1 (v{:x} / 10) % 10", x));
-        utils::log_str(&format!("LD I + 2 (v{:x} % 100) % 10", x));
+    fn call_fx33(&self, bus: &mut impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("LD I v{:x} / 100", x);
+        trace!("LD I + 1 (v{:x} / 10) % 10", x);
+        trace!("LD I + 2 (v{:x} % 100) % 10", x);
         bus.memwrite(self.idx, self.v[x] / 100);
         bus.memwrite(self.idx + 1, (self.v[x] / 10) % 10);
         bus.memwrite(self.idx + 2, (self.v[x] % 100) % 10);
@@ -417,27 +824,814 @@ impl Cpu {
     }
 
     // MEM = V0..Vx
-    fn call_fx55(&self, bus: &mut Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("DUMP v0 ..v{:x}", x));
+    fn call_fx55(&mut self, bus: &mut impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("DUMP v0 ..v{:x}", x);
 
         for v in 0..=x {
             bus.memwrite(self.idx + v as u16, self.v[v]);
         }
 
+        self.increment_index(x);
+
         ProgramCounterKind::Next
     }
 
     // V0..Vx = MEM
-    fn call_fx65(&mut self, bus: &Bus, x: usize) -> ProgramCounterKind {
-        utils::log_str(&format!("LD v0 ..v{:x}", x));
+    fn call_fx65(&mut self, bus: &impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("LD v0 ..v{:x}", x);
 
         for v in 0..=x {
             self.v[v] = bus.memread(self.idx + v as u16);
         }
 
+        self.increment_index(x);
+
+        ProgramCounterKind::Next
+    }
+
+    // After FX55 and FX65, as the memory quirks say
+    fn increment_index(&mut self, x: usize) {
+        if self.quirks.memory {
+            self.idx += x as u16 + !self.quirks.memory_by_x as u16;
+        }
+    }
+
+    // OUT Vx
+    fn call_fxf8(&mut self, bus: &mut impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("OUT v{:x}", x);
+        bus.port_out(self.v[x]);
         ProgramCounterKind::Next
     }
 
+    // IN Vx, waits until the port has a byte
+    fn call_fxfb(&mut self, bus: &mut impl Bus, x: usize) -> ProgramCounterKind {
+        trace!("IN v{:x}", x);
+        match bus.port_in() {
+            None => {
+                self.status = MachineStatus::WaitingForKey;
+                ProgramCounterKind::Jump(self.pc)
+            }
+            Some(value) => {
+                self.v[x] = value;
+                ProgramCounterKind::Next
+            }
+        }
+    }
+
+}
+
+
+// I and jump targets wrap at the end of the machine's memory, 4 KiB on
+// CHIP-8 and 64 KiB on XO-CHIP
+fn wrap_address(bus: &impl Bus, addr: usize) -> u16 {
+    (addr % bus.memory_size()) as u16
+}
+
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SystemBus;
+    use crate::quirks::{Quirks, StackDepth};
+    use crate::font::FontSet;
+    use crate::profiler::FrameStats;
+    use crate::memory::XO_MEM_SIZE;
+
+    fn setup(quirks: Quirks) -> (Cpu, SystemBus) {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(quirks);
+        (cpu, SystemBus::new())
+    }
+
+    // Write opcode at the program counter and run it
+    fn exec(cpu: &mut Cpu, bus: &mut impl Bus, opcode: u16) {
+        bus.memwrite(cpu.pc, (opcode >> 8) as u8);
+        bus.memwrite(cpu.pc + 1, opcode as u8);
+        cpu.tick(bus);
+    }
+
+    #[test]
+    fn test_00e0_clears_screen() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        bus.draw(0, 0, 0, 5, true);
+        exec(&mut cpu, &mut bus, 0x00E0);
+        assert!(bus.get_vram().iter().all(|&px| px == 0));
+        assert_eq!(cpu.pc, PROG_START + 2);
+    }
+
+    #[test]
+    fn test_2nnn_00ee_call_and_return() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x2300);
+        assert_eq!(cpu.pc, 0x300);
+        assert_eq!(cpu.stack, vec![PROG_START + 2]);
+        exec(&mut cpu, &mut bus, 0x00EE);
+        assert_eq!(cpu.pc, PROG_START + 2);
+        assert!(cpu.stack.is_empty());
+    }
+
+    #[test]
+    fn test_stack_overflow_and_underflow() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x00EE);
+        assert_eq!(cpu.error(), Some(EmulatorError::StackUnderflow { addr: PROG_START }));
+
+        cpu.reset();
+        for _ in 0..12 {
+            exec(&mut cpu, &mut bus, 0x2200);
+        }
+        assert_eq!(cpu.status(), MachineStatus::Running);
+        exec(&mut cpu, &mut bus, 0x2200);
+        assert_eq!(cpu.error(), Some(EmulatorError::StackOverflow { addr: PROG_START }));
+    }
+
+    #[test]
+    fn test_unlimited_stack() {
+        let mut quirks = Quirks::chip8();
+        quirks.stack_depth = StackDepth::Unlimited;
+        let (mut cpu, mut bus) = setup(quirks);
+        for _ in 0..100 {
+            exec(&mut cpu, &mut bus, 0x2200);
+        }
+        assert_eq!(cpu.status(), MachineStatus::Running);
+    }
+
+    #[test]
+    fn test_stack_in_ram_at_vip_addresses() {
+        let mut quirks = Quirks::chip8();
+        quirks.stack_in_ram = true;
+        let (mut cpu, mut bus) = setup(quirks);
+        exec(&mut cpu, &mut bus, 0x2300);
+        assert_eq!(bus.memread(0xECE), 0x02);
+        assert_eq!(bus.memread(0xECF), 0x02);
+        bus.memwrite(0xECF, 0x40);
+        exec(&mut cpu, &mut bus, 0x00EE);
+        assert_eq!(cpu.pc, 0x240);
+    }
+
+    #[test]
+    fn test_1nnn_jumps() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x1ABC);
+        assert_eq!(cpu.pc, 0xABC);
+    }
+
+    #[test]
+    fn test_3xnn_4xnn_skips() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[3] = 0x42;
+        exec(&mut cpu, &mut bus, 0x3342);
+        assert_eq!(cpu.pc, PROG_START + 4);
+        exec(&mut cpu, &mut bus, 0x3341);
+        assert_eq!(cpu.pc, PROG_START + 6);
+        exec(&mut cpu, &mut bus, 0x4342);
+        assert_eq!(cpu.pc, PROG_START + 8);
+        exec(&mut cpu, &mut bus, 0x4341);
+        assert_eq!(cpu.pc, PROG_START + 12);
+    }
+
+    #[test]
+    fn test_5xy0_9xy0_skips() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[1] = 7;
+        cpu.v[2] = 7;
+        exec(&mut cpu, &mut bus, 0x5120);
+        assert_eq!(cpu.pc, PROG_START + 4);
+        exec(&mut cpu, &mut bus, 0x9120);
+        assert_eq!(cpu.pc, PROG_START + 6);
+        cpu.v[2] = 8;
+        exec(&mut cpu, &mut bus, 0x5120);
+        assert_eq!(cpu.pc, PROG_START + 8);
+        exec(&mut cpu, &mut bus, 0x9120);
+        assert_eq!(cpu.pc, PROG_START + 12);
+    }
+
+    #[test]
+    fn test_6xnn_7xnn_load_and_add_wrap_without_flag() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x6AFF);
+        assert_eq!(cpu.v[0xA], 0xFF);
+        exec(&mut cpu, &mut bus, 0x7A02);
+        assert_eq!(cpu.v[0xA], 0x01);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_8xy0_to_8xy3_logic() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[1] = 0b1100;
+        cpu.v[2] = 0b1010;
+        exec(&mut cpu, &mut bus, 0x8310);
+        assert_eq!(cpu.v[3], 0b1100);
+        cpu.v[0xF] = 5;
+        exec(&mut cpu, &mut bus, 0x8321);
+        assert_eq!(cpu.v[3], 0b1110);
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.v[3] = 0b1100;
+        exec(&mut cpu, &mut bus, 0x8322);
+        assert_eq!(cpu.v[3], 0b1000);
+        cpu.v[3] = 0b1100;
+        exec(&mut cpu, &mut bus, 0x8323);
+        assert_eq!(cpu.v[3], 0b0110);
+    }
+
+    #[test]
+    fn test_logic_keeps_vf_without_vf_reset_quirk() {
+        let (mut cpu, mut bus) = setup(Quirks::schip());
+        cpu.v[0xF] = 5;
+        exec(&mut cpu, &mut bus, 0x8121);
+        assert_eq!(cpu.v[0xF], 5);
+    }
+
+    #[test]
+    fn test_8xy4_carry() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 0xFF;
+        cpu.v[1] = 0x02;
+        exec(&mut cpu, &mut bus, 0x8014);
+        assert_eq!(cpu.v[0], 0x01);
+        assert_eq!(cpu.v[0xF], 1);
+        exec(&mut cpu, &mut bus, 0x8014);
+        assert_eq!(cpu.v[0], 0x03);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_8xy5_8xy7_borrow() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 0x01;
+        cpu.v[1] = 0x02;
+        exec(&mut cpu, &mut bus, 0x8015);
+        assert_eq!(cpu.v[0], 0xFF);
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.v[0] = 0x03;
+        exec(&mut cpu, &mut bus, 0x8015);
+        assert_eq!(cpu.v[0], 0x01);
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.v[0] = 0x01;
+        exec(&mut cpu, &mut bus, 0x8017);
+        assert_eq!(cpu.v[0], 0x01);
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.v[0] = 0x03;
+        exec(&mut cpu, &mut bus, 0x8017);
+        assert_eq!(cpu.v[0], 0xFF);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_flag_wins_when_vf_is_the_target() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0xF] = 0xFF;
+        cpu.v[1] = 0x01;
+        exec(&mut cpu, &mut bus, 0x8F14);
+        assert_eq!(cpu.v[0xF], 1);
+        cpu.v[0xF] = 0x81;
+        exec(&mut cpu, &mut bus, 0x8FFE);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_shifts_use_vy_on_vip() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[1] = 0x81;
+        exec(&mut cpu, &mut bus, 0x8016);
+        assert_eq!(cpu.v[0], 0x40);
+        assert_eq!(cpu.v[0xF], 1);
+        exec(&mut cpu, &mut bus, 0x801E);
+        assert_eq!(cpu.v[0], 0x02);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_shifts_in_place_with_shifting_quirk() {
+        let (mut cpu, mut bus) = setup(Quirks::schip());
+        cpu.v[0] = 0x02;
+        cpu.v[1] = 0xFF;
+        exec(&mut cpu, &mut bus, 0x8016);
+        assert_eq!(cpu.v[0], 0x01);
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.v[0] = 0x80;
+        exec(&mut cpu, &mut bus, 0x801E);
+        assert_eq!(cpu.v[0], 0x00);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_annn_bnnn() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0xA123);
+        assert_eq!(cpu.idx, 0x123);
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        exec(&mut cpu, &mut bus, 0xB300);
+        assert_eq!(cpu.pc, 0x310);
+    }
+
+    #[test]
+    fn test_bxnn_with_jumping_quirk() {
+        let (mut cpu, mut bus) = setup(Quirks::schip());
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        exec(&mut cpu, &mut bus, 0xB300);
+        assert_eq!(cpu.pc, 0x320);
+    }
+
+    #[test]
+    fn test_cxnn_masks_random() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        for _ in 0..32 {
+            exec(&mut cpu, &mut bus, 0xC50F);
+            assert_eq!(cpu.v[5] & 0xF0, 0);
+        }
+    }
+
+    #[test]
+    fn test_dxyn_draws_and_reports_collision() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 62;
+        cpu.v[1] = 31;
+        exec(&mut cpu, &mut bus, 0xA000);
+        exec(&mut cpu, &mut bus, 0xD011);
+        assert_eq!(cpu.v[0xF], 0);
+        assert_eq!(bus.get_vram()[31 * 64 + 62], 1);
+        exec(&mut cpu, &mut bus, 0xD011);
+        assert_eq!(cpu.v[0xF], 1);
+        assert!(bus.get_vram().iter().all(|&px| px == 0));
+    }
+
+    #[test]
+    fn test_display_wait_ends_frame_until_timers_tick() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0xD001);
+        assert!(cpu.is_awaiting_vblank());
+        cpu.tick_timers(&mut bus);
+        assert!(!cpu.is_awaiting_vblank());
+
+        let (mut cpu, mut bus) = setup(Quirks::schip());
+        exec(&mut cpu, &mut bus, 0xD001);
+        assert!(!cpu.is_awaiting_vblank());
+    }
+
+    #[test]
+    fn test_dxyn_clips_or_wraps_per_quirk() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 60 + 64;
+        exec(&mut cpu, &mut bus, 0xA000);
+        exec(&mut cpu, &mut bus, 0xD011);
+        assert_eq!(&bus.get_vram()[60..64], &[1, 1, 1, 1]);
+        assert_eq!(bus.get_vram()[0], 0);
+
+        let (mut cpu, mut bus) = setup(Quirks::xochip());
+        cpu.v[0] = 62;
+        exec(&mut cpu, &mut bus, 0xA000);
+        exec(&mut cpu, &mut bus, 0xD011);
+        assert_eq!(&bus.get_vram()[0..2], &[1, 1]);
+    }
+
+    #[test]
+    fn test_dxyn_clips_at_the_edges_of_taller_displays() {
+        // Three full rows at 0x300, drawn from near the bottom right corner
+        let draw = |quirks: Quirks, width: usize, x: u8, y: u8| {
+            let (mut cpu, mut bus) = setup(quirks);
+            bus.set_display_size(width, 64);
+            for addr in 0x300..0x303 {
+                bus.memwrite(addr, 0xFF);
+            }
+            cpu.v[0] = x;
+            cpu.v[1] = y;
+            exec(&mut cpu, &mut bus, 0xA300);
+            exec(&mut cpu, &mut bus, 0xD013);
+            bus.get_vram().iter().enumerate()
+                .filter(|(_, &px)| px == 1)
+                .map(|(addr, _)| (addr % width, addr / width))
+                .collect::<Vec<_>>()
+        };
+
+        // HIRES 64x64, the origin wraps at 64 rows, not 32
+        let lit = draw(Quirks::chip8(), 64, 60, 62 + 64);
+        assert_eq!(lit.len(), 8);
+        assert!(lit.iter().all(|&(x, y)| x >= 60 && y >= 62));
+
+        let lit = draw(Quirks::xochip(), 64, 60, 62);
+        assert_eq!(lit.len(), 24);
+        assert!(lit.contains(&(0, 0)) && lit.contains(&(63, 63)));
+
+        // CHIP-10 128x64
+        let lit = draw(Quirks::chip8(), 128, 124, 63);
+        assert_eq!(lit, vec![(124, 63), (125, 63), (126, 63), (127, 63)]);
+
+        let lit = draw(Quirks::xochip(), 128, 124, 63);
+        assert_eq!(lit.len(), 24);
+        assert!(lit.contains(&(3, 1)) && !lit.contains(&(4, 1)));
+    }
+
+    #[test]
+    fn test_ex9e_exa1_keys() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 0xA;
+        exec(&mut cpu, &mut bus, 0xE09E);
+        assert_eq!(cpu.pc, PROG_START + 2);
+        exec(&mut cpu, &mut bus, 0xE0A1);
+        assert_eq!(cpu.pc, PROG_START + 6);
+        bus.press_key(0xA);
+        exec(&mut cpu, &mut bus, 0xE09E);
+        assert_eq!(cpu.pc, PROG_START + 10);
+        exec(&mut cpu, &mut bus, 0xE0A1);
+        assert_eq!(cpu.pc, PROG_START + 12);
+    }
+
+    #[test]
+    fn test_fx0a_blocks_until_key_release() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0xF30A);
+        assert_eq!(cpu.pc, PROG_START);
+        assert_eq!(cpu.status(), MachineStatus::WaitingForKey);
+
+        // Held keys don't count until they come back up
+        bus.press_key(0x7);
+        exec(&mut cpu, &mut bus, 0xF30A);
+        exec(&mut cpu, &mut bus, 0xF30A);
+        assert_eq!(cpu.pc, PROG_START);
+        assert_eq!(cpu.status(), MachineStatus::WaitingForKey);
+        assert_eq!(cpu.v[3], 0);
+
+        bus.release_key(0x7);
+        exec(&mut cpu, &mut bus, 0xF30A);
+        assert_eq!(cpu.pc, PROG_START + 2);
+        assert_eq!(cpu.status(), MachineStatus::Running);
+        assert_eq!(cpu.v[3], 0x7);
+    }
+
+    #[test]
+    fn test_timers() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[2] = 10;
+        exec(&mut cpu, &mut bus, 0xF215);
+        exec(&mut cpu, &mut bus, 0xF218);
+        assert_eq!(cpu.st, 10);
+        exec(&mut cpu, &mut bus, 0xF107);
+        assert_eq!(cpu.v[1], 10);
+        cpu.tick_timers(&mut bus);
+        exec(&mut cpu, &mut bus, 0xF107);
+        assert_eq!(cpu.v[1], 9);
+        assert!(bus.is_sound_on());
+    }
+
+    #[test]
+    fn test_fx1e_fx29() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[0] = 0xF;
+        exec(&mut cpu, &mut bus, 0xAFFF);
+        exec(&mut cpu, &mut bus, 0xF01E);
+        assert_eq!(cpu.idx, 0x00E);
+        exec(&mut cpu, &mut bus, 0xF029);
+        assert_eq!(cpu.idx, 0xF * 5);
+        cpu.v[0] = 0x3A;
+        exec(&mut cpu, &mut bus, 0xF029);
+        assert_eq!(cpu.idx, 0xA * 5);
+
+        // XO-CHIP's 64 KiB are all reachable
+        let (mut cpu, mut bus) = setup(Quirks::xochip());
+        bus.set_memory_size(XO_MEM_SIZE);
+        cpu.v[0] = 0x20;
+        exec(&mut cpu, &mut bus, 0xAFF0);
+        exec(&mut cpu, &mut bus, 0xF01E);
+        assert_eq!(cpu.idx, 0x1010);
+        exec(&mut cpu, &mut bus, 0xBFF0);
+        assert_eq!(cpu.pc, 0x1010);
+    }
+
+    #[test]
+    fn test_profiler_counts() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        // 200: call 206, 202: draw, 204: halt, 206: v0 = 1, 208: return
+        let rom = [0x22, 0x06, 0xD0, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE];
+        for (i, byte) in rom.iter().enumerate() {
+            bus.memwrite(PROG_START + i as u16, *byte);
+        }
+        cpu.set_profiling(true);
+        for _ in 0..6 {
+            cpu.tick(&mut bus);
+        }
+        cpu.tick_timers(&mut bus);
+
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.instructions(), 5);
+        assert_eq!(profiler.hotspots(1)[0].addr, 0x200);
+        assert_eq!(profiler.opcode_counts().len(), 5);
+        let sub = profiler.subroutines().into_iter().find(|s| s.entry == 0x206).unwrap();
+        assert_eq!((sub.calls, sub.instructions), (1, 2));
+        assert_eq!(profiler.frames(), &[FrameStats { draws: 1, collisions: 0 }]);
+        assert_eq!(profiler.folded_stacks(), "main 3\nmain;sub_0206 2\n");
+    }
+
+    // Flat memory that logs every access, no display or keys
+    struct RecordingBus {
+        mem: Vec<u8>,
+        writes: Vec<(u16, u8)>,
+        draws: usize,
+    }
+
+    impl Bus for RecordingBus {
+        fn memread(&self, addr: u16) -> u8 { self.mem[addr as usize] }
+        fn memwrite(&mut self, addr: u16, val: u8) {
+            self.writes.push((addr, val));
+            self.mem[addr as usize] = val;
+        }
+        fn draw(&mut self, _: u8, _: u8, _: u16, _: u16, _: bool) -> bool {
+            self.draws += 1;
+            false
+        }
+        fn clrs(&mut self) {}
+        fn is_key_pressed(&self, _: u8) -> bool { false }
+        fn get_pressed_key(&self) -> Option<u8> { None }
+        fn is_sound_on(&self) -> bool { false }
+        fn set_sound_on(&mut self) {}
+        fn set_sound_off(&mut self) {}
+    }
+
+    #[test]
+    fn test_runs_against_custom_bus() {
+        let mut bus = RecordingBus { mem: vec![0; 4096], writes: Vec::new(), draws: 0 };
+        // 200: v0 = 123, 202: I = 300, 204: BCD v0, 206: draw, 208: halt
+        let rom = [0x60, 0x7B, 0xA3, 0x00, 0xF0, 0x33, 0xD0, 0x01, 0x12, 0x08];
+        bus.mem[0x200..0x200 + rom.len()].copy_from_slice(&rom);
+
+        let mut cpu = Cpu::new();
+        for _ in 0..5 {
+            cpu.tick(&mut bus);
+        }
+
+        assert_eq!(bus.writes, vec![(0x300, 1), (0x301, 2), (0x302, 3)]);
+        assert_eq!(bus.draws, 1);
+        assert_eq!(cpu.status(), MachineStatus::Halted);
+    }
+
+    #[test]
+    fn test_chip8x_opcodes_rejected_on_chip8() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0xE0F2);
+        assert_eq!(cpu.error(), Some(EmulatorError::UnknownOpcode { addr: 0x200, opcode: 0xE0F2 }));
+
+        // 02A0 is just a machine code call elsewhere
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x02A0);
+        assert_eq!((cpu.pc, cpu.status()), (0x202, MachineStatus::Running));
+    }
+
+    #[test]
+    fn test_sys_calls_are_skipped() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        for opcode in [0x0000, 0x0123, 0x00FF].iter() {
+            exec(&mut cpu, &mut bus, *opcode);
+        }
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!(cpu.status(), MachineStatus::Running);
+    }
+
+    #[test]
+    fn test_chip8x_colours() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8x());
+        exec(&mut cpu, &mut bus, 0x02A0);  // Background blue -> black
+
+        // Zone columns 1-2, rows 0-3 green, then 2 rows at (0, 8) yellow
+        cpu.v[0] = 0x11;
+        cpu.v[1] = 0x00;
+        cpu.v[2] = 4;
+        exec(&mut cpu, &mut bus, 0xB020);
+        cpu.v[0] = 0;
+        cpu.v[1] = 8;
+        cpu.v[2] = 5;
+        exec(&mut cpu, &mut bus, 0xB022);
+
+        // Light pixels at (8, 0), (0, 8) and (0, 10)
+        bus.memwrite(0x300, 0xFF);
+        cpu.idx = 0x300;
+        cpu.v[3] = 8;
+        cpu.v[4] = 0;
+        exec(&mut cpu, &mut bus, 0xD341);
+        cpu.v[3] = 0;
+        cpu.v[4] = 8;
+        exec(&mut cpu, &mut bus, 0xD341);
+        cpu.v[4] = 10;
+        exec(&mut cpu, &mut bus, 0xD341);
+
+        let colors = bus.get_color_vram();
+        assert_eq!(colors[8], 4);
+        assert_eq!(colors[8 * 64], 5);
+        assert_eq!(colors[10 * 64], 1);  // Past the 2 yellow rows, still red
+        assert_eq!(colors[1], 0);        // Unlit, black background
+    }
+
+    #[test]
+    fn test_chip8x_keypad_ports_and_nibble_add() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8x());
+        cpu.v[0] = 0x7;
+        bus.press_key2(0x7);
+        exec(&mut cpu, &mut bus, 0xE0F2);
+        assert_eq!(cpu.pc, 0x204);
+        exec(&mut cpu, &mut bus, 0xE0F5);
+        assert_eq!(cpu.pc, 0x206);
+
+        cpu.v[1] = 0x56;
+        cpu.v[2] = 0x35;
+        exec(&mut cpu, &mut bus, 0x5121);
+        assert_eq!(cpu.v[1], 0x03);
+
+        exec(&mut cpu, &mut bus, 0xF1F8);
+        assert_eq!(bus.port_output(), 0x03);
+        exec(&mut cpu, &mut bus, 0xF3FB);
+        assert_eq!(cpu.status(), MachineStatus::WaitingForKey);
+        bus.set_port_input(0x42);
+        exec(&mut cpu, &mut bus, 0xF3FB);
+        assert_eq!(cpu.v[3], 0x42);
+    }
+
+    #[test]
+    fn test_font_address() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        assert!(bus.set_font_address(0x050));
+        assert!(!bus.set_font_address(0xFFF));
+        bus.set_font(FontSet::Vip.sprites());
+        cpu.v[0] = 0x1;
+        exec(&mut cpu, &mut bus, 0xF029);
+        assert_eq!(cpu.idx, 0x055);
+        assert_eq!(bus.memread(0x055), 0x60);
+        assert_eq!(bus.memread(0x005), 0x00);
+
+        bus.clear_memory_from(0);
+        assert_eq!(bus.memread(0x055), 0x60);
+        bus.set_memory_size(XO_MEM_SIZE);
+        assert_eq!(bus.font_address(), 0x050);
+        assert_eq!(bus.memread(0x055), 0x60);
+    }
+
+    #[test]
+    fn test_fx33_bcd() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v[4] = 254;
+        exec(&mut cpu, &mut bus, 0xA300);
+        exec(&mut cpu, &mut bus, 0xF433);
+        assert_eq!(bus.memread(0x300), 2);
+        assert_eq!(bus.memread(0x301), 5);
+        assert_eq!(bus.memread(0x302), 4);
+        assert_eq!(cpu.idx, 0x300);
+    }
+
+    #[test]
+    fn test_fx55_fx65_increment_i_on_vip() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.v = [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        exec(&mut cpu, &mut bus, 0xA300);
+        exec(&mut cpu, &mut bus, 0xF355);
+        assert_eq!(cpu.idx, 0x304);
+        assert_eq!(bus.memread(0x303), 4);
+        cpu.v = [0; 16];
+        exec(&mut cpu, &mut bus, 0xA300);
+        exec(&mut cpu, &mut bus, 0xF265);
+        assert_eq!(&cpu.v[0..4], &[1, 2, 3, 0]);
+        assert_eq!(cpu.idx, 0x303);
+    }
+
+    #[test]
+    fn test_fx55_fx65_increment_i_by_x() {
+        let mut quirks = Quirks::chip8();
+        quirks.memory_by_x = true;
+        let (mut cpu, mut bus) = setup(quirks);
+        exec(&mut cpu, &mut bus, 0xA300);
+        exec(&mut cpu, &mut bus, 0xF355);
+        assert_eq!(cpu.idx, 0x303);
+        exec(&mut cpu, &mut bus, 0xF265);
+        assert_eq!(cpu.idx, 0x305);
+    }
+
+    #[test]
+    fn test_fx55_fx65_keep_i_without_memory_quirk() {
+        let (mut cpu, mut bus) = setup(Quirks::schip());
+        exec(&mut cpu, &mut bus, 0xA300);
+        exec(&mut cpu, &mut bus, 0xF355);
+        assert_eq!(cpu.idx, 0x300);
+        exec(&mut cpu, &mut bus, 0xF365);
+        assert_eq!(cpu.idx, 0x300);
+    }
+
+    #[test]
+    fn test_memwrite_invalidates_decoded_instruction() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x6001);
+        cpu.pc = PROG_START;
+        bus.memwrite(PROG_START + 1, 0x02);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.v[0], 0x02);
+    }
+
+    #[test]
+    fn test_unknown_opcode_stops_with_error() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x5121);
+        assert_eq!(cpu.pc, PROG_START);
+        assert_eq!(cpu.status(), MachineStatus::Errored);
+        assert_eq!(
+            cpu.error(),
+            Some(EmulatorError::UnknownOpcode { addr: PROG_START, opcode: 0x5121 })
+        );
+    }
+
+    #[test]
+    fn test_running_off_the_end_of_memory_stops_with_error() {
+        let (mut cpu, mut bus) = setup(Quirks::xochip());
+        bus.set_memory_size(XO_MEM_SIZE);
+        cpu.pc = 0xFFFE;
+        exec(&mut cpu, &mut bus, 0x6001);
+        assert_eq!((cpu.pc, cpu.v[0]), (0x0000, 1));
+        assert_eq!(cpu.status(), MachineStatus::Errored);
+        assert_eq!(cpu.error(), Some(EmulatorError::EndOfMemory { addr: 0xFFFE }));
+
+        // A skip from the last instruction of 4 KiB
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        cpu.pc = 0xFFE;
+        exec(&mut cpu, &mut bus, 0x3000);
+        assert_eq!((cpu.pc, cpu.status()), (0x002, MachineStatus::Errored));
+    }
+
+    #[test]
+    fn test_self_jump_halts() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        exec(&mut cpu, &mut bus, 0x1200);
+        assert_eq!(cpu.status(), MachineStatus::Halted);
+        exec(&mut cpu, &mut bus, 0x6001);
+        assert_eq!(cpu.v[0], 0);
+        cpu.reset();
+        assert_eq!(cpu.status(), MachineStatus::Running);
+    }
+
+    #[test]
+    fn test_debugger_writes_resume_a_halted_cpu() {
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        bus.memwrite(PROG_START, 0x12);
+        bus.memwrite(PROG_START + 1, 0x00);
+        bus.memwrite(0x300, 0x61);
+        bus.memwrite(0x301, 0x07);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.status(), MachineStatus::Halted);
+
+        cpu.set_pc(0x300);
+        assert_eq!(cpu.status(), MachineStatus::Running);
+        cpu.tick(&mut bus);
+        assert_eq!((cpu.pc, cpu.v[1]), (0x302, 7));
+
+        cpu.set_pc(PROG_START);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.status(), MachineStatus::Halted);
+        cpu.set_register(0, 1);
+        assert_eq!(cpu.status(), MachineStatus::Running);
+
+        // An unknown opcode stops the cpu until the pc moves past it
+        exec(&mut cpu, &mut bus, 0x5121);
+        assert_eq!(cpu.status(), MachineStatus::Errored);
+        cpu.set_register(0, 2);
+        assert_eq!(cpu.status(), MachineStatus::Errored);
+        cpu.set_pc(0x300);
+        assert_eq!((cpu.status(), cpu.error()), (MachineStatus::Running, None));
+    }
+
+    #[test]
+    fn test_delay_timer_poll_waits_for_timer() {
+        // 200: v0 = dt, 202: skip if v0 == 0, 204: jump 200, 206: halt
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        for (offset, byte) in [0xF0, 0x07, 0x30, 0x00, 0x12, 0x00, 0x12, 0x06].iter().enumerate() {
+            bus.memwrite(PROG_START + offset as u16, *byte);
+        }
+        cpu.dt = 2;
+
+        for _ in 0..3 {
+            cpu.tick(&mut bus);
+        }
+        assert_eq!(cpu.status(), MachineStatus::WaitingForTimer);
+
+        // The frame ends at the jump, once the timer runs out the loop exits
+        cpu.run_frame(&mut bus, 100);
+        assert_eq!((cpu.pc, cpu.dt), (PROG_START, 1));
+        cpu.run_frame(&mut bus, 100);
+        assert_eq!((cpu.pc, cpu.dt), (PROG_START, 0));
+        cpu.run_frame(&mut bus, 100);
+        assert_eq!((cpu.pc, cpu.status()), (0x206, MachineStatus::Halted));
+
+        // A loop that never reads the timer can't end at all
+        let (mut cpu, mut bus) = setup(Quirks::chip8());
+        for (offset, byte) in [0x30, 0x05, 0x12, 0x00].iter().enumerate() {
+            bus.memwrite(PROG_START + offset as u16, *byte);
+        }
+        cpu.dt = 10;
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        assert_eq!(cpu.status(), MachineStatus::Halted);
+    }
+}
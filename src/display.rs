@@ -1,20 +1,44 @@
-/// Display controls the display for chip_8 console emulator
+//! Display controls the display for chip_8 console emulator
+//!
+//! The resolution depends on the platform and is picked when a ROM loads
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
 
-const VRAM_SIZE: usize = HEIGHT * WIDTH;
+// HIRES CHIP-8 doubles the rows, CHIP-10 doubles both
+pub const HIRES_HEIGHT: usize = 64;
+pub const CHIP10_WIDTH: usize = 128;
 
+// CHIP-8X colours foreground in cells 8 pixels wide and 1 tall
+const COLOR_CELL_WIDTH: usize = 8;
 
-// Display represented by a 1D array of bytes
-pub struct Display {
-    vram: [u8; VRAM_SIZE],
-}
+// VP-590 colours as 0xRRGGBB, indexed by the colour number in VY
+pub const PALETTE: [u32; 8] = [
+    0x000000,  // Black
+    0xFF0000,  // Red
+    0x0000FF,  // Blue
+    0xFF00FF,  // Violet
+    0x00FF00,  // Green
+    0xFFFF00,  // Yellow
+    0x00FFFF,  // Aqua
+    0xFFFFFF,  // White
+];
+
+// 02A0 steps the background through blue, black, green and red
+const BACKGROUNDS: [u8; 4] = [2, 0, 4, 1];
+
+const DEFAULT_FOREGROUND: u8 = 1;
 
 
-// Get memory address from 2D coords
-pub fn get_addr_from_xy(x: usize, y: usize) -> usize {
-    (y * WIDTH) + x
+// Display represented by a 1D array of bytes
+#[derive(Clone)]
+pub struct Display {
+    width: usize,
+    height: usize,
+    vram: Vec<u8>,
+    colors: Vec<u8>,       // Foreground colour per cell
+    background: usize,     // Index into BACKGROUNDS
+    color_vram: Vec<u8>,   // Palette index per pixel, built on request
 }
 
 
@@ -22,31 +46,68 @@ impl Display {
 
     // All pixels are set to 0 when display is initialized
     pub fn new() ->  Display {
-        Display { vram: [0u8; VRAM_SIZE] }
+        Display::with_size(WIDTH, HEIGHT)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> Display {
+        Display {
+            width,
+            height,
+            vram: vec![0u8; width * height],
+            colors: vec![DEFAULT_FOREGROUND; width / COLOR_CELL_WIDTH * height],
+            background: 0,
+            color_vram: vec![0u8; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Get memory address from 2D coords
+    fn get_addr_from_xy(&self, x: usize, y: usize) -> usize {
+        (y * self.width) + x
+    }
+
+    // Back to red on blue, 00E0 leaves colours alone
+    pub fn reset_colors(&mut self) {
+        self.colors.iter_mut().for_each(|c| *c = DEFAULT_FOREGROUND);
+        self.background = 0;
     }
 
     // Sets all pixels to false
     pub fn clrs(&mut self) {
-        self.vram = [0u8; VRAM_SIZE];
+        self.vram.iter_mut().for_each(|px| *px = 0);
     }
 
-    // Draw sprite from memslice, starting at (x, y), return true if collision
-    pub fn draw_sprite(&mut self, x: u8, y: u8, memslice: &[u8]) -> bool {
-        let origin_x = x as usize;
-        let origin_y = y as usize;
-        let sprite_len = memslice.len();
+    // Draw sprite from memslice, starting at (x, y), return true if collision.
+    // The origin always wraps onto the screen, with clip set the rest of
+    // the sprite is cut off at the edges instead of wrapping around.
+    // Every DXYN comes through here, so the 64x64 HIRES and 128x64
+    // CHIP-10 displays clip at their own edges
+    pub fn draw_sprite(&mut self, x: u8, y: u8, memslice: &[u8], clip: bool) -> bool {
+        let (width, height) = (self.width, self.height);
+        let origin_x = x as usize % width;
+        let origin_y = y as usize % height;
 
         let mut collision = false;
 
-        for y in 0..sprite_len {
-            let byte = memslice[y];
-
+        for (y, byte) in memslice.iter().cloned().enumerate() {
             for x in 0..8 {
                 // Since sprites are binary coded
                 let px = (byte >> (7 - x)) & 0b000_0001;
-                let x_coord = (x + origin_x) % WIDTH;
-                let y_coord = (y + origin_y) % HEIGHT;
-                let vram_addr = get_addr_from_xy(x_coord, y_coord);
+
+                if clip && (x + origin_x >= width || y + origin_y >= height) {
+                    continue;
+                }
+
+                let x_coord = (x + origin_x) % width;
+                let y_coord = (y + origin_y) % height;
+                let vram_addr = self.get_addr_from_xy(x_coord, y_coord);
                 let prev_px = self.vram[vram_addr];
 
                 self.vram[vram_addr] ^= px;  // Save xor'd value
@@ -60,8 +121,43 @@ impl Display {
         collision
     }
 
-    // Get copy of the display buffer
-    pub fn get_vram(&self) -> Vec<u8> {
-        self.vram.clone().to_vec()
+    // Get the display buffer
+    pub fn get_vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn cycle_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUNDS.len();
+    }
+
+    // Colour columns x.. of 8 pixels and rows y.. of single pixels,
+    // both wrap around the screen
+    pub fn set_foreground(&mut self, x: usize, y: usize, columns: usize, rows: usize, color: u8) {
+        let cell_columns = self.width / COLOR_CELL_WIDTH;
+        for row in y..y + rows {
+            for column in x..x + columns {
+                let cell = (row % self.height) * cell_columns + column % cell_columns;
+                self.colors[cell] = color & 7;
+            }
+        }
+    }
+
+    // Palette index per pixel, lit pixels take their cell's colour
+    pub fn get_color_vram(&mut self) -> &[u8] {
+        let background = BACKGROUNDS[self.background];
+        let (width, cell_columns) = (self.width, self.width / COLOR_CELL_WIDTH);
+        for (addr, px) in self.vram.iter().enumerate() {
+            let cell = (addr / width) * cell_columns + (addr % width) / COLOR_CELL_WIDTH;
+            self.color_vram[addr] = if *px == 1 { self.colors[cell] } else { background };
+        }
+        &self.color_vram
     }
 }
+
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
@@ -0,0 +1,246 @@
+//! Reinforcement learning environment
+//!
+//! A gym style wrapper for training agents natively: `reset` starts an
+//! episode, `step` holds the action's keys for a number of frames and
+//! returns the framebuffer, the reward and whether the episode is over.
+//! Rewards come from a per-ROM `Task` that reads the machine's RAM and
+//! registers. The whole environment is `Clone`, so a rollout can branch
+//! from any state
+
+use std::sync::Arc;
+
+use crate::bus::{Bus, SystemBus};
+use crate::cpu::{Cpu, MachineStatus};
+use crate::error::RomError;
+use crate::machine::{check_rom_fits, install_rom};
+use crate::quirks::{QuirkProfile, Quirks};
+use crate::romdb::sha1_hex;
+use crate::speed::{self, DEFAULT_IPS};
+use crate::utils::SeededRng;
+
+
+const PONG_SHA1: &str = "a60611339661e3ab2d8af024ad1da5880a6f8665";
+
+
+// Read only view of the machine handed to task functions
+pub struct Machine<'a> {
+    cpu: &'a Cpu,
+    bus: &'a SystemBus,
+}
+
+
+impl<'a> Machine<'a> {
+
+    pub fn memread(&self, addr: u16) -> u8 {
+        self.bus.memread(addr)
+    }
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.cpu.register(x)
+    }
+
+    pub fn status(&self) -> MachineStatus {
+        self.cpu.status()
+    }
+}
+
+
+pub type ScoreFn = Arc<dyn Fn(&Machine) -> f64 + Send + Sync>;
+pub type DoneFn = Arc<dyn Fn(&Machine) -> bool + Send + Sync>;
+
+
+// What an agent is rewarded for. The reward of a frame is the change in
+// score, episodes also end when the program halts or errors
+#[derive(Clone)]
+pub struct Task {
+    pub score: ScoreFn,
+    pub done: DoneFn,
+}
+
+
+impl Task {
+
+    pub fn new(
+        score: impl Fn(&Machine) -> f64 + Send + Sync + 'static,
+        done: impl Fn(&Machine) -> bool + Send + Sync + 'static,
+    ) -> Task {
+        Task { score: Arc::new(score), done: Arc::new(done) }
+    }
+
+    // No reward, the episode only ends when the program stops
+    pub fn none() -> Task {
+        Task::new(|_| 0.0, |_| false)
+    }
+
+    // PONG's score is VE as two digits, it writes them as BCD to
+    // 0x2F2 - 0x2F4 before drawing. The tens belong to the left paddle
+    // (keys 1 and 4), the agent, the ones to the right. A game ends at
+    // first_to points
+    pub fn pong(first_to: u8) -> Task {
+        let left = |m: &Machine| m.memread(0x2F3);
+        let right = |m: &Machine| m.memread(0x2F4);
+        Task::new(
+            move |m| left(m) as f64 - right(m) as f64,
+            move |m| left(m) >= first_to || right(m) >= first_to,
+        )
+    }
+
+    // Built in task for a known ROM
+    pub fn for_rom(rom: &[u8]) -> Option<Task> {
+        match sha1_hex(rom).as_str() {
+            PONG_SHA1 => Some(Task::pong(9)),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvConfig {
+    pub profile: QuirkProfile,        // Sets the memory and display size
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    pub frame_skip: u32,              // Frames each action is held for
+    pub sticky_action_probability: f64,  // Chance a frame repeats the last action
+    pub max_frames: Option<u64>,      // Truncate episodes after this many frames
+    pub seed: u64,
+}
+
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            profile: QuirkProfile::Chip8,
+            quirks: Quirks::chip8(),
+            instructions_per_frame: speed::instructions_per_frame(DEFAULT_IPS),
+            frame_skip: 4,
+            sticky_action_probability: 0.25,
+            max_frames: None,
+            seed: 0,
+        }
+    }
+}
+
+
+// Everything that changes during an episode
+#[derive(Clone)]
+struct State {
+    cpu: Cpu,
+    bus: SystemBus,
+    rng: SeededRng,
+    last_action: u16,  // Held keys as a keypad bitmask
+    frame: u64,
+    score: f64,
+}
+
+
+#[derive(Clone)]
+pub struct Env {
+    config: EnvConfig,
+    task: Task,
+    initial: State,
+    state: State,
+    episode: u64,
+}
+
+
+impl Env {
+
+    // Loads the ROM the way Console::load_rom does, at the profile's
+    // load address and with a 64x64 display for the HIRES patch
+    pub fn new(rom: &[u8], task: Task, config: EnvConfig) -> Result<Env, RomError> {
+        let load_address = config.profile.load_address();
+        check_rom_fits(rom, config.profile.memory_size(), load_address)?;
+
+        let mut bus = SystemBus::new();
+        bus.set_memory_size(config.profile.memory_size());
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(config.quirks);
+        cpu.seed_rng(config.seed);
+        install_rom(&mut cpu, &mut bus, rom, config.profile, load_address);
+
+        let initial = State {
+            cpu,
+            bus,
+            rng: SeededRng::new(config.seed),
+            last_action: 0,
+            frame: 0,
+            score: 0.0,
+        };
+
+        Ok(Env { config, task, state: initial.clone(), initial, episode: 0 })
+    }
+
+    // Start a new episode, each one reseeds from the config's seed and
+    // the episode number so episodes differ but runs are reproducible
+    pub fn reset(&mut self) -> Vec<u8> {
+        self.episode += 1;
+        let seed = self.config.seed.wrapping_add(self.episode);
+
+        self.state = self.initial.clone();
+        self.state.cpu.seed_rng(seed);
+        self.state.rng = SeededRng::new(seed);
+        self.state.score = self.score();
+        self.observation()
+    }
+
+    // Hold the action's keys for frame_skip frames
+    pub fn step(&mut self, action_keys: &[u8]) -> (Vec<u8>, f64, bool) {
+        let action = action_keys.iter().fold(0u16, |keys, key| keys | 1 << (key & 0xF));
+        let mut reward = 0.0;
+        let mut done = self.is_done();
+
+        for _ in 0..self.config.frame_skip.max(1) {
+            if done {
+                break;
+            }
+
+            let sticky = self.state.rng.next_f64() < self.config.sticky_action_probability;
+            if !sticky {
+                self.state.last_action = action;
+            }
+
+            let state = &mut self.state;
+            state.bus.set_pressed_keys(state.last_action);
+            state.cpu.run_frame(&mut state.bus, self.config.instructions_per_frame);
+            state.frame += 1;
+
+            let score = self.score();
+            reward += score - self.state.score;
+            self.state.score = score;
+            done = self.is_done();
+        }
+
+        (self.observation(), reward, done)
+    }
+
+    // Framebuffer, one byte per pixel row by row
+    pub fn observation(&self) -> Vec<u8> {
+        self.state.bus.get_vram().to_vec()
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.state.frame
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    fn machine(&self) -> Machine<'_> {
+        Machine { cpu: &self.state.cpu, bus: &self.state.bus }
+    }
+
+    fn score(&self) -> f64 {
+        (self.task.score)(&self.machine())
+    }
+
+    fn is_done(&self) -> bool {
+        let stopped = matches!(
+            self.state.cpu.status(),
+            MachineStatus::Halted | MachineStatus::Errored
+        );
+        let truncated = self.config.max_frames.is_some_and(|max| self.state.frame >= max);
+        stopped || truncated || (self.task.done)(&self.machine())
+    }
+}
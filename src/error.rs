@@ -0,0 +1,123 @@
+//! Errors raised while emulating a program
+
+use std::fmt;
+
+use wasm_bindgen::JsValue;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmulatorError {
+    UnknownOpcode { addr: u16, opcode: u16 },
+    StackOverflow { addr: u16 },
+    StackUnderflow { addr: u16 },
+    EndOfMemory { addr: u16 },
+}
+
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::UnknownOpcode { addr, opcode } =>
+                write!(f, "unknown opcode {:04x} at {:03x}", opcode, addr),
+            EmulatorError::StackOverflow { addr } =>
+                write!(f, "stack overflow calling from {:03x}", addr),
+            EmulatorError::StackUnderflow { addr } =>
+                write!(f, "stack underflow returning from {:03x}", addr),
+            EmulatorError::EndOfMemory { addr } =>
+                write!(f, "ran off the end of memory after {:03x}", addr),
+        }
+    }
+}
+
+
+impl std::error::Error for EmulatorError {}
+
+
+// Reasons a ROM can't be loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomError {
+    TooLarge { size: usize, max: usize },
+    LoadAddressOutOfRange { addr: u16, memory: usize },
+}
+
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooLarge { size, max } =>
+                write!(f, "ROM is {} bytes, the platform allows at most {}", size, max),
+            RomError::LoadAddressOutOfRange { addr, memory } =>
+                write!(f, "load address {:03x} is outside {} bytes of memory", addr, memory),
+        }
+    }
+}
+
+
+impl std::error::Error for RomError {}
+
+
+impl From<RomError> for JsValue {
+    fn from(error: RomError) -> JsValue {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+
+// Reasons a font can't be installed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FontError {
+    WrongSize { size: usize, expected: usize },
+    AddressOutOfRange { addr: u16, memory: usize },
+    OverlapsProgram { addr: u16, load_address: u16 },
+}
+
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::WrongSize { size, expected } =>
+                write!(f, "font is {} bytes, expected {}", size, expected),
+            FontError::AddressOutOfRange { addr, memory } =>
+                write!(f, "font at {:03x} doesn't fit in {} bytes of memory", addr, memory),
+            FontError::OverlapsProgram { addr, load_address } =>
+                write!(f, "font at {:03x} overlaps programs loaded at {:03x}", addr, load_address),
+        }
+    }
+}
+
+
+impl std::error::Error for FontError {}
+
+
+impl From<FontError> for JsValue {
+    fn from(error: FontError) -> JsValue {
+        JsValue::from_str(&error.to_string())
+    }
+}
+
+
+// Netplay packets that can't be decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetplayError {
+    WrongPacketSize { size: usize, expected: usize },
+}
+
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetplayError::WrongPacketSize { size, expected } =>
+                write!(f, "netplay packet is {} bytes, expected {}", size, expected),
+        }
+    }
+}
+
+
+impl std::error::Error for NetplayError {}
+
+
+impl From<NetplayError> for JsValue {
+    fn from(error: NetplayError) -> JsValue {
+        JsValue::from_str(&error.to_string())
+    }
+}
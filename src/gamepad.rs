@@ -0,0 +1,164 @@
+//! Gamepad input
+//!
+//! Turns raw gamepad state into host keys for the input mapping layer.
+//! The layout follows the browser Gamepad API's standard mapping: button
+//! values from 0 to 1 and stick axes from -1 to 1, so the wasm frontend
+//! passes `navigator.getGamepads()` values straight in and native
+//! frontends fill the same slices from their own gamepad library.
+//!
+//! Buttons are named "Button0" up, except the standard D-pad buttons 12
+//! to 15 which are "DPadUp", "DPadDown", "DPadLeft" and "DPadRight".
+//! A stick axis pushed past the deadzone holds "Axis0-" or "Axis0+"
+
+use std::collections::BTreeSet;
+
+use crate::input::{InputMap, InputState};
+
+
+pub const DEFAULT_DEADZONE: f64 = 0.25;
+
+// Analog triggers count as pressed past half way
+const BUTTON_THRESHOLD: f64 = 0.5;
+
+const DPAD: [(usize, &str); 4] = [
+    (12, "DPadUp"),
+    (13, "DPadDown"),
+    (14, "DPadLeft"),
+    (15, "DPadRight"),
+];
+
+// D-pad and left stick steer like WASD, the face buttons sit on Q and E
+const DEFAULT_BINDINGS: [(&str, u8); 10] = [
+    ("DPadUp", 0x5),
+    ("DPadDown", 0x9),
+    ("DPadLeft", 0x8),
+    ("DPadRight", 0xA),
+    ("Axis1-", 0x5),
+    ("Axis1+", 0x9),
+    ("Axis0-", 0x8),
+    ("Axis0+", 0xA),
+    ("Button0", 0x4),
+    ("Button1", 0x6),
+];
+
+
+// Device name for the gamepad at an index, "gamepad0" is player one
+pub fn device_name(index: usize) -> String {
+    format!("gamepad{}", index)
+}
+
+
+// Bind the default layout for the gamepad at index
+pub fn bind_defaults(map: &mut InputMap, index: usize) {
+    let device = device_name(index);
+    for (host_key, key) in DEFAULT_BINDINGS.iter() {
+        map.bind(&device, host_key, *key);
+    }
+}
+
+
+fn button_name(button: usize) -> String {
+    DPAD.iter()
+        .find(|&&(index, _)| index == button)
+        .map_or_else(|| format!("Button{}", button), |(_, name)| name.to_string())
+}
+
+
+// Host keys held in the given state
+pub fn held_keys(buttons: &[f64], axes: &[f64], deadzone: f64) -> BTreeSet<String> {
+    let pressed = buttons.iter().enumerate()
+        .filter(|&(_, &value)| value >= BUTTON_THRESHOLD)
+        .map(|(button, _)| button_name(button));
+
+    let tilted = axes.iter().enumerate()
+        .filter(|&(_, &value)| value.abs() > deadzone)
+        .map(|(axis, &value)| format!("Axis{}{}", axis, if value < 0.0 { '-' } else { '+' }));
+
+    pressed.chain(tilted).collect()
+}
+
+
+// One connected gamepad, remembers what it held last poll so only
+// changes reach the input state
+#[derive(Clone, Debug)]
+pub struct Gamepad {
+    device: String,
+    deadzone: f64,
+    held: BTreeSet<String>,
+}
+
+
+impl Gamepad {
+
+    pub fn new(index: usize) -> Gamepad {
+        Gamepad { device: device_name(index), deadzone: DEFAULT_DEADZONE, held: BTreeSet::new() }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    // Axis values at or below this are ignored, 0 to 1
+    pub fn set_deadzone(&mut self, deadzone: f64) {
+        self.deadzone = deadzone.clamp(0.0, 1.0);
+    }
+
+    // Poll, press newly held keys and release those let go
+    pub fn update(&mut self, buttons: &[f64], axes: &[f64], input: &mut InputState) {
+        let held = held_keys(buttons, axes, self.deadzone);
+
+        for host_key in self.held.difference(&held) {
+            input.key_up(&self.device, host_key);
+        }
+        for host_key in held.difference(&self.held) {
+            input.key_down(&self.device, host_key);
+        }
+
+        self.held = held;
+    }
+
+    // Release everything, for when the gamepad disconnects
+    pub fn disconnect(&mut self, input: &mut InputState) {
+        self.update(&[], &[], input);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputConfig;
+
+    #[test]
+    fn test_buttons_dpad_and_stick_deadzone() {
+        let mut buttons = [0.0; 16];
+        buttons[0] = 1.0;
+        buttons[6] = 0.4;   // Trigger not far enough
+        buttons[12] = 1.0;
+        let held = held_keys(&buttons, &[0.2, -0.9], DEFAULT_DEADZONE);
+
+        let expected: BTreeSet<String> = ["Button0", "DPadUp", "Axis1-"].iter()
+            .map(|key| key.to_string())
+            .collect();
+        assert_eq!(held, expected);
+    }
+
+    #[test]
+    fn test_update_presses_and_releases_keys() {
+        let config = InputConfig::new();
+        let mut input = InputState::new();
+        let mut pad = Gamepad::new(0);
+
+        let mut buttons = [0.0; 16];
+        buttons[15] = 1.0;
+        pad.update(&buttons, &[0.0, 0.5], &mut input);
+        assert_eq!(input.keypad(&config, None), 1 << 0xA | 1 << 0x9);
+
+        pad.set_deadzone(0.6);
+        pad.update(&buttons, &[0.0, 0.5], &mut input);
+        assert_eq!(input.keypad(&config, None), 1 << 0xA);
+
+        pad.disconnect(&mut input);
+        assert_eq!(input.keypad(&config, None), 0);
+    }
+}
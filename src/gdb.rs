@@ -0,0 +1,495 @@
+//! GDB remote serial protocol stub
+//!
+//! Serves a `Console` to debuggers that speak RSP over TCP. Supports
+//! register and memory reads and writes, single-step, continue with
+//! software breakpoints and interrupting a running program with Ctrl-C.
+//!
+//! Registers are numbered V0-VF (0-15), I (16), PC (17), DT (18), ST (19)
+//! and SP (20), values are little endian as RSP expects. I and PC are two
+//! bytes, the rest one. SP is the stack depth and can't be written. GDB
+//! has no CHIP-8 architecture of its own, the layout is also served as
+//! target.xml for clients that ask.
+//!
+//! Detaching or killing ends the session, the machine stays as it is for
+//! the next debugger to attach
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::TcpStream;
+
+use crate::cpu::MachineStatus;
+use crate::machine::Console;
+
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:1234";
+
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const REGISTER_COUNT: usize = 21;
+const I: usize = 16;
+const PC: usize = 17;
+const DT: usize = 18;
+const ST: usize = 19;
+const SP: usize = 20;
+
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target><feature name="org.chip8.core">"#,
+    r#"<reg name="v0" bitsize="8" regnum="0"/><reg name="v1" bitsize="8"/>"#,
+    r#"<reg name="v2" bitsize="8"/><reg name="v3" bitsize="8"/>"#,
+    r#"<reg name="v4" bitsize="8"/><reg name="v5" bitsize="8"/>"#,
+    r#"<reg name="v6" bitsize="8"/><reg name="v7" bitsize="8"/>"#,
+    r#"<reg name="v8" bitsize="8"/><reg name="v9" bitsize="8"/>"#,
+    r#"<reg name="va" bitsize="8"/><reg name="vb" bitsize="8"/>"#,
+    r#"<reg name="vc" bitsize="8"/><reg name="vd" bitsize="8"/>"#,
+    r#"<reg name="ve" bitsize="8"/><reg name="vf" bitsize="8"/>"#,
+    r#"<reg name="i" bitsize="16" type="data_ptr"/><reg name="pc" bitsize="16" type="code_ptr"/>"#,
+    r#"<reg name="dt" bitsize="8"/><reg name="st" bitsize="8"/><reg name="sp" bitsize="8"/>"#,
+    r#"</feature></target>"#,
+);
+
+
+// What the session does after a packet
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    Reply(String),
+    Resume,                  // Run until a breakpoint, a stop or an interrupt
+    Detach(Option<String>),  // Reply, if any, then close the connection
+}
+
+
+pub struct GdbStub {
+    console: Console,
+    breakpoints: BTreeSet<u16>,
+    ticks: u32,  // Instructions run this frame
+}
+
+
+impl GdbStub {
+
+    pub fn new(console: Console) -> GdbStub {
+        GdbStub { console, breakpoints: BTreeSet::new(), ticks: 0 }
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    pub fn handle(&mut self, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+
+        match command {
+            "?" => Action::Reply(stop_reply(SIGTRAP)),
+            "g" => Action::Reply((0..REGISTER_COUNT).map(|n| self.read_register(n)).collect()),
+            "G" => self.write_registers(args).map_or_else(|| reply("E01"), |_| reply("OK")),
+            "p" => parse_hex(args).filter(|&n| n < REGISTER_COUNT)
+                .map_or_else(|| reply("E01"), |n| Action::Reply(self.read_register(n))),
+            "P" => self.write_register(args).map_or_else(|| reply("E01"), |_| reply("OK")),
+            "m" => self.read_memory(args).map_or_else(|| reply("E01"), Action::Reply),
+            "M" => self.write_memory(args).map_or_else(|| reply("E01"), |_| reply("OK")),
+            "s" | "c" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.console.cpu_mut().set_pc(addr as u16);
+                }
+                // Step off a breakpoint before looking for the next one
+                self.execute();
+                match (command, self.stop_signal()) {
+                    ("c", None) => Action::Resume,
+                    (_, signal) => Action::Reply(stop_reply(signal.unwrap_or(SIGTRAP))),
+                }
+            }
+            "Z" | "z" => self.set_breakpoint(command == "Z", args),
+            "H" => reply("OK"),
+            "D" => Action::Detach(Some("OK".to_string())),
+            "k" => Action::Detach(None),
+            "q" => self.query(args),
+            _ => reply(""),
+        }
+    }
+
+    // Run up to a frame's worth of instructions, the stop signal once the
+    // program hits a breakpoint or stops
+    pub fn resume(&mut self) -> Option<u8> {
+        for _ in 0..self.console.instructions_per_frame().max(1) {
+            if let Some(signal) = self.stop_signal() {
+                return Some(signal);
+            }
+            if self.breakpoints.contains(&self.console.pc()) {
+                return Some(SIGTRAP);
+            }
+            self.execute();
+        }
+        None
+    }
+
+    // One instruction, ending the frame where Cpu::run_frame would
+    fn execute(&mut self) {
+        self.console.tick();
+        self.ticks += 1;
+
+        let cpu = self.console.cpu();
+        if self.ticks >= self.console.instructions_per_frame()
+            || cpu.status() != MachineStatus::Running
+            || cpu.is_awaiting_vblank()
+        {
+            self.console.tick_timers();
+            self.ticks = 0;
+        }
+    }
+
+    fn stop_signal(&self) -> Option<u8> {
+        match self.console.status() {
+            MachineStatus::Halted => Some(SIGTRAP),
+            MachineStatus::Errored => Some(SIGILL),
+            _ => None,
+        }
+    }
+
+    fn read_register(&self, n: usize) -> String {
+        let cpu = self.console.cpu();
+        match n {
+            I => to_hex(&cpu.index().to_le_bytes()),
+            PC => to_hex(&cpu.pc().to_le_bytes()),
+            DT => to_hex(&[cpu.delay_timer()]),
+            ST => to_hex(&[cpu.sound_timer()]),
+            SP => to_hex(&[cpu.stack_depth() as u8]),
+            x => to_hex(&[cpu.register(x)]),
+        }
+    }
+
+    // Value bytes for register n, None if they don't fit
+    fn set_register(&mut self, n: usize, bytes: &[u8]) -> Option<()> {
+        let cpu = self.console.cpu_mut();
+        match (n, bytes) {
+            (I, &[lo, hi]) => cpu.set_index(u16::from_le_bytes([lo, hi])),
+            (PC, &[lo, hi]) => cpu.set_pc(u16::from_le_bytes([lo, hi])),
+            (DT, &[value]) => cpu.set_delay_timer(value),
+            (ST, &[value]) => cpu.set_sound_timer(value),
+            (SP, &[depth]) if depth as usize == cpu.stack_depth() => {}
+            (x, &[value]) if x < I => cpu.set_register(x, value),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<()> {
+        let (n, value) = args.split_once('=')?;
+        self.set_register(parse_hex(n)?, &from_hex(value)?)
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<()> {
+        let bytes = from_hex(args)?;
+        let sizes = (0..REGISTER_COUNT).map(|n| if n == I || n == PC { 2 } else { 1 });
+        if bytes.len() != sizes.clone().sum::<usize>() {
+            return None;
+        }
+
+        let mut offset = 0;
+        for (n, size) in sizes.enumerate() {
+            // Leave SP alone rather than reject the whole write
+            if n != SP {
+                self.set_register(n, &bytes[offset..offset + size])?;
+            }
+            offset += size;
+        }
+        Some(())
+    }
+
+    // Memory past the end is an error rather than wrapping round
+    fn memory_range(&self, args: &str) -> Option<(u16, usize)> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        if addr.checked_add(len)? > self.console.memory_size() {
+            return None;
+        }
+        Some((addr as u16, len))
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = self.memory_range(args)?;
+        let bytes: Vec<u8> = (0..len).map(|i| self.console.memread(addr + i as u16)).collect();
+        Some(to_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = self.memory_range(range)?;
+        let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.console.memwrite(addr + i as u16, byte);
+        }
+        Some(())
+    }
+
+    // Software and hardware breakpoints are the same thing here, watchpoints
+    // aren't supported
+    fn set_breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(parse_hex).filter(|&addr| addr < self.console.memory_size());
+
+        match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                if insert {
+                    self.breakpoints.insert(addr as u16);
+                } else {
+                    self.breakpoints.remove(&(addr as u16));
+                }
+                Action::Reply("OK".to_string())
+            }
+            (Some("0"), None) | (Some("1"), None) => Action::Reply("E01".to_string()),
+            _ => Action::Reply(String::new()),
+        }
+    }
+
+    fn query(&self, args: &str) -> Action {
+        if args.starts_with("Supported") {
+            return Action::Reply("PacketSize=1000;qXfer:features:read+".to_string());
+        }
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            return Action::Reply(target_xml(range).unwrap_or_else(|| "E01".to_string()));
+        }
+
+        let reply = match args {
+            "Attached" => "1",
+            "C" => "QC1",
+            "fThreadInfo" => "m1",
+            "sThreadInfo" => "l",
+            _ => "",
+        };
+        Action::Reply(reply.to_string())
+    }
+}
+
+
+// Debug one connection until the debugger detaches or hangs up
+pub fn serve(stream: TcpStream, stub: &mut GdbStub) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    while let Some(packet) = read_packet(&mut reader, &mut writer)? {
+        match stub.handle(&packet) {
+            Action::Reply(reply) => write_packet(&mut writer, &reply)?,
+            Action::Resume => {
+                let signal = loop {
+                    if let Some(signal) = stub.resume() {
+                        break signal;
+                    }
+                    if is_interrupted(&mut reader)? {
+                        break SIGINT;
+                    }
+                };
+                write_packet(&mut writer, &stop_reply(signal))?;
+            }
+            Action::Detach(reply) => {
+                if let Some(reply) = reply {
+                    write_packet(&mut writer, &reply)?;
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+// The next packet's data, acknowledged. None when the debugger hangs up
+pub fn read_packet(reader: &mut impl BufRead, writer: &mut impl Write) -> io::Result<Option<String>> {
+    loop {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] != b'$' {
+            continue;  // Acks, or an interrupt while already stopped
+        }
+
+        let mut data = Vec::new();
+        if reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+            return Ok(None);
+        }
+        let mut checksum = [0; 2];
+        reader.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if expected != Some(checksum_of(&data)) {
+            writer.write_all(b"-")?;
+            continue;
+        }
+        writer.write_all(b"+")?;
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    }
+}
+
+
+pub fn write_packet(writer: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(writer, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+    writer.flush()
+}
+
+
+// Whether Ctrl-C arrived, without waiting for it. Acks in the way are
+// dropped, anything else waits for the program to stop
+fn is_interrupted(reader: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    reader.get_ref().set_nonblocking(true)?;
+    let interrupted = loop {
+        let pending = match reader.fill_buf() {
+            Ok(buffer) => buffer.first().copied(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => {
+                reader.get_ref().set_nonblocking(false)?;
+                return Err(e);
+            }
+        };
+        match pending {
+            Some(b'+') | Some(b'-') => reader.consume(1),
+            Some(INTERRUPT) => {
+                reader.consume(1);
+                break true;
+            }
+            _ => break false,
+        }
+    };
+    reader.get_ref().set_nonblocking(false)?;
+    Ok(interrupted)
+}
+
+
+// The part of target.xml at offset,length, "m" marks more to come
+fn target_xml(range: &str) -> Option<String> {
+    let (offset, len) = range.split_once(',')?;
+    let (offset, len) = (parse_hex(offset)?, parse_hex(len)?);
+    let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..)?;
+    let chunk = &rest[..rest.len().min(len)];
+    Some(format!("{}{}", if chunk.len() < rest.len() { "m" } else { "l" }, chunk))
+}
+
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stub() -> GdbStub {
+        let rom = [
+            0x60, 0x07,  // 200: v0 = 7
+            0xA3, 0x00,  // 202: i = 300
+            0x22, 0x0A,  // 204: call 20A
+            0x12, 0x06,  // 206: halt
+            0x00, 0x00,
+            0xF0, 0x55,  // 20A: save v0
+            0x00, 0xEE,  // 20C: return
+        ];
+        let mut console = Console::new();
+        console.load_rom(&rom).unwrap();
+        GdbStub::new(console)
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("{:?} for {}", action, packet),
+        }
+    }
+
+    #[test]
+    fn test_registers_memory_and_stepping() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+
+        // V0-VF, I, PC, DT, ST then SP
+        let registers = reply(&mut stub, "g");
+        assert_eq!(&registers[..4], "0700");
+        assert_eq!(&registers[32..], "00030a02000001");
+        assert_eq!(reply(&mut stub, "p11"), "0a02");
+
+        assert_eq!(reply(&mut stub, "P1=2a"), "OK");
+        assert_eq!(reply(&mut stub, "P12=34"), "OK");
+        assert_eq!(stub.console().cpu().register(1), 0x2A);
+        assert_eq!(stub.console().cpu().delay_timer(), 0x34);
+        assert_eq!(reply(&mut stub, "P12=3401"), "E01");
+        assert_eq!(reply(&mut stub, "P14=05"), "E01");
+        assert_eq!(reply(&mut stub, "P11=0a02"), "OK");
+
+        assert_eq!(reply(&mut stub, "M300,2:beef"), "OK");
+        assert_eq!(reply(&mut stub, "m2fe,4"), "0000beef");
+        assert_eq!(reply(&mut stub, "mfff,2"), "E01");
+        assert_eq!(reply(&mut stub, "M300,2:be"), "E01");
+
+        let mut registers = reply(&mut stub, "g");
+        registers.replace_range(0..2, "09");
+        assert_eq!(reply(&mut stub, &format!("G{}", registers)), "OK");
+        assert_eq!(stub.console().cpu().register(0), 9);
+        assert_eq!(reply(&mut stub, "G00"), "E01");
+    }
+
+    #[test]
+    fn test_continue_stops_at_breakpoints_and_halt() {
+        let mut stub = stub();
+        assert_eq!(reply(&mut stub, "Z0,20c,2"), "OK");
+        assert_eq!(reply(&mut stub, "Z2,300,1"), "");
+
+        assert_eq!(stub.handle("c"), Action::Resume);
+        assert_eq!(stub.resume(), Some(SIGTRAP));
+        assert_eq!(stub.console().pc(), 0x20C);
+        assert_eq!(stub.console().memread(0x300), 7);
+
+        assert_eq!(reply(&mut stub, "z0,20c,2"), "OK");
+        assert_eq!(stub.handle("c"), Action::Resume);
+        assert_eq!(stub.resume(), Some(SIGTRAP));
+        assert_eq!(stub.console().status(), MachineStatus::Halted);
+        assert_eq!(reply(&mut stub, "c"), "S05");
+    }
+
+    #[test]
+    fn test_packet_framing() {
+        let mut written = Vec::new();
+        write_packet(&mut written, "m200,2").unwrap();
+        assert_eq!(written, b"$m200,2#5d");
+
+        let mut acks = Vec::new();
+        let mut input = &b"+$g#67\x03$g#00$?#3f"[..];
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), Some("g".to_string()));
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), Some("?".to_string()));
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), None);
+        assert_eq!(acks, b"+-+");
+    }
+}
@@ -0,0 +1,304 @@
+//! Hooks for bots, scripts and overlays
+//!
+//! Callbacks run before each instruction, when the program counter
+//! reaches an address, after a watched address is written and at the end
+//! of each frame. Native callbacks get a `HookContext` to read and poke
+//! the machine, JavaScript callbacks get the event's numbers as
+//! arguments. Memory hooks fire once the writing instruction finishes,
+//! writes made from a hook don't fire hooks themselves
+
+use std::collections::BTreeSet;
+
+use crate::bus::{Bus, SystemBus};
+use crate::cpu::{Cpu, MachineStatus};
+use crate::instruction::Instruction;
+
+
+pub type HookId = u32;
+
+pub type InstructionFn = dyn FnMut(&mut HookContext, u16, u16);  // pc, opcode
+pub type PcFn = dyn FnMut(&mut HookContext);
+pub type WriteFn = dyn FnMut(&mut HookContext, u16, u8);         // addr, value
+pub type FrameFn = dyn FnMut(&mut HookContext, u64);             // frame number
+
+
+enum Hook {
+    Instruction(Box<InstructionFn>),
+    Pc(u16, Box<PcFn>),
+    Write(u16, Box<WriteFn>),
+    Frame(Box<FrameFn>),
+}
+
+
+// The machine as a hook sees it
+pub struct HookContext<'a> {
+    cpu: &'a mut Cpu,
+    bus: &'a mut SystemBus,
+}
+
+
+impl<'a> HookContext<'a> {
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.cpu.register(x)
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.cpu.set_register(x, value);
+    }
+
+    pub fn memread(&self, addr: u16) -> u8 {
+        self.bus.memread(addr)
+    }
+
+    pub fn memwrite(&mut self, addr: u16, value: u8) {
+        self.bus.memwrite(addr, value);
+    }
+
+    pub fn pressed_keys(&self) -> u16 {
+        self.bus.pressed_keys()
+    }
+
+    // Hold exactly these keys, bit n for key n
+    pub fn set_pressed_keys(&mut self, keys: u16) {
+        self.bus.set_pressed_keys(keys);
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        self.bus.get_vram()
+    }
+}
+
+
+// Passes everything to the system bus, noting writes to watched addresses
+struct WatchingBus<'a> {
+    bus: &'a mut SystemBus,
+    watched: &'a BTreeSet<u16>,
+    writes: Vec<(u16, u8)>,
+}
+
+
+impl<'a> Bus for WatchingBus<'a> {
+    fn memread(&self, addr: u16) -> u8 {
+        self.bus.memread(addr)
+    }
+
+    fn memwrite(&mut self, addr: u16, val: u8) {
+        self.bus.memwrite(addr, val);
+        if self.watched.contains(&addr) {
+            self.writes.push((addr, val));
+        }
+    }
+
+    fn fetch(&mut self, addr: u16) -> Instruction {
+        self.bus.fetch(addr)
+    }
+
+    fn draw(&mut self, x: u8, y: u8, addr: u16, sprite_len: u16, clip: bool) -> bool {
+        self.bus.draw(x, y, addr, sprite_len, clip)
+    }
+
+    fn clrs(&mut self) {
+        self.bus.clrs();
+    }
+
+    fn memory_size(&self) -> usize {
+        self.bus.memory_size()
+    }
+
+    fn font_address(&self) -> u16 {
+        self.bus.font_address()
+    }
+
+    fn is_key_pressed(&self, keycode: u8) -> bool {
+        self.bus.is_key_pressed(keycode)
+    }
+
+    fn get_pressed_key(&self) -> Option<u8> {
+        self.bus.get_pressed_key()
+    }
+
+    fn is_sound_on(&self) -> bool {
+        self.bus.is_sound_on()
+    }
+
+    fn set_sound_on(&mut self) {
+        self.bus.set_sound_on();
+    }
+
+    fn set_sound_off(&mut self) {
+        self.bus.set_sound_off();
+    }
+
+    fn cycle_background(&mut self) {
+        self.bus.cycle_background();
+    }
+
+    fn set_foreground(&mut self, x: usize, y: usize, columns: usize, rows: usize, color: u8) {
+        self.bus.set_foreground(x, y, columns, rows, color);
+    }
+
+    fn is_key2_pressed(&self, keycode: u8) -> bool {
+        self.bus.is_key2_pressed(keycode)
+    }
+
+    fn port_out(&mut self, value: u8) {
+        self.bus.port_out(value);
+    }
+
+    fn port_in(&mut self) -> Option<u8> {
+        self.bus.port_in()
+    }
+}
+
+
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<(HookId, Hook)>,
+    watched: BTreeSet<u16>,  // Addresses with write hooks
+    next_id: HookId,
+}
+
+
+impl Hooks {
+
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn on_instruction(&mut self, hook: impl FnMut(&mut HookContext, u16, u16) + 'static) -> HookId {
+        self.add(Hook::Instruction(Box::new(hook)))
+    }
+
+    pub fn on_pc(&mut self, addr: u16, hook: impl FnMut(&mut HookContext) + 'static) -> HookId {
+        self.add(Hook::Pc(addr, Box::new(hook)))
+    }
+
+    pub fn on_write(&mut self, addr: u16, hook: impl FnMut(&mut HookContext, u16, u8) + 'static) -> HookId {
+        self.watched.insert(addr);
+        self.add(Hook::Write(addr, Box::new(hook)))
+    }
+
+    pub fn on_frame(&mut self, hook: impl FnMut(&mut HookContext, u64) + 'static) -> HookId {
+        self.add(Hook::Frame(Box::new(hook)))
+    }
+
+    pub fn remove(&mut self, id: HookId) {
+        self.hooks.retain(|(hook_id, _)| *hook_id != id);
+        self.watched = self.hooks.iter()
+            .filter_map(|(_, hook)| match hook {
+                Hook::Write(addr, _) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+    }
+
+    // Execute one instruction with hooks around it
+    pub fn tick(&mut self, cpu: &mut Cpu, bus: &mut SystemBus) {
+        if let MachineStatus::Halted | MachineStatus::Errored = cpu.status() {
+            return;
+        }
+
+        let pc = cpu.pc();
+        let opcode = u16::from_be_bytes([bus.memread(pc), bus.memread(pc.wrapping_add(1))]);
+        let mut context = HookContext { cpu: &mut *cpu, bus: &mut *bus };
+        for (_, hook) in self.hooks.iter_mut() {
+            match hook {
+                Hook::Instruction(hook) => hook(&mut context, pc, opcode),
+                Hook::Pc(addr, hook) if *addr == pc => hook(&mut context),
+                _ => {}
+            }
+        }
+
+        let mut watching = WatchingBus { bus: &mut *bus, watched: &self.watched, writes: Vec::new() };
+        cpu.tick(&mut watching);
+        let writes = watching.writes;
+
+        let mut context = HookContext { cpu: &mut *cpu, bus: &mut *bus };
+        for (addr, value) in writes {
+            for (_, hook) in self.hooks.iter_mut() {
+                if let Hook::Write(watched, hook) = hook {
+                    if *watched == addr {
+                        hook(&mut context, addr, value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Cpu::run_frame with hooks, frame hooks run after the timers tick
+    // and get the number of the frame that just ran
+    pub fn run_frame(&mut self, cpu: &mut Cpu, bus: &mut SystemBus, instructions: u32, frame: u64) {
+        cpu.run_frame_with(bus, instructions, |cpu, bus| self.tick(cpu, bus));
+
+        let mut context = HookContext { cpu: &mut *cpu, bus: &mut *bus };
+        for (_, hook) in self.hooks.iter_mut() {
+            if let Hook::Frame(hook) = hook {
+                hook(&mut context, frame);
+            }
+        }
+    }
+
+    fn add(&mut self, hook: Hook) -> HookId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.hooks.push((id, hook));
+        id
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_hooks_fire_and_can_be_removed() {
+        let (mut cpu, mut bus) = (Cpu::new(), SystemBus::new());
+        let rom = [
+            0x60, 0x07,  // 200: v0 = 7
+            0xA3, 0x00,  // 202: i = 300
+            0xF0, 0x55,  // 204: save v0
+            0x12, 0x06,  // 206: halt
+        ];
+        for (offset, byte) in rom.iter().enumerate() {
+            bus.memwrite(0x200 + offset as u16, *byte);
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut hooks = Hooks::new();
+
+        let l = log.clone();
+        let every = hooks.on_instruction(move |_, pc, opcode| l.borrow_mut().push(format!("{:03X} {:04X}", pc, opcode)));
+        let l = log.clone();
+        hooks.on_pc(0x204, move |ctx| l.borrow_mut().push(format!("pc v0={}", ctx.register(0))));
+        let l = log.clone();
+        hooks.on_write(0x300, move |ctx, addr, value| {
+            l.borrow_mut().push(format!("write {:03X}={}", addr, value));
+            ctx.set_pressed_keys(1 << value);
+        });
+        let l = log.clone();
+        hooks.on_frame(move |_, frame| l.borrow_mut().push(format!("frame {}", frame)));
+
+        hooks.run_frame(&mut cpu, &mut bus, 3, 0);
+        assert_eq!(*log.borrow(), vec![
+            "200 6007", "202 A300", "204 F055", "pc v0=7", "write 300=7", "frame 0",
+        ]);
+        assert!(bus.is_key_pressed(7));
+
+        hooks.remove(every);
+        log.borrow_mut().clear();
+        hooks.run_frame(&mut cpu, &mut bus, 1, 1);
+        assert_eq!(*log.borrow(), vec!["frame 1"]);
+    }
+}
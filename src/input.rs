@@ -0,0 +1,297 @@
+//! Host input mapping
+//!
+//! Resolves keys from any number of host devices into the 16 key hex
+//! keypad, so two players can share a keyboard or each bring their own
+//! device. Bindings are grouped by device name ("keyboard", "gamepad0")
+//! then by the device's key identifier, KeyboardEvent.code for keyboards.
+//! A ROM can have its own bindings, looked up by hash before the defaults,
+//! and the whole configuration round trips through JSON
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::gamepad;
+
+
+pub const KEYBOARD: &str = "keyboard";
+
+// The original layout, four keys a row from 1 to V
+const KEYBOARD_LAYOUT: [&str; 16] = [
+    "Digit1", "Digit2", "Digit3", "Digit4",
+    "KeyQ", "KeyW", "KeyE", "KeyR",
+    "KeyA", "KeyS", "KeyD", "KeyF",
+    "KeyZ", "KeyX", "KeyC", "KeyV",
+];
+
+// Database action names and the keys they get in a ROM's profile,
+// player one on WASD and the first gamepad's D-pad, player two on the
+// arrow keys and the second gamepad
+const ACTION_KEYS: [(&str, &str, usize, &str); 8] = [
+    ("up", "KeyW", 0, "DPadUp"),
+    ("down", "KeyS", 0, "DPadDown"),
+    ("left", "KeyA", 0, "DPadLeft"),
+    ("right", "KeyD", 0, "DPadRight"),
+    ("player2Up", "ArrowUp", 1, "DPadUp"),
+    ("player2Down", "ArrowDown", 1, "DPadDown"),
+    ("player2Left", "ArrowLeft", 1, "DPadLeft"),
+    ("player2Right", "ArrowRight", 1, "DPadRight"),
+];
+
+
+// Device name to host key to CHIP-8 key
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputMap {
+    devices: BTreeMap<String, BTreeMap<String, u8>>,
+    // Autofire period in frames for host keys, per device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    turbo: BTreeMap<String, BTreeMap<String, u32>>,
+}
+
+
+impl InputMap {
+
+    pub fn new() -> InputMap {
+        InputMap::default()
+    }
+
+    // Every hex key on the keyboard, and the first gamepad
+    pub fn keyboard() -> InputMap {
+        let mut map = InputMap::new();
+        for (key, code) in KEYBOARD_LAYOUT.iter().enumerate() {
+            map.bind(KEYBOARD, code, key as u8);
+        }
+        gamepad::bind_defaults(&mut map, 0);
+        map
+    }
+
+    // Keyboard and gamepad bindings for a ROM's named actions from the
+    // database, unknown action names are skipped
+    pub fn from_actions(actions: &HashMap<String, u8>) -> InputMap {
+        let mut map = InputMap::new();
+        for (action, code, pad, button) in ACTION_KEYS.iter() {
+            if let Some(&key) = actions.get(*action) {
+                map.bind(KEYBOARD, code, key);
+                map.bind(&gamepad::device_name(*pad), button, key);
+            }
+        }
+        map
+    }
+
+    pub fn bind(&mut self, device: &str, host_key: &str, key: u8) {
+        self.devices.entry(device.to_string()).or_default()
+            .insert(host_key.to_string(), key & 0xF);
+    }
+
+    pub fn unbind(&mut self, device: &str, host_key: &str) {
+        if let Some(keys) = self.devices.get_mut(device) {
+            keys.remove(host_key);
+        }
+    }
+
+    pub fn resolve(&self, device: &str, host_key: &str) -> Option<u8> {
+        self.devices.get(device)?.get(host_key).copied()
+    }
+
+    // Held, the key is pressed for period frames then released for
+    // period frames. None or 0 turns autofire off
+    pub fn set_turbo(&mut self, device: &str, host_key: &str, period: Option<u32>) {
+        match period.filter(|&period| period > 0) {
+            Some(period) => {
+                self.turbo.entry(device.to_string()).or_default()
+                    .insert(host_key.to_string(), period);
+            }
+            None => if let Some(keys) = self.turbo.get_mut(device) {
+                keys.remove(host_key);
+            },
+        }
+    }
+
+    pub fn turbo(&self, device: &str, host_key: &str) -> Option<u32> {
+        self.turbo.get(device)?.get(host_key).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.values().all(|keys| keys.is_empty())
+            && self.turbo.values().all(|keys| keys.is_empty())
+    }
+}
+
+
+// Default bindings and per-ROM overrides keyed by the ROM's SHA-1
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputConfig {
+    pub default: InputMap,
+    #[serde(default)]
+    pub roms: HashMap<String, InputMap>,
+}
+
+
+impl InputConfig {
+
+    pub fn new() -> InputConfig {
+        InputConfig { default: InputMap::keyboard(), roms: HashMap::new() }
+    }
+
+    pub fn from_json(json: &str) -> Result<InputConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("input config serializes")
+    }
+
+    // The ROM's bindings, created empty on first use
+    pub fn rom_mut(&mut self, hash: &str) -> &mut InputMap {
+        self.roms.entry(hash.to_string()).or_default()
+    }
+
+    // A ROM's own binding for the host key wins over the default
+    pub fn resolve(&self, hash: Option<&str>, device: &str, host_key: &str) -> Option<u8> {
+        hash.and_then(|hash| self.roms.get(hash))
+            .and_then(|map| map.resolve(device, host_key))
+            .or_else(|| self.default.resolve(device, host_key))
+    }
+
+    pub fn turbo(&self, hash: Option<&str>, device: &str, host_key: &str) -> Option<u32> {
+        hash.and_then(|hash| self.roms.get(hash))
+            .and_then(|map| map.turbo(device, host_key))
+            .or_else(|| self.default.turbo(device, host_key))
+    }
+}
+
+
+impl Default for InputConfig {
+    fn default() -> InputConfig {
+        InputConfig::new()
+    }
+}
+
+
+// Host keys currently held on every device, with the frame each went
+// down on for autofire, and keys pressed on the keypad directly
+#[derive(Clone, Debug, Default)]
+pub struct InputState {
+    held: BTreeMap<(String, String), u64>,
+    direct: u16,
+    frame: u64,
+}
+
+
+impl InputState {
+
+    pub fn new() -> InputState {
+        InputState::default()
+    }
+
+    pub fn key_down(&mut self, device: &str, host_key: &str) {
+        let frame = self.frame;
+        self.held.entry((device.to_string(), host_key.to_string())).or_insert(frame);
+    }
+
+    pub fn key_up(&mut self, device: &str, host_key: &str) {
+        self.held.remove(&(device.to_string(), host_key.to_string()));
+    }
+
+    pub fn press_direct(&mut self, key: u8) {
+        self.direct |= 1 << (key & 0xF);
+    }
+
+    pub fn release_direct(&mut self, key: u8) {
+        self.direct &= !(1 << (key & 0xF));
+    }
+
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.direct = 0;
+    }
+
+    // Advance autofire, called once per emulated frame
+    pub fn tick(&mut self) {
+        self.frame += 1;
+    }
+
+    // Whether any held key fires on its own and needs the keypad
+    // refreshed every frame
+    pub fn has_turbo(&self, config: &InputConfig, hash: Option<&str>) -> bool {
+        self.held.keys().any(|(device, host_key)| config.turbo(hash, device, host_key).is_some())
+    }
+
+    // Keypad bitmask of every held key that is bound, so two host keys
+    // on the same CHIP-8 key keep it down until both are released
+    pub fn keypad(&self, config: &InputConfig, hash: Option<&str>) -> u16 {
+        self.held.iter()
+            .filter(|((device, host_key), &since)| {
+                config.turbo(hash, device, host_key)
+                    .map_or(true, |period| (self.frame - since) / period as u64 % 2 == 0)
+            })
+            .filter_map(|((device, host_key), _)| config.resolve(hash, device, host_key))
+            .fold(self.direct, |keys, key| keys | 1 << key)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_two_players_resolve_to_one_keypad() {
+        let mut config = InputConfig::new();
+        config.default.bind("gamepad1", "DPadUp", 0xC);
+
+        let mut input = InputState::new();
+        input.key_down(KEYBOARD, "Digit2");
+        input.key_down("gamepad1", "DPadUp");
+        input.key_down(KEYBOARD, "Unbound");
+        assert_eq!(input.keypad(&config, None), 1 << 0x1 | 1 << 0xC);
+
+        // Both players holding the same key
+        config.default.bind("gamepad1", "DPadDown", 0x1);
+        input.key_down("gamepad1", "DPadDown");
+        input.key_up(KEYBOARD, "Digit2");
+        assert_eq!(input.keypad(&config, None), 1 << 0x1 | 1 << 0xC);
+    }
+
+    #[test]
+    fn test_rom_profile_overrides_default_and_round_trips() {
+        let actions: HashMap<String, u8> = [("up", 1), ("player2Up", 12)].iter()
+            .map(|&(action, key)| (action.to_string(), key))
+            .collect();
+
+        let mut config = InputConfig::new();
+        *config.rom_mut("abc") = InputMap::from_actions(&actions);
+
+        assert_eq!(config.resolve(Some("abc"), KEYBOARD, "KeyW"), Some(1));
+        assert_eq!(config.resolve(Some("abc"), KEYBOARD, "ArrowUp"), Some(12));
+        assert_eq!(config.resolve(Some("abc"), KEYBOARD, "KeyQ"), Some(4));
+        assert_eq!(config.resolve(None, KEYBOARD, "KeyW"), Some(5));
+        assert_eq!(config.resolve(Some("def"), KEYBOARD, "ArrowUp"), None);
+        assert_eq!(config.resolve(Some("abc"), "gamepad1", "DPadUp"), Some(12));
+
+        let restored = InputConfig::from_json(&config.to_json()).unwrap();
+        assert_eq!(restored, config);
+    }
+
+    #[test]
+    fn test_turbo_toggles_every_period() {
+        let mut config = InputConfig::new();
+        config.default.set_turbo(KEYBOARD, "KeyQ", Some(2));
+
+        let mut input = InputState::new();
+        input.key_down(KEYBOARD, "KeyQ");
+        input.key_down(KEYBOARD, "KeyW");
+        assert!(input.has_turbo(&config, None));
+
+        let mut frames = Vec::new();
+        for _ in 0..6 {
+            frames.push(input.keypad(&config, None) & 1 << 4 != 0);
+            assert!(input.keypad(&config, None) & 1 << 5 != 0);
+            input.tick();
+        }
+        assert_eq!(frames, vec![true, true, false, false, true, true]);
+
+        config.default.set_turbo(KEYBOARD, "KeyQ", None);
+        assert!(!input.has_turbo(&config, None));
+    }
+}
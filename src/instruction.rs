@@ -0,0 +1,181 @@
+//! Decoded CHIP-8 instructions
+//!
+//! Variants are named after the opcode patterns handled by the cpu's
+//! `call_*` functions, operands are pre-extracted so execution never
+//! has to mask the raw opcode again. CHIP-8X only opcodes decode on
+//! every platform, the cpu rejects them unless CHIP-8X is enabled
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Op00e0,
+    Op00ee,
+    Op0230,
+    Op02a0,
+    Op0nnn(u16),
+    Op1nnn(u16),
+    Op2nnn(u16),
+    Op3xnn(usize, u8),
+    Op4xnn(usize, u8),
+    Op5xy0(usize, usize),
+    Op5xy1(usize, usize),
+    Op6xnn(usize, u8),
+    Op7xnn(usize, u8),
+    Op8xy0(usize, usize),
+    Op8xy1(usize, usize),
+    Op8xy2(usize, usize),
+    Op8xy3(usize, usize),
+    Op8xy4(usize, usize),
+    Op8xy5(usize, usize),
+    Op8xy6(usize, usize),
+    Op8xy7(usize, usize),
+    Op8xye(usize, usize),
+    Op9xy0(usize, usize),
+    Opannn(u16),
+    Opbnnn(usize, u16),
+    Opcxnn(usize, u8),
+    Opdxyn(usize, usize, u8),
+    Opex9e(usize),
+    Opexa1(usize),
+    Opexf2(usize),
+    Opexf5(usize),
+    Opfx07(usize),
+    Opfx0a(usize),
+    Opfx15(usize),
+    Opfx18(usize),
+    Opfx1e(usize),
+    Opfx29(usize),
+    Opfx33(usize),
+    Opfx55(usize),
+    Opfx65(usize),
+    Opfxf8(usize),
+    Opfxfb(usize),
+    Unknown(u16),
+}
+
+
+impl Instruction {
+
+    // Legend:
+    // NNN - 2-byte (used for mem address);
+    // NN - 1-byte constant (used for data)
+    // N - 4-bit constant
+    // X, Y - 4-bit register id
+    pub fn decode(opcode: u16) -> Instruction {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+
+        let n = (opcode & 0x000F) as u8;
+        let nn = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+
+        let p1 = opcode & 0x000F;
+        let p2 = (opcode & 0x00F0) >> 4;
+        let p3 = (opcode & 0x0F00) >> 8;
+        let p4 = (opcode & 0xF000) >> 12;
+
+        match (p4, p3, p2, p1) {
+            (0, 0, 0xE, 0) => Instruction::Op00e0,
+            (0, 0, 0xE, 0xE) => Instruction::Op00ee,
+            (0, 2, 3, 0) => Instruction::Op0230,
+            (0, 2, 0xA, 0) => Instruction::Op02a0,
+            (0, _, _, _) => Instruction::Op0nnn(nnn),
+            (0x1, _, _, _) => Instruction::Op1nnn(nnn),
+            (0x2, _, _, _) => Instruction::Op2nnn(nnn),
+            (0x3, _, _, _) => Instruction::Op3xnn(x, nn),
+            (0x4, _, _, _) => Instruction::Op4xnn(x, nn),
+            (0x5, _, _, 0) => Instruction::Op5xy0(x, y),
+            (0x5, _, _, 1) => Instruction::Op5xy1(x, y),
+            (0x6, _, _, _) => Instruction::Op6xnn(x, nn),
+            (0x7, _, _, _) => Instruction::Op7xnn(x, nn),
+            (0x8, _, _, 0) => Instruction::Op8xy0(x, y),
+            (0x8, _, _, 1) => Instruction::Op8xy1(x, y),
+            (0x8, _, _, 2) => Instruction::Op8xy2(x, y),
+            (0x8, _, _, 3) => Instruction::Op8xy3(x, y),
+            (0x8, _, _, 4) => Instruction::Op8xy4(x, y),
+            (0x8, _, _, 5) => Instruction::Op8xy5(x, y),
+            (0x8, _, _, 6) => Instruction::Op8xy6(x, y),
+            (0x8, _, _, 7) => Instruction::Op8xy7(x, y),
+            (0x8, _, _, 0xE) => Instruction::Op8xye(x, y),
+            (0x9, _, _, 0) => Instruction::Op9xy0(x, y),
+            (0xA, _, _, _) => Instruction::Opannn(nnn),
+            (0xB, _, _, _) => Instruction::Opbnnn(x, nnn),
+            (0xC, _, _, _) => Instruction::Opcxnn(x, nn),
+            (0xD, _, _, _) => Instruction::Opdxyn(x, y, n),
+            (0xE, _, 9, 0xE) => Instruction::Opex9e(x),
+            (0xE, _, 0xA, 1) => Instruction::Opexa1(x),
+            (0xE, _, 0xF, 2) => Instruction::Opexf2(x),
+            (0xE, _, 0xF, 5) => Instruction::Opexf5(x),
+            (0xF, _, 0, 7) => Instruction::Opfx07(x),
+            (0xF, _, 0, 0xA) => Instruction::Opfx0a(x),
+            (0xF, _, 1, 5) => Instruction::Opfx15(x),
+            (0xF, _, 1, 8) => Instruction::Opfx18(x),
+            (0xF, _, 1, 0xE) => Instruction::Opfx1e(x),
+            (0xF, _, 2, 9) => Instruction::Opfx29(x),
+            (0xF, _, 3, 3) => Instruction::Opfx33(x),
+            (0xF, _, 5, 5) => Instruction::Opfx55(x),
+            (0xF, _, 6, 5) => Instruction::Opfx65(x),
+            (0xF, _, 0xF, 8) => Instruction::Opfxf8(x),
+            (0xF, _, 0xF, 0xB) => Instruction::Opfxfb(x),
+            (_, _, _, _) => Instruction::Unknown(opcode)
+        }
+    }
+
+    // Opcodes only CHIP-8X understands, BXYN shares BNNN's encoding so
+    // isn't among them
+    pub fn is_chip8x(&self) -> bool {
+        matches!(self,
+            Instruction::Op02a0 | Instruction::Op5xy1(..)
+            | Instruction::Opexf2(_) | Instruction::Opexf5(_)
+            | Instruction::Opfxf8(_) | Instruction::Opfxfb(_))
+    }
+
+    // The opcode pattern this instruction was decoded from, e.g. "8XY4"
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::Op00e0 => "00E0",
+            Instruction::Op00ee => "00EE",
+            Instruction::Op0230 => "0230",
+            Instruction::Op02a0 => "02A0",
+            Instruction::Op0nnn(_) => "0NNN",
+            Instruction::Op1nnn(_) => "1NNN",
+            Instruction::Op2nnn(_) => "2NNN",
+            Instruction::Op3xnn(..) => "3XNN",
+            Instruction::Op4xnn(..) => "4XNN",
+            Instruction::Op5xy0(..) => "5XY0",
+            Instruction::Op5xy1(..) => "5XY1",
+            Instruction::Op6xnn(..) => "6XNN",
+            Instruction::Op7xnn(..) => "7XNN",
+            Instruction::Op8xy0(..) => "8XY0",
+            Instruction::Op8xy1(..) => "8XY1",
+            Instruction::Op8xy2(..) => "8XY2",
+            Instruction::Op8xy3(..) => "8XY3",
+            Instruction::Op8xy4(..) => "8XY4",
+            Instruction::Op8xy5(..) => "8XY5",
+            Instruction::Op8xy6(..) => "8XY6",
+            Instruction::Op8xy7(..) => "8XY7",
+            Instruction::Op8xye(..) => "8XYE",
+            Instruction::Op9xy0(..) => "9XY0",
+            Instruction::Opannn(_) => "ANNN",
+            Instruction::Opbnnn(..) => "BNNN",
+            Instruction::Opcxnn(..) => "CXNN",
+            Instruction::Opdxyn(..) => "DXYN",
+            Instruction::Opex9e(_) => "EX9E",
+            Instruction::Opexa1(_) => "EXA1",
+            Instruction::Opexf2(_) => "EXF2",
+            Instruction::Opexf5(_) => "EXF5",
+            Instruction::Opfx07(_) => "FX07",
+            Instruction::Opfx0a(_) => "FX0A",
+            Instruction::Opfx15(_) => "FX15",
+            Instruction::Opfx18(_) => "FX18",
+            Instruction::Opfx1e(_) => "FX1E",
+            Instruction::Opfx29(_) => "FX29",
+            Instruction::Opfx33(_) => "FX33",
+            Instruction::Opfx55(_) => "FX55",
+            Instruction::Opfx65(_) => "FX65",
+            Instruction::Opfxf8(_) => "FXF8",
+            Instruction::Opfxfb(_) => "FXFB",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+}
@@ -1,31 +1,51 @@
-/// CHIP 8 console keypad consists of 16 buttons, each represented
-/// hexadecimal digits 0 - F in memory
+//! CHIP 8 console keypad consists of 16 buttons, each represented
+//! hexadecimal digits 0 - F in memory. Any number of them can be held
 
 
-pub struct Keypad { key_pressed: Option<u8> }
+#[derive(Clone)]
+pub struct Keypad { pressed: u16 }  // Bit n set while key n is held
 
 
 impl Keypad {
 
     pub fn new() -> Keypad {
-        Keypad {key_pressed: None}
+        Keypad { pressed: 0 }
     }
 
+    // Lowest held key, what FX0A reads
     pub fn get_pressed_key(&self) -> Option<u8> {
-        self.key_pressed
+        match self.pressed {
+            0 => None,
+            keys => Some(keys.trailing_zeros() as u8),
+        }
+    }
+
+    pub fn press(&mut self, key_code: u8) {
+        self.pressed |= 1 << (key_code & 0xF);
+    }
+
+    pub fn release(&mut self, key_code: u8) {
+        self.pressed &= !(1 << (key_code & 0xF));
     }
 
-    pub fn set_pressed_key(&mut self, key: Option<u8>)  {
-        self.key_pressed = key
+    // Replace every held key at once
+    pub fn set_pressed_keys(&mut self, keys: u16) {
+        self.pressed = keys;
+    }
+
+    pub fn pressed_keys(&self) -> u16 {
+        self.pressed
     }
 
     pub fn is_key_pressed(&self, key_code: u8) -> bool {
-        match self.key_pressed {
-            Some(k) => k == key_code,
-            _ => false
-        }
+        key_code < 16 && self.pressed & (1 << key_code) != 0
     }
 
 }
 
 
+impl Default for Keypad {
+    fn default() -> Keypad {
+        Keypad::new()
+    }
+}
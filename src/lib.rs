@@ -1,8 +1,29 @@
-/// Lib.rs
+//! Lib.rs
+#[macro_use]
+pub mod utils;
 pub mod bus;
+pub mod cartridge;
 pub mod cpu;
-pub mod utils;
 pub mod keypad;
 pub mod memory;
+pub mod font;
+pub mod quirks;
+pub mod romdb;
+pub mod error;
 pub mod display;
-pub mod machine;
\ No newline at end of file
+pub mod speed;
+pub mod machine;
+pub mod instruction;
+pub mod analysis;
+pub mod profiler;
+pub mod cheats;
+pub mod env;
+pub mod input;
+pub mod gamepad;
+pub mod netplay;
+pub mod rollback;
+pub mod hooks;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdb;
@@ -1,8 +1,22 @@
-/// CHIP8 Entry point
+//! CHIP8 Entry point
+
+use std::convert::TryInto;
 
 use crate::cpu;
+use crate::cpu::MachineStatus;
 use crate::utils;
-use crate::bus::Bus;
+use crate::bus::{Bus, SystemBus};
+use crate::display::PALETTE;
+use crate::error::{FontError, RomError};
+use crate::font::{FontSet, FONT_SIZE};
+use crate::gamepad::Gamepad;
+use crate::hooks::{HookId, Hooks};
+use crate::input::{InputConfig, InputMap, InputState};
+use crate::quirks::{QuirkProfile, Quirks, StackDepth};
+use crate::cartridge::Cartridge;
+use crate::cheats::{Cheat, CheatBook, CheatTarget, Comparison, MemorySearch};
+use crate::romdb::{sha1_hex, RomDatabase, RomInfo};
+use crate::speed::{Governor, SpeedMode, DEFAULT_IPS, TIMER_HZ};
 extern crate web_sys;
 use wasm_bindgen::prelude::*;
 
@@ -14,10 +28,74 @@ use wasm_bindgen::prelude::*;
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 
+// Summary of a successfully loaded ROM
+#[wasm_bindgen]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedRom {
+    size: usize,
+    load_address: u16,
+    hash: String,
+}
+
+
+#[wasm_bindgen]
+impl LoadedRom {
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    // Lowercase hex SHA-1 of the ROM bytes
+    pub fn hash(&self) -> String {
+        self.hash.clone()
+    }
+}
+
+
+// Everything a frame can change, for rollback. Settings like the ROM
+// database, cheats and key bindings are not part of it
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: cpu::Cpu,
+    bus: SystemBus,
+    frame: u64,
+}
+
+
+// The platform and speed chosen through the API. Every ROM starts from
+// these, database and cartridge settings only last until the next load
+#[derive(Clone, Copy)]
+struct Settings {
+    profile: QuirkProfile,
+    quirks: Quirks,
+    load_address: u16,
+    ips: u32,
+}
+
+
 #[wasm_bindgen]
 pub struct Console {
-    bus: Bus,
+    bus: SystemBus,
     cpu: cpu::Cpu,
+    governor: Governor,
+    romdb: RomDatabase,
+    rom_info: Option<RomInfo>,
+    auto_configure: bool,  // Apply database settings when a ROM loads
+    settings: Settings,
+    load_address: u16,
+    profile: QuirkProfile,
+    rom_hash: Option<String>,
+    cheats: CheatBook,
+    search: Option<MemorySearch>,
+    input: InputConfig,
+    held: InputState,  // Host keys down on every device
+    gamepads: Vec<Gamepad>,
+    hooks: Hooks,
+    frame: u64,  // Frames run since the ROM loaded or the machine reset
 }
 
 
@@ -26,34 +104,350 @@ impl Console {
 
     pub fn new() -> Console {
         utils::set_panic_hook();
-        Console { bus: Bus::new(), cpu: cpu::Cpu::new() }
+        Console {
+            bus: SystemBus::new(),
+            cpu: cpu::Cpu::new(),
+            governor: Governor::new(),
+            romdb: RomDatabase::bundled(),
+            rom_info: None,
+            auto_configure: true,
+            settings: Settings {
+                profile: QuirkProfile::Chip8,
+                quirks: Quirks::default(),
+                load_address: cpu::PROG_START,
+                ips: DEFAULT_IPS,
+            },
+            load_address: cpu::PROG_START,
+            profile: QuirkProfile::Chip8,
+            rom_hash: None,
+            cheats: CheatBook::new(),
+            search: None,
+            input: InputConfig::new(),
+            held: InputState::new(),
+            gamepads: Vec::new(),
+            hooks: Hooks::new(),
+            frame: 0,
+        }
     }
 
-    // Loads ROM into memory
-    pub fn load_rom(&mut self, rom: &[u8]) {
-        let start_addr = cpu::PROG_START;
+    // Loads ROM into memory at the load address and restarts the cpu.
+    // The ROM is checked against the memory of the platform it will run
+    // on, and only then is the machine put back to the user's settings
+    // and configured from the ROM database before install_rom writes it
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<LoadedRom, RomError> {
+        let hash = sha1_hex(rom);
+        let info = self.romdb.lookup_hash(&hash);
 
-        for (idx, byte) in rom.iter().cloned().enumerate() {
-            self.bus.memwrite(start_addr + idx as u16, byte);
+        let profile = info.as_ref()
+            .filter(|_| self.auto_configure)
+            .and_then(|info| info.profile());
+        let (memory, load_address) = match profile {
+            Some(profile) => (profile.memory_size(), profile.load_address()),
+            None => (self.settings.profile.memory_size(), self.settings.load_address),
+        };
+        check_rom_fits(rom, memory, load_address)?;
+
+        self.rom_info = info;
+        self.restore_settings();
+        if self.auto_configure {
+            self.apply_rom_settings();
         }
+
+        let start_addr = self.load_address;
+        install_rom(&mut self.cpu, &mut self.bus, rom, self.profile, start_addr);
+        self.rom_hash = Some(hash.clone());
+        self.frame = 0;
+        self.search = None;
+        self.add_rom_input_profile();
+        self.update_keypad();
+
+        Ok(LoadedRom { size: rom.len(), load_address: start_addr, hash })
+    }
+
+    // Load address for later ROMs, 0x600 for ETI-660 programs
+    pub fn set_load_address(&mut self, addr: u16) {
+        self.settings.load_address = addr;
+        self.load_address = addr;
+    }
+
+    // Largest ROM the platform can hold at the load address
+    pub fn max_rom_size(&self) -> usize {
+        self.bus.memory_size().saturating_sub(self.load_address as usize)
+    }
+
+    // Load a byte-literal Octo cartridge GIF, its options override database
+    // settings
+    pub fn load_cartridge(&mut self, gif: &[u8]) -> Result<LoadedRom, JsValue> {
+        let cartridge = Cartridge::decode(gif)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(self.insert_cartridge(&cartridge)?)
+    }
+
+    // Replace the bundled database with the contents of the community
+    // chip-8-database's sha1-hashes.json and programs.json
+    pub fn load_rom_database(&mut self, hashes: &str, programs: &str) -> Result<(), JsValue> {
+        self.romdb = RomDatabase::from_json(hashes, programs)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(())
+    }
+
+    // Whether load_rom applies recommended quirks and tick rate
+    pub fn set_auto_configure(&mut self, enabled: bool) {
+        self.auto_configure = enabled;
+    }
+
+    // Database entry for the loaded ROM as JSON, for title, colours and keys
+    pub fn rom_metadata(&self) -> Option<String> {
+        let info = self.rom_info.as_ref()?;
+        serde_json::to_string(info).ok()
+    }
+
+    // Use one of the built in fonts
+    pub fn set_font(&mut self, font: FontSet) {
+        self.bus.set_font(font.sprites());
+    }
+
+    // Use a custom font of 16 five byte glyphs, kept across ROM loads
+    pub fn load_font(&mut self, font: &[u8]) -> Result<(), FontError> {
+        let glyphs: &[u8; FONT_SIZE] = font.try_into()
+            .map_err(|_| FontError::WrongSize { size: font.len(), expected: FONT_SIZE })?;
+        self.bus.set_font(glyphs);
+        Ok(())
+    }
+
+    // Move the font, some interpreters keep it at 0x050. It has to end
+    // before the load address or ROMs would overwrite it
+    pub fn set_font_address(&mut self, addr: u16) -> Result<(), FontError> {
+        if addr as usize + FONT_SIZE > self.load_address as usize {
+            return Err(FontError::OverlapsProgram { addr, load_address: self.load_address });
+        }
+        if !self.bus.set_font_address(addr) {
+            return Err(FontError::AddressOutOfRange { addr, memory: self.bus.memory_size() });
+        }
+        Ok(())
+    }
+
+    pub fn font_address(&self) -> u16 {
+        self.bus.font_address()
     }
 
     // Resets machine
     pub fn reset(&mut self) {
         self.cpu.reset();
         self.bus.reset();
+        self.governor.reset();
+        self.frame = 0;
+    }
+
+    // Switch interpreter behaviour, memory size and load address to
+    // match the given platform, memory is cleared when its size changes
+    pub fn set_quirk_profile(&mut self, profile: QuirkProfile) {
+        self.settings.profile = profile;
+        self.settings.quirks = Quirks::from_profile(profile);
+        self.settings.load_address = profile.load_address();
+        self.cpu.set_quirks(self.settings.quirks);
+        self.bus.set_memory_size(profile.memory_size());
+        self.load_address = profile.load_address();
+        self.profile = profile;
+    }
+
+    // Override the profile's call stack depth
+    pub fn set_stack_depth(&mut self, depth: StackDepth) {
+        self.update_quirks(|quirks| quirks.stack_depth = depth);
+    }
+
+    // Keep return addresses in RAM at the VIP's 0xEA0 - 0xECF
+    pub fn set_stack_in_ram(&mut self, enabled: bool) {
+        self.update_quirks(|quirks| quirks.stack_in_ram = enabled);
+    }
+
+    // Make DXYN wait for vblank like the COSMAC VIP
+    pub fn set_display_wait(&mut self, enabled: bool) {
+        self.update_quirks(|quirks| quirks.display_wait = enabled);
+    }
+
+    // Clip sprites at the screen edge instead of wrapping them
+    pub fn set_clipping(&mut self, enabled: bool) {
+        self.update_quirks(|quirks| quirks.clipping = enabled);
     }
 
     // Execute Cycle
     pub fn tick(&mut self) {
-        self.cpu.tick(&mut self.bus);
+        if self.hooks.is_empty() {
+            self.cpu.tick(&mut self.bus);
+        } else {
+            self.hooks.tick(&mut self.cpu, &mut self.bus);
+        }
    }
 
+    // Whether the program is running, idle or stopped
+    pub fn status(&self) -> MachineStatus {
+        self.cpu.status()
+    }
+
+    // Description of the error that stopped the machine
+    pub fn error_message(&self) -> Option<String> {
+        self.cpu.error().map(|e| e.to_string())
+    }
+
+    // Emulate the frames due after elapsed_ms of host time,
+    // returns the number of frames run so the caller knows to redraw
+    pub fn run(&mut self, elapsed_ms: f64) -> u32 {
+        let frames = self.governor.frames_due(elapsed_ms);
+
+        for _ in 0..frames {
+            self.run_frame();
+        }
+
+        frames
+    }
+
+    // Run a single frame, used for frame advance
+    pub fn advance_frame(&mut self) {
+        self.run_frame();
+    }
+
+    // Run one frame with exactly these keys held and no cheats, hooks or
+    // autofire, for netplay where every peer must compute the same frame
+    pub fn step_frame(&mut self, keys: u16) {
+        self.bus.set_pressed_keys(keys);
+        let instructions = self.governor.instructions_per_frame();
+        self.cpu.run_frame(&mut self.bus, instructions);
+        self.frame += 1;
+    }
+
+    // Frames run since the ROM loaded or the machine reset
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Make CXNN reproducible, peers in a netplay session share the seed
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.cpu.seed_rng(seed);
+    }
+
+    // Hash of RAM and the display for desync checks
+    pub fn state_hash(&self) -> u64 {
+        self.bus.state_hash()
+    }
+
+    // Run at a fixed clock speed in real time
+    pub fn set_target_ips(&mut self, ips: u32) {
+        self.settings.ips = ips;
+        self.governor.set_mode(SpeedMode::InstructionsPerSecond(ips));
+    }
+
+    // Run at N x real time, below 1 for slow motion. Each frame runs as
+    // many instructions as the ROM's configured speed
+    pub fn set_speed_multiplier(&mut self, multiplier: f64) {
+        self.governor.set_mode(SpeedMode::Multiplier(multiplier));
+    }
+
+    // Stop running frames from run(), step with advance_frame()
+    pub fn set_frame_advance(&mut self) {
+        self.governor.set_mode(SpeedMode::FrameAdvance);
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.governor.instructions_per_frame()
+    }
+
+    // Frames dropped because the host could not keep up
+    pub fn skipped_frames(&self) -> u32 {
+        self.governor.skipped_frames()
+    }
+
+    // Snapshot memory to begin a new cheat search
+    pub fn start_cheat_search(&mut self) {
+        self.search = Some(MemorySearch::start(&self.bus));
+    }
+
+    // Narrow the search, returns how many candidates are left.
+    // value is only compared with EqualTo
+    pub fn filter_cheat_search(&mut self, comparison: Comparison, value: u8) -> usize {
+        let bus = &self.bus;
+        self.search.get_or_insert_with(|| MemorySearch::start(bus))
+            .filter(bus, comparison, value)
+    }
+
+    pub fn cheat_candidates(&self) -> Vec<u16> {
+        self.search.as_ref().map_or_else(Vec::new, |s| s.candidates().to_vec())
+    }
+
+    // Freeze a memory address for the loaded ROM
+    pub fn add_memory_cheat(&mut self, name: &str, addr: u16, value: u8) {
+        self.add_cheat(Cheat::new(name, CheatTarget::Memory(addr), value));
+    }
+
+    // Freeze register Vx for the loaded ROM
+    pub fn add_register_cheat(&mut self, name: &str, x: usize, value: u8) {
+        self.add_cheat(Cheat::new(name, CheatTarget::Register(x & 0xF), value));
+    }
+
+    pub fn remove_cheat(&mut self, name: &str) {
+        if let Some(hash) = &self.rom_hash {
+            self.cheats.remove(hash, name);
+        }
+    }
+
+    pub fn set_cheat_enabled(&mut self, name: &str, enabled: bool) {
+        if let Some(hash) = &self.rom_hash {
+            self.cheats.set_enabled(hash, name, enabled);
+        }
+    }
+
+    // Cheats for every ROM as JSON, for saving
+    pub fn export_cheats(&self) -> String {
+        self.cheats.to_json()
+    }
+
+    pub fn import_cheats(&mut self, json: &str) -> Result<(), JsValue> {
+        self.cheats = CheatBook::from_json(json)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(())
+    }
+
+    // Start a fresh profile, or stop profiling
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.cpu.set_profiling(enabled);
+    }
+
+    // Profile as JSON with the given number of hotspots
+    pub fn profile_report(&self, hotspots: usize) -> Option<String> {
+        let report = self.cpu.profiler()?.report(hotspots);
+        serde_json::to_string(&report).ok()
+    }
+
+    // Profile in folded stack format for flame graphs
+    pub fn profile_folded_stacks(&self) -> Option<String> {
+        Some(self.cpu.profiler()?.folded_stacks())
+    }
+
+    // Display resolution, set when a ROM loads
+    pub fn width(&self) -> usize {
+        self.bus.display_size().0
+    }
+
+    pub fn height(&self) -> usize {
+        self.bus.display_size().1
+    }
+
     // Gets display memory
     pub fn get_vram(&self) -> *const u8 {
         self.bus.get_vram().as_ptr()
     }
 
+    // Palette index per pixel for CHIP-8X colour output, look the
+    // indices up in palette()
+    pub fn get_color_vram(&mut self) -> *const u8 {
+        self.bus.get_color_vram().as_ptr()
+    }
+
+    // Colours as 0xRRGGBB
+    pub fn palette(&self) -> Vec<u32> {
+        PALETTE.to_vec()
+    }
+
     // Check whether to beep or not
     pub fn beep(&self) -> bool {
         self.bus.is_sound_on()
@@ -61,18 +455,446 @@ impl Console {
 
     // Press key on keypad
     pub fn press_key(&mut self, keycode: u8) {
-        let key = Some(keycode);
-        self.bus.press_key(key)
+        self.held.press_direct(keycode);
+        self.update_keypad();
     }
 
-    // Resets keypad if key is pressed
+    // Release key on keypad, other held keys stay down
     pub fn release_key(&mut self, keycode: u8) {
-        self.bus.release_key(keycode);
+        self.held.release_direct(keycode);
+        self.update_keypad();
     }
 
-    // Get pressed key
+    // Lowest held key
     pub fn get_pressed_key(&self) -> Option<u8> {
         self.bus.get_pressed_key()
     }
 
+    // A host key went down, device is "keyboard" with the key's
+    // KeyboardEvent.code, or any name the frontend gives a gamepad
+    pub fn host_key_down(&mut self, device: &str, host_key: &str) {
+        self.held.key_down(device, host_key);
+        self.update_keypad();
+    }
+
+    pub fn host_key_up(&mut self, device: &str, host_key: &str) {
+        self.held.key_up(device, host_key);
+        self.update_keypad();
+    }
+
+    // Release every host key, for when the page loses focus
+    pub fn release_host_keys(&mut self) {
+        for gamepad in self.gamepads.iter_mut() {
+            gamepad.disconnect(&mut self.held);
+        }
+        self.held.clear();
+        self.update_keypad();
+    }
+
+    // Keypad bitmask, bit n set while key n is held
+    pub fn pressed_keys(&self) -> u16 {
+        self.bus.pressed_keys()
+    }
+
+    // Keys this player's host keys and gamepads hold, without a netplay
+    // peer's, to send to the peer
+    pub fn host_keys(&self) -> u16 {
+        self.held.keypad(&self.input, self.rom_hash.as_deref())
+    }
+
+    // Bind a host key for every ROM
+    pub fn bind_key(&mut self, device: &str, host_key: &str, key: u8) {
+        self.input.default.bind(device, host_key, key);
+        self.update_keypad();
+    }
+
+    pub fn unbind_key(&mut self, device: &str, host_key: &str) {
+        self.input.default.unbind(device, host_key);
+        self.update_keypad();
+    }
+
+    // Bind a host key for the loaded ROM only
+    pub fn bind_rom_key(&mut self, device: &str, host_key: &str, key: u8) {
+        if let Some(hash) = &self.rom_hash {
+            self.input.rom_mut(hash).bind(device, host_key, key);
+            self.update_keypad();
+        }
+    }
+
+    pub fn unbind_rom_key(&mut self, device: &str, host_key: &str) {
+        if let Some(hash) = &self.rom_hash {
+            self.input.rom_mut(hash).unbind(device, host_key);
+            self.update_keypad();
+        }
+    }
+
+    // Poll a gamepad with its button values, 0 to 1, and axes, -1 to 1,
+    // in the Gamepad API's standard layout
+    pub fn update_gamepad(&mut self, index: usize, buttons: &[f64], axes: &[f64]) {
+        Console::gamepad(&mut self.gamepads, index).update(buttons, axes, &mut self.held);
+        self.update_keypad();
+    }
+
+    pub fn disconnect_gamepad(&mut self, index: usize) {
+        if let Some(gamepad) = self.gamepads.get_mut(index) {
+            gamepad.disconnect(&mut self.held);
+            self.update_keypad();
+        }
+    }
+
+    // Stick movement up to this is ignored, 0 to 1
+    pub fn set_gamepad_deadzone(&mut self, index: usize, deadzone: f64) {
+        Console::gamepad(&mut self.gamepads, index).set_deadzone(deadzone);
+    }
+
+    // Autofire a held host key, on for period frames then off as long.
+    // A period of 0 turns it off
+    pub fn set_turbo(&mut self, device: &str, host_key: &str, period: u32) {
+        self.input.default.set_turbo(device, host_key, Some(period));
+        self.update_keypad();
+    }
+
+    // Autofire for the loaded ROM only
+    pub fn set_rom_turbo(&mut self, device: &str, host_key: &str, period: u32) {
+        if let Some(hash) = &self.rom_hash {
+            self.input.rom_mut(hash).set_turbo(device, host_key, Some(period));
+            self.update_keypad();
+        }
+    }
+
+    // Key bindings for every ROM as JSON, for saving
+    pub fn input_config(&self) -> String {
+        self.input.to_json()
+    }
+
+    pub fn load_input_config(&mut self, json: &str) -> Result<(), JsValue> {
+        self.input = InputConfig::from_json(json)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.update_keypad();
+        Ok(())
+    }
+
+    // Call back with the pc and opcode before every instruction. Hooks
+    // run while the console is busy, read what they need from the
+    // arguments and call the console once run() returns
+    pub fn on_instruction(&mut self, callback: js_sys::Function) -> HookId {
+        self.hooks.on_instruction(move |_, pc, opcode| {
+            report_hook_error(callback.call2(&JsValue::NULL, &pc.into(), &opcode.into()));
+        })
+    }
+
+    // Call back when the instruction at addr is about to run
+    pub fn on_pc(&mut self, addr: u16, callback: js_sys::Function) -> HookId {
+        self.hooks.on_pc(addr, move |_| {
+            report_hook_error(callback.call1(&JsValue::NULL, &addr.into()));
+        })
+    }
+
+    // Call back with the address and value after addr is written
+    pub fn on_memory_write(&mut self, addr: u16, callback: js_sys::Function) -> HookId {
+        self.hooks.on_write(addr, move |_, addr, value| {
+            report_hook_error(callback.call2(&JsValue::NULL, &addr.into(), &value.into()));
+        })
+    }
+
+    // Call back with the frame number after every frame
+    pub fn on_frame(&mut self, callback: js_sys::Function) -> HookId {
+        self.hooks.on_frame(move |_, frame| {
+            report_hook_error(callback.call1(&JsValue::NULL, &(frame as f64).into()));
+        })
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) {
+        self.hooks.remove(id);
+    }
+
+    // Press key on the CHIP-8X second keypad
+    pub fn press_key2(&mut self, keycode: u8) {
+        self.bus.press_key2(keycode);
+    }
+
+    pub fn release_key2(&mut self, keycode: u8) {
+        self.bus.release_key2(keycode);
+    }
+
+    // Last byte a CHIP-8X program sent with FXF8
+    pub fn port_output(&self) -> u8 {
+        self.bus.port_output()
+    }
+
+    // Byte for the next FXFB to read
+    pub fn set_port_input(&mut self, value: u8) {
+        self.bus.set_port_input(value);
+    }
+
+}
+
+
+// Not exported to JS
+impl Console {
+
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState { cpu: self.cpu.clone(), bus: self.bus.clone(), frame: self.frame }
+    }
+
+    // The bus is restored in place so its decode cache survives rollback
+    pub fn load_state(&mut self, state: &SaveState) {
+        self.cpu = state.cpu.clone();
+        self.bus.restore(&state.bus);
+        self.frame = state.frame;
+    }
+
+    // Load a decoded cartridge's program and apply its options
+    pub fn insert_cartridge(&mut self, cartridge: &Cartridge) -> Result<LoadedRom, RomError> {
+        let loaded = self.load_rom(&cartridge.rom)?;
+
+        let options = &cartridge.options;
+        let info = self.rom_info.get_or_insert_with(|| RomInfo {
+            hash: sha1_hex(&cartridge.rom),
+            title: String::new(),
+            authors: Vec::new(),
+            platform: None,
+            tickrate: None,
+            colors: None,
+            keys: Default::default(),
+            quirk_overrides: Default::default(),
+        });
+
+        info.tickrate = options.tickrate.or(info.tickrate);
+        info.colors = options.colors().or_else(|| info.colors.take());
+        info.keys.extend(options.keys.iter().map(|(k, v)| (k.clone(), *v)));
+
+        let quirks = options.quirks(self.cpu.quirks());
+        self.cpu.set_quirks(quirks);
+
+        if let Some(tickrate) = options.tickrate {
+            let ips = (tickrate as f64 * TIMER_HZ) as u32;
+            self.governor.set_base_ips(ips);
+        }
+
+        self.add_rom_input_profile();
+        self.update_keypad();
+        Ok(loaded)
+    }
+
+    // The user's platform, quirks and speed, undoing the last ROM's
+    fn restore_settings(&mut self) {
+        let settings = self.settings;
+        self.bus.set_memory_size(settings.profile.memory_size());
+        self.load_address = settings.load_address;
+        self.profile = settings.profile;
+        self.cpu.set_quirks(settings.quirks);
+        self.governor.set_base_ips(settings.ips);
+    }
+
+    // Recommended quirks and speed for the loaded ROM
+    fn apply_rom_settings(&mut self) {
+        let info = match &self.rom_info {
+            Some(info) => info,
+            None => return,
+        };
+
+        if let Some(profile) = info.profile() {
+            self.bus.set_memory_size(profile.memory_size());
+            self.load_address = profile.load_address();
+            self.profile = profile;
+        }
+
+        if let Some(quirks) = info.quirks() {
+            self.cpu.set_quirks(quirks);
+        }
+
+        if let Some(tickrate) = info.tickrate {
+            let ips = (tickrate as f64 * TIMER_HZ) as u32;
+            self.governor.set_base_ips(ips);
+        }
+    }
+
+    pub fn input(&self) -> &InputConfig {
+        &self.input
+    }
+
+    // Give a ROM without bindings of its own the database's actions
+    fn add_rom_input_profile(&mut self) {
+        let (hash, info) = match (&self.rom_hash, &self.rom_info) {
+            (Some(hash), Some(info)) => (hash, info),
+            _ => return,
+        };
+
+        let profile = InputMap::from_actions(&info.keys);
+        if !self.input.roms.contains_key(hash) && !profile.is_empty() {
+            self.input.roms.insert(hash.clone(), profile);
+        }
+    }
+
+    // Gamepads are added the first time an index is seen
+    fn gamepad(gamepads: &mut Vec<Gamepad>, index: usize) -> &mut Gamepad {
+        while gamepads.len() <= index {
+            gamepads.push(Gamepad::new(gamepads.len()));
+        }
+        &mut gamepads[index]
+    }
+
+    // Resolve held host keys and keys pressed directly onto the keypad
+    fn update_keypad(&mut self) {
+        let keys = self.held.keypad(&self.input, self.rom_hash.as_deref());
+        self.bus.set_pressed_keys(keys);
+    }
+
+    // Cheats are kept against the loaded ROM's hash
+    fn add_cheat(&mut self, cheat: Cheat) {
+        if let Some(hash) = &self.rom_hash {
+            self.cheats.add(hash, cheat);
+        }
+    }
+
+    pub fn cheats(&self) -> &CheatBook {
+        &self.cheats
+    }
+
+    // Register Rust closures to run on instructions, addresses, writes
+    // and frames
+    pub fn hooks(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    // Changes the running quirks and the user's, so the change outlives
+    // database settings
+    fn update_quirks(&mut self, update: impl Fn(&mut Quirks)) {
+        let mut quirks = self.cpu.quirks();
+        update(&mut quirks);
+        self.cpu.set_quirks(quirks);
+        update(&mut self.settings.quirks);
+    }
+
+    // One 60Hz frame with autofire stepped and cheats applied first
+    fn run_frame(&mut self) {
+        self.held.tick();
+        if self.held.has_turbo(&self.input, self.rom_hash.as_deref()) {
+            self.update_keypad();
+        }
+
+        if let Some(hash) = &self.rom_hash {
+            self.cheats.apply(hash, &mut self.cpu, &mut self.bus);
+        }
+
+        let instructions = self.governor.instructions_per_frame();
+        if self.hooks.is_empty() {
+            self.cpu.run_frame(&mut self.bus, instructions);
+        } else {
+            self.hooks.run_frame(&mut self.cpu, &mut self.bus, instructions, self.frame);
+        }
+        self.frame += 1;
+    }
+
+    pub fn speed_mode(&self) -> SpeedMode {
+        self.governor.mode()
+    }
+
+    // Borrow display memory
+    pub fn vram(&self) -> &[u8] {
+        self.bus.get_vram()
+    }
+
+    pub fn memread(&self, addr: u16) -> u8 {
+        self.bus.memread(addr)
+    }
+
+    pub fn memwrite(&mut self, addr: u16, value: u8) {
+        self.bus.memwrite(addr, value);
+    }
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.cpu.register(x)
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.cpu.set_register(x, value);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn cpu(&self) -> &cpu::Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut cpu::Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.bus.memory_size()
+    }
+
+    // End the frame for hosts that run one instruction at a time
+    pub fn tick_timers(&mut self) {
+        self.cpu.tick_timers(&mut self.bus);
+    }
+}
+
+
+// A ROM must start inside memory and fit between its load address and
+// the end of memory
+pub(crate) fn check_rom_fits(rom: &[u8], memory: usize, load_address: u16) -> Result<(), RomError> {
+    if load_address as usize >= memory {
+        return Err(RomError::LoadAddressOutOfRange { addr: load_address, memory });
+    }
+
+    let max = memory - load_address as usize;
+    if rom.len() > max {
+        return Err(RomError::TooLarge { size: rom.len(), max });
+    }
+
+    Ok(())
+}
+
+
+// Write a checked ROM at load_address and restart the cpu there, shared
+// by the Console and Env. Everything from the program area up is cleared
+// first, ROMs starting with the HIRES patch get a 64x64 display and
+// start at the patch's entry point
+pub(crate) fn install_rom(
+    cpu: &mut cpu::Cpu,
+    bus: &mut SystemBus,
+    rom: &[u8],
+    profile: QuirkProfile,
+    load_address: u16,
+) {
+    bus.clear_memory_from(load_address.min(cpu::PROG_START));
+    for (idx, byte) in rom.iter().cloned().enumerate() {
+        bus.memwrite(load_address + idx as u16, byte);
+    }
+
+    let hires = cpu::is_hires(rom, load_address);
+    let (width, height) = if hires {
+        QuirkProfile::HiresChip8.display_size()
+    } else {
+        profile.display_size()
+    };
+    bus.set_display_size(width, height);
+
+    cpu.set_hires(hires);
+    cpu.set_entry_point(if hires { cpu::HIRES_ENTRY } else { load_address });
+    cpu.reset();
+}
+
+
+// A throwing hook shouldn't stop the emulator
+fn report_hook_error(result: Result<JsValue, JsValue>) {
+    if let Err(error) = result {
+        utils::log_str(&format!("hook failed: {:?}", error));
+    }
+}
+
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
 }
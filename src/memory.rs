@@ -1,51 +1,120 @@
-/// CHIP-8 Memory
+//! CHIP-8 Memory
+
+use crate::font::{FontSet, DEFAULT_FONT_ADDR, FONT_SIZE};
 
 pub const MEM_SIZE: usize = 4096;
 
-const FONT_SPRITES: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-];
-
-
-pub struct Ram { mem: [u8; MEM_SIZE] }
+// XO-CHIP extends memory to the full 16 bit address space
+pub const XO_MEM_SIZE: usize = 65536;
+
+#[derive(Clone)]
+pub struct Ram {
+    mem: Vec<u8>,
+    font: [u8; FONT_SIZE],
+    font_addr: u16,
+}
 
 
 impl Ram {
 
     pub fn new() -> Ram {
-        let mut ram = Ram { mem: [0u8; MEM_SIZE] };
-        ram.mem[0..FONT_SPRITES.len()].copy_from_slice(&FONT_SPRITES);
+        Ram::with_size(MEM_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Ram {
+        let mut ram = Ram {
+            mem: vec![0u8; size],
+            font: *FontSet::Chip48.sprites(),
+            font_addr: DEFAULT_FONT_ADDR,
+        };
+        ram.write_font();
         ram
     }
 
     pub fn reset(&mut self){
-        self.mem = [0u8; MEM_SIZE];
-        self.mem[0..FONT_SPRITES.len()].copy_from_slice(&FONT_SPRITES);
+        self.mem.iter_mut().for_each(|b| *b = 0);
+        self.write_font();
+    }
+
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    // Overwrite with another RAM's contents, reusing the allocation
+    pub fn copy_from(&mut self, other: &Ram) {
+        self.mem.clear();
+        self.mem.extend_from_slice(&other.mem);
+        self.font = other.font;
+        self.font_addr = other.font_addr;
     }
 
+    // Addresses wrap around the address space
     pub fn memwrite(&mut self, addr: u16, byte: u8) {
-        self.mem[addr as usize] = byte;
+        let size = self.size();
+        self.mem[addr as usize % size] = byte;
     }
 
     pub fn memread(&self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+        self.mem[addr as usize % self.size()]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mem
+    }
+
+    // Borrow len bytes starting at addr, cut short at the end of memory
+    pub fn slice(&self, addr: u16, len: u16) -> &[u8] {
+        let start = addr as usize % self.size();
+        let end = (start + len as usize).min(self.size());
+        &self.mem[start..end]
+    }
+
+    // Zero everything from addr to the end of memory
+    pub fn clear_from(&mut self, addr: u16) {
+        let start = (addr as usize).min(self.size());
+        self.mem[start..].iter_mut().for_each(|b| *b = 0);
+        self.write_font();
     }
 
+    pub fn font(&self) -> &[u8; FONT_SIZE] {
+        &self.font
+    }
+
+    pub fn font_address(&self) -> u16 {
+        self.font_addr
+    }
+
+    // Replace the font, it survives resets and ROM loads
+    pub fn set_font(&mut self, font: &[u8; FONT_SIZE]) {
+        self.font = *font;
+        self.write_font();
+    }
+
+    // Move the font, the old copy is zeroed. Callers check it fits
+    pub fn set_font_address(&mut self, addr: u16) {
+        let old = self.font_addr as usize;
+        self.mem[old..old + FONT_SIZE].iter_mut().for_each(|b| *b = 0);
+        self.font_addr = addr;
+        self.write_font();
+    }
+
+    // Whether the font fits at addr in this much memory
+    pub fn font_fits(addr: u16, size: usize) -> bool {
+        addr as usize + FONT_SIZE <= size
+    }
+
+    fn write_font(&mut self) {
+        let start = self.font_addr as usize;
+        self.mem[start..start + FONT_SIZE].copy_from_slice(&self.font);
+    }
+
+}
+
+
+impl Default for Ram {
+    fn default() -> Ram {
+        Ram::new()
+    }
 }
 
 
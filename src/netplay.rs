@@ -0,0 +1,589 @@
+//! Lockstep netplay
+//!
+//! Two peers run the same ROM with the same seed and only exchange
+//! keypad input. Each frame's input is scheduled `input_delay` frames
+//! ahead and sent to the peer, a frame runs once both players' input for
+//! it has arrived, so both machines step through identical states. Every
+//! packet also carries the hash of the sender's last frame, a mismatch
+//! with ours for that frame means the machines have desynced.
+//!
+//! The transport is up to the frontend, packets are plain bytes. Peers
+//! can meet through the relay in `relay`, native ones over TCP and
+//! browsers over WebSocket
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use wasm_bindgen::prelude::*;
+
+use crate::error::NetplayError;
+use crate::machine::Console;
+
+
+pub const PACKET_SIZE: usize = 19;
+
+// Three frames, 50ms, hides the latency of most local networks
+pub const DEFAULT_INPUT_DELAY: u32 = 3;
+
+
+// Keys one player holds on a frame, and the sender's latest state hash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputPacket {
+    pub frame: u32,
+    pub keys: u16,
+    pub checksum: Option<(u32, u64)>,  // Frame and hash after it ran
+}
+
+
+impl InputPacket {
+
+    // Big endian frame, keys, then a flag byte and the checksum
+    pub fn to_bytes(&self) -> [u8; PACKET_SIZE] {
+        let mut bytes = [0u8; PACKET_SIZE];
+        bytes[0..4].copy_from_slice(&self.frame.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.keys.to_be_bytes());
+        if let Some((frame, hash)) = self.checksum {
+            bytes[6] = 1;
+            bytes[7..11].copy_from_slice(&frame.to_be_bytes());
+            bytes[11..19].copy_from_slice(&hash.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<InputPacket, NetplayError> {
+        let bytes: &[u8; PACKET_SIZE] = bytes.try_into()
+            .map_err(|_| NetplayError::WrongPacketSize { size: bytes.len(), expected: PACKET_SIZE })?;
+
+        let checksum = if bytes[6] != 0 {
+            let frame = u32::from_be_bytes(bytes[7..11].try_into().unwrap());
+            let hash = u64::from_be_bytes(bytes[11..19].try_into().unwrap());
+            Some((frame, hash))
+        } else {
+            None
+        };
+
+        Ok(InputPacket {
+            frame: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            keys: u16::from_be_bytes(bytes[4..6].try_into().unwrap()),
+            checksum,
+        })
+    }
+}
+
+
+pub fn write_packet(writer: &mut impl Write, packet: &InputPacket) -> io::Result<()> {
+    writer.write_all(&packet.to_bytes())
+}
+
+
+pub fn read_packet(reader: &mut impl Read) -> io::Result<InputPacket> {
+    let mut bytes = [0u8; PACKET_SIZE];
+    reader.read_exact(&mut bytes)?;
+    InputPacket::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+
+// State hashes from both peers, matched up by frame
+#[derive(Clone, Debug, Default)]
+pub struct Checksums {
+    local: BTreeMap<u32, u64>,   // Kept until the peer's hash arrives
+    remote: BTreeMap<u32, u64>,
+    desync: Option<u32>,
+}
+
+
+impl Checksums {
+
+    pub fn new() -> Checksums {
+        Checksums::default()
+    }
+
+    // Record our hash after frame, hashes before keep_from are dropped
+    // as the peer will never send them
+    pub fn record_local(&mut self, frame: u32, hash: u64, keep_from: u32) {
+        match self.remote.remove(&frame) {
+            Some(remote) => self.compare(frame, hash, remote),
+            None => { self.local.insert(frame, hash); }
+        }
+
+        self.local = self.local.split_off(&keep_from);
+        self.remote = self.remote.split_off(&keep_from);
+    }
+
+    pub fn record_remote(&mut self, frame: u32, hash: u64) {
+        match self.local.remove(&frame) {
+            Some(local) => self.compare(frame, local, hash),
+            None => { self.remote.insert(frame, hash); }
+        }
+    }
+
+    // Newest hash of ours the peer hasn't matched yet, to send
+    pub fn latest_local(&self) -> Option<(u32, u64)> {
+        self.local.iter().next_back().map(|(&frame, &hash)| (frame, hash))
+    }
+
+    // First frame the machines' hashes differed on
+    pub fn desync_frame(&self) -> Option<u32> {
+        self.desync
+    }
+
+    fn compare(&mut self, frame: u32, local: u64, remote: u64) {
+        if local != remote && self.desync.map_or(true, |first| frame < first) {
+            self.desync = Some(frame);
+        }
+    }
+}
+
+
+#[wasm_bindgen]
+pub struct Lockstep {
+    input_delay: u32,
+    frame: u32,                        // Next frame to run
+    next_local: u32,                   // Next frame to schedule local input for
+    local: BTreeMap<u32, u16>,
+    remote: BTreeMap<u32, u16>,
+    checksums: Checksums,
+}
+
+
+#[wasm_bindgen]
+impl Lockstep {
+
+    pub fn new(input_delay: u32) -> Lockstep {
+        Lockstep {
+            input_delay,
+            frame: 0,
+            next_local: input_delay,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            checksums: Checksums::new(),
+        }
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn input_delay(&self) -> u32 {
+        self.input_delay
+    }
+
+    // First frame the machines' hashes differed on
+    pub fn desync_frame(&self) -> Option<u32> {
+        self.checksums.desync_frame()
+    }
+
+    // Schedule local keys, returns the packet to send or nothing when
+    // input is already queued input_delay frames ahead
+    pub fn local_input(&mut self, keys: u16) -> Option<Vec<u8>> {
+        self.add_local_input(keys).map(|packet| packet.to_bytes().to_vec())
+    }
+
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<(), NetplayError> {
+        self.receive_packet(InputPacket::from_bytes(bytes)?);
+        Ok(())
+    }
+
+    // Run the next frame on the console if both players' input is in,
+    // returns false while waiting on the peer
+    pub fn advance(&mut self, console: &mut Console) -> bool {
+        let keys = match self.inputs(self.frame) {
+            Some((local, remote)) => local | remote,
+            None => return false,
+        };
+
+        console.step_frame(keys);
+
+        // Peers stay within a few frames of each other
+        let keep_from = self.frame.saturating_sub(self.input_delay + 1);
+        self.checksums.record_local(self.frame, console.state_hash(), keep_from);
+
+        self.frame += 1;
+        self.local.remove(&(self.frame - 1));
+        self.remote.remove(&(self.frame - 1));
+        true
+    }
+}
+
+
+// Not exported to JS
+impl Lockstep {
+
+    pub fn add_local_input(&mut self, keys: u16) -> Option<InputPacket> {
+        if self.next_local > self.frame + self.input_delay {
+            return None;
+        }
+
+        let frame = self.next_local;
+        self.local.insert(frame, keys);
+        self.next_local += 1;
+
+        Some(InputPacket { frame, keys, checksum: self.checksums.latest_local() })
+    }
+
+    pub fn receive_packet(&mut self, packet: InputPacket) {
+        if packet.frame >= self.frame {
+            self.remote.insert(packet.frame, packet.keys);
+        }
+
+        if let Some((frame, hash)) = packet.checksum {
+            self.checksums.record_remote(frame, hash);
+        }
+    }
+
+    // Both players' keys for a frame, nobody presses anything before the
+    // first input arrives
+    fn inputs(&self, frame: u32) -> Option<(u16, u16)> {
+        if frame < self.input_delay {
+            return Some((0, 0));
+        }
+        Some((*self.local.get(&frame)?, *self.remote.get(&frame)?))
+    }
+}
+
+
+// Pairs up peers on one machine or a LAN, each pair of clients that
+// connects gets the packets the other sends. Native peers send bare
+// packets over TCP, browsers connect with WebSocket and send one packet
+// per message, the two kinds can play each other
+#[cfg(not(target_arch = "wasm32"))]
+pub mod relay {
+    use std::io::{self, Read};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::PACKET_SIZE;
+
+    pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+
+    // A connected client and how its packets are framed
+    pub enum Peer {
+        Tcp(TcpStream),
+        WebSocket(TcpStream),
+    }
+
+
+    impl Peer {
+
+        // Browsers open with an HTTP upgrade request, a native peer's
+        // first packet starts with the top byte of a small frame number
+        // and never with the G of GET
+        pub fn accept(mut stream: TcpStream) -> io::Result<Peer> {
+            stream.set_nodelay(true)?;
+
+            let mut first = [0u8; 1];
+            if stream.peek(&mut first)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if first[0] != b'G' {
+                return Ok(Peer::Tcp(stream));
+            }
+
+            websocket::handshake(&mut stream)?;
+            Ok(Peer::WebSocket(stream))
+        }
+
+        fn try_clone(&self) -> io::Result<Peer> {
+            Ok(match self {
+                Peer::Tcp(stream) => Peer::Tcp(stream.try_clone()?),
+                Peer::WebSocket(stream) => Peer::WebSocket(stream.try_clone()?),
+            })
+        }
+
+        // Next packet's bytes, None once the peer hangs up
+        pub fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+            match self {
+                Peer::Tcp(stream) => {
+                    let mut bytes = vec![0u8; PACKET_SIZE];
+                    match stream.read_exact(&mut bytes) {
+                        Ok(()) => Ok(Some(bytes)),
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                }
+                Peer::WebSocket(stream) => websocket::read_message(stream),
+            }
+        }
+
+        pub fn write_packet(&mut self, bytes: &[u8]) -> io::Result<()> {
+            match self {
+                Peer::Tcp(stream) => io::Write::write_all(stream, bytes),
+                Peer::WebSocket(stream) => websocket::write_message(stream, bytes, None),
+            }
+        }
+
+        // Tell the peer nothing more is coming
+        fn finish(&mut self) {
+            let stream = match self {
+                Peer::Tcp(stream) => stream,
+                Peer::WebSocket(stream) => {
+                    let _ = websocket::write_close(stream);
+                    stream
+                }
+            };
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+    }
+
+
+    // Accept clients forever and pair them up in the order their
+    // handshakes finish, a slow client doesn't hold up the others
+    pub fn serve(listener: &TcpListener) -> io::Result<()> {
+        let (sender, peers) = mpsc::channel();
+        thread::spawn(move || {
+            while let (Ok(first), Ok(second)) = (peers.recv(), peers.recv()) {
+                let _ = pair(first, second);
+            }
+        });
+
+        for stream in listener.incoming() {
+            let sender = sender.clone();
+            let stream = stream?;
+            thread::spawn(move || {
+                if let Ok(peer) = Peer::accept(stream) {
+                    let _ = sender.send(peer);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Forward each peer's packets to the other until one hangs up
+    pub fn pair(first: Peer, second: Peer) -> io::Result<()> {
+        let forward = |mut from: Peer, mut to: Peer| {
+            thread::spawn(move || {
+                while let Ok(Some(packet)) = from.read_packet() {
+                    if to.write_packet(&packet).is_err() {
+                        break;
+                    }
+                }
+                to.finish();
+            })
+        };
+        forward(first.try_clone()?, second.try_clone()?);
+        forward(second, first);
+        Ok(())
+    }
+
+
+    // Just enough of RFC 6455 for small binary messages
+    pub mod websocket {
+        use std::io::{self, Read, Write};
+
+        use sha1::{Digest, Sha1};
+
+        const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+        // Longest upgrade request and message accepted
+        const MAX_REQUEST: usize = 8192;
+        const MAX_MESSAGE: u64 = 1024;
+
+        const OP_TEXT: u8 = 0x1;
+        const OP_BINARY: u8 = 0x2;
+        const OP_CLOSE: u8 = 0x8;
+        const OP_PING: u8 = 0x9;
+        const OP_PONG: u8 = 0xA;
+
+        const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+        // Answer the client's upgrade request
+        pub fn handshake(stream: &mut (impl Read + Write)) -> io::Result<()> {
+            let request = read_request(stream)?;
+            let key = request.lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+                .map(|(_, value)| value.trim())
+                .ok_or_else(|| invalid("upgrade request without Sec-WebSocket-Key"))?;
+
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            )
+        }
+
+        // Proves to the client that the server speaks WebSocket
+        pub fn accept_key(key: &str) -> String {
+            base64(&Sha1::digest(format!("{}{}", key, GUID).as_bytes()))
+        }
+
+        // Byte at a time, so nothing after the request is read early
+        fn read_request(reader: &mut impl Read) -> io::Result<String> {
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                if request.len() == MAX_REQUEST {
+                    return Err(invalid("upgrade request too long"));
+                }
+                reader.read_exact(&mut byte)?;
+                request.push(byte[0]);
+            }
+            Ok(String::from_utf8_lossy(&request).into_owned())
+        }
+
+        // Next data message's payload, None once the peer closes.
+        // Fragmented messages aren't supported, browsers don't split
+        // messages this small
+        pub fn read_message(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+            loop {
+                let mut header = [0u8; 2];
+                match reader.read_exact(&mut header) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+
+                let fin = header[0] & 0x80 != 0;
+                let opcode = header[0] & 0x0F;
+                let len = match header[1] & 0x7F {
+                    126 => {
+                        let mut len = [0u8; 2];
+                        reader.read_exact(&mut len)?;
+                        u16::from_be_bytes(len) as u64
+                    }
+                    127 => {
+                        let mut len = [0u8; 8];
+                        reader.read_exact(&mut len)?;
+                        u64::from_be_bytes(len)
+                    }
+                    len => len as u64,
+                };
+                if len > MAX_MESSAGE {
+                    return Err(invalid("WebSocket message too long"));
+                }
+
+                let mut mask = [0u8; 4];
+                if header[1] & 0x80 != 0 {
+                    reader.read_exact(&mut mask)?;
+                }
+                let mut payload = vec![0u8; len as usize];
+                reader.read_exact(&mut payload)?;
+                payload.iter_mut().zip(mask.iter().cycle()).for_each(|(b, m)| *b ^= m);
+
+                match opcode {
+                    OP_TEXT | OP_BINARY if fin => return Ok(Some(payload)),
+                    OP_CLOSE => return Ok(None),
+                    OP_PING | OP_PONG => continue,
+                    _ => return Err(invalid("unsupported WebSocket frame")),
+                }
+            }
+        }
+
+        // One binary message, clients mask what they send and servers don't
+        pub fn write_message(writer: &mut impl Write, payload: &[u8], mask: Option<[u8; 4]>) -> io::Result<()> {
+            let masked = if mask.is_some() { 0x80 } else { 0 };
+            let mut frame = vec![0x80 | OP_BINARY];
+            match payload.len() {
+                len if len < 126 => frame.push(masked | len as u8),
+                len if len <= u16::MAX as usize => {
+                    frame.push(masked | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+                len => {
+                    frame.push(masked | 127);
+                    frame.extend_from_slice(&(len as u64).to_be_bytes());
+                }
+            }
+
+            match mask {
+                Some(mask) => {
+                    frame.extend_from_slice(&mask);
+                    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+                }
+                None => frame.extend_from_slice(payload),
+            }
+            writer.write_all(&frame)
+        }
+
+        pub fn write_close(writer: &mut impl Write) -> io::Result<()> {
+            writer.write_all(&[0x80 | OP_CLOSE, 0])
+        }
+
+        fn base64(bytes: &[u8]) -> String {
+            let mut encoded = String::new();
+            for chunk in bytes.chunks(3) {
+                let n = chunk.iter().enumerate()
+                    .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+                for i in 0..4 {
+                    if i <= chunk.len() {
+                        encoded.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+                    } else {
+                        encoded.push('=');
+                    }
+                }
+            }
+            encoded
+        }
+
+        fn invalid(message: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = InputPacket { frame: 70000, keys: 0x8001, checksum: Some((69999, u64::MAX - 1)) };
+        assert_eq!(InputPacket::from_bytes(&packet.to_bytes()), Ok(packet));
+
+        let packet = InputPacket { checksum: None, ..packet };
+        assert_eq!(InputPacket::from_bytes(&packet.to_bytes()), Ok(packet));
+
+        assert_eq!(
+            InputPacket::from_bytes(&[0; 3]),
+            Err(NetplayError::WrongPacketSize { size: 3, expected: PACKET_SIZE })
+        );
+    }
+
+    #[test]
+    fn test_waits_for_remote_input_and_spots_desync() {
+        let mut console = Console::new();
+        let mut session = Lockstep::new(2);
+
+        // Local input runs ahead by the delay and no further
+        assert_eq!(session.add_local_input(1).map(|p| p.frame), Some(2));
+        assert_eq!(session.add_local_input(9), None);
+
+        assert!(session.advance(&mut console));
+        assert!(session.advance(&mut console));
+        assert!(!session.advance(&mut console));
+
+        session.receive_packet(InputPacket { frame: 2, keys: 1, checksum: Some((1, 0)) });
+        assert_eq!(session.desync_frame(), Some(1));
+        assert!(session.advance(&mut console));
+        assert_eq!(session.frame(), 3);
+    }
+
+    #[test]
+    fn test_websocket_handshake_and_frames() {
+        use relay::websocket;
+
+        // The example from RFC 6455
+        assert_eq!(websocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let packet = InputPacket { frame: 5, keys: 0x10, checksum: None }.to_bytes();
+        let mut frames = Vec::new();
+        websocket::write_message(&mut frames, &packet, Some([1, 2, 3, 4])).unwrap();
+        websocket::write_message(&mut frames, &[0; 300], None).unwrap();
+        websocket::write_close(&mut frames).unwrap();
+        assert_eq!(frames[1], 0x80 | PACKET_SIZE as u8);
+
+        let mut reader = &frames[..];
+        assert_eq!(websocket::read_message(&mut reader).unwrap(), Some(packet.to_vec()));
+        assert_eq!(websocket::read_message(&mut reader).unwrap(), Some(vec![0; 300]));
+        assert_eq!(websocket::read_message(&mut reader).unwrap(), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Execution profiler
+//!
+//! Counts instructions by address, by opcode pattern and by the call
+//! stack they ran under. Subroutines are tracked by pairing 2NNN with
+//! 00EE, the main program is the root of every stack
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::instruction::Instruction;
+
+
+// Draws issued and how many of them collided during one frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FrameStats {
+    pub draws: u32,
+    pub collisions: u32,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Hotspot {
+    pub addr: u16,
+    pub count: u64,
+}
+
+
+// Instructions run inside a subroutine, excluding the ones it called
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SubroutineStats {
+    pub entry: u16,
+    pub calls: u64,
+    pub instructions: u64,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub instructions: u64,
+    pub hotspots: Vec<Hotspot>,
+    pub opcodes: Vec<(&'static str, u64)>,
+    pub subroutines: Vec<SubroutineStats>,
+    pub frames: Vec<FrameStats>,
+}
+
+
+#[derive(Clone)]
+pub struct Profiler {
+    entry: u16,
+    instructions: u64,
+    per_pc: HashMap<u16, u64>,
+    per_pattern: HashMap<&'static str, u64>,
+    calls: HashMap<u16, u64>,
+    self_counts: HashMap<u16, u64>,
+    stacks: HashMap<Vec<u16>, u64>,  // Folded call stacks, entry first
+    call_stack: Vec<u16>,
+    frames: Vec<FrameStats>,
+    frame: FrameStats,
+}
+
+
+impl Profiler {
+
+    // entry is the main program's start, the root of each stack
+    pub fn new(entry: u16) -> Profiler {
+        Profiler {
+            entry,
+            instructions: 0,
+            per_pc: HashMap::new(),
+            per_pattern: HashMap::new(),
+            calls: HashMap::new(),
+            self_counts: HashMap::new(),
+            stacks: HashMap::new(),
+            call_stack: vec![entry],
+            frames: Vec::new(),
+            frame: FrameStats::default(),
+        }
+    }
+
+    // Count an instruction about to execute at pc
+    pub fn record(&mut self, pc: u16, instruction: &Instruction) {
+        self.instructions += 1;
+        *self.per_pc.entry(pc).or_insert(0) += 1;
+        *self.per_pattern.entry(instruction.pattern()).or_insert(0) += 1;
+
+        let current = *self.call_stack.last().unwrap_or(&self.entry);
+        *self.self_counts.entry(current).or_insert(0) += 1;
+
+        if let Some(count) = self.stacks.get_mut(&self.call_stack) {
+            *count += 1;
+        } else {
+            self.stacks.insert(self.call_stack.clone(), 1);
+        }
+    }
+
+    // A 2NNN completed, later instructions belong to target
+    pub fn enter(&mut self, target: u16) {
+        *self.calls.entry(target).or_insert(0) += 1;
+        self.call_stack.push(target);
+    }
+
+    // A 00EE completed, the main program is never popped
+    pub fn leave(&mut self) {
+        if self.call_stack.len() > 1 {
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn record_draw(&mut self, collision: bool) {
+        self.frame.draws += 1;
+        if collision {
+            self.frame.collisions += 1;
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames.push(self.frame);
+        self.frame = FrameStats::default();
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn frames(&self) -> &[FrameStats] {
+        &self.frames
+    }
+
+    // Most executed addresses, busiest first
+    pub fn hotspots(&self, limit: usize) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self.per_pc.iter()
+            .map(|(&addr, &count)| Hotspot { addr, count })
+            .collect();
+        hotspots.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+        hotspots.truncate(limit);
+        hotspots
+    }
+
+    // Executions per opcode pattern, busiest first
+    pub fn opcode_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = self.per_pattern.iter()
+            .map(|(&pattern, &count)| (pattern, count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    // Every subroutine that ran, busiest first. The main program is
+    // listed with zero calls
+    pub fn subroutines(&self) -> Vec<SubroutineStats> {
+        let mut subroutines: Vec<SubroutineStats> = self.self_counts.iter()
+            .map(|(&entry, &instructions)| SubroutineStats {
+                entry,
+                calls: self.calls.get(&entry).copied().unwrap_or(0),
+                instructions,
+            })
+            .collect();
+        subroutines.sort_by(|a, b| b.instructions.cmp(&a.instructions).then(a.entry.cmp(&b.entry)));
+        subroutines
+    }
+
+    pub fn report(&self, hotspots: usize) -> Report {
+        Report {
+            instructions: self.instructions,
+            hotspots: self.hotspots(hotspots),
+            opcodes: self.opcode_counts(),
+            subroutines: self.subroutines(),
+            frames: self.frames.clone(),
+        }
+    }
+
+    // Folded stacks for flamegraph.pl and compatible tools, one
+    // `main;sub_0208;sub_0300 42` line per distinct stack
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .map(|(stack, count)| {
+                let mut line = String::from("main");
+                for addr in &stack[1..] {
+                    let _ = write!(line, ";sub_{:04X}", addr);
+                }
+                let _ = write!(line, " {}", count);
+                line
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
@@ -0,0 +1,164 @@
+//! Behavioural differences between CHIP-8 interpreters
+//!
+//! Names follow Timendus' chip8-test-suite quirks test
+
+use wasm_bindgen::prelude::*;
+
+use crate::cpu::{CHIP8X_PROG_START, PROG_START};
+use crate::display::{CHIP10_WIDTH, HEIGHT, HIRES_HEIGHT, WIDTH};
+use crate::memory::{MEM_SIZE, XO_MEM_SIZE};
+
+
+// Named quirk profiles selectable from the frontend
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuirkProfile {
+    Chip8,
+    SuperChip,
+    XoChip,
+    Chip8X,
+    HiresChip8,  // VIP with the 64x64 HIRES patch
+    Chip10,      // 128x64 VIP variant
+}
+
+
+impl QuirkProfile {
+
+    // Bytes of RAM on the platform
+    pub fn memory_size(self) -> usize {
+        match self {
+            QuirkProfile::XoChip => XO_MEM_SIZE,
+            _ => MEM_SIZE,
+        }
+    }
+
+    // Display width and height in pixels
+    pub fn display_size(self) -> (usize, usize) {
+        match self {
+            QuirkProfile::HiresChip8 => (WIDTH, HIRES_HEIGHT),
+            QuirkProfile::Chip10 => (CHIP10_WIDTH, HIRES_HEIGHT),
+            _ => (WIDTH, HEIGHT),
+        }
+    }
+
+    // Where the platform's programs are loaded and start
+    pub fn load_address(self) -> u16 {
+        match self {
+            QuirkProfile::Chip8X => CHIP8X_PROG_START,
+            _ => PROG_START,
+        }
+    }
+}
+
+
+// Number of nested 2NNN calls before the stack overflows
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackDepth {
+    Twelve,
+    Sixteen,
+    Unlimited,
+}
+
+
+impl StackDepth {
+
+    pub fn limit(self) -> Option<usize> {
+        match self {
+            StackDepth::Twelve => Some(12),
+            StackDepth::Sixteen => Some(16),
+            StackDepth::Unlimited => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub vf_reset: bool,   // 8XY1, 8XY2, 8XY3 reset VF to 0
+    pub memory: bool,     // FX55, FX65 increment I
+    pub memory_by_x: bool,   // The increment is X, not X + 1, like SUPER-CHIP 1.0
+    pub shifting: bool,   // 8XY6, 8XYE shift VX in place, ignoring VY
+    pub jumping: bool,    // BNNN behaves as BXNN, jumping to XNN + VX
+    pub display_wait: bool,  // DXYN waits for vblank, ending the frame
+    pub clipping: bool,   // Sprites are clipped at the screen edge, not wrapped
+    pub stack_depth: StackDepth,
+    pub stack_in_ram: bool,  // Return addresses live in RAM like the VIP
+    pub chip8x: bool,     // CHIP-8X colour, second keypad and port opcodes
+}
+
+
+impl Quirks {
+
+    // Original COSMAC VIP interpreter
+    pub fn chip8() -> Quirks {
+        Quirks {
+            vf_reset: true,
+            memory: true,
+            memory_by_x: false,
+            shifting: false,
+            jumping: false,
+            display_wait: true,
+            clipping: true,
+            stack_depth: StackDepth::Twelve,
+            stack_in_ram: false,
+            chip8x: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1 on the HP48
+    pub fn schip() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory: false,
+            memory_by_x: false,
+            shifting: true,
+            jumping: true,
+            display_wait: false,
+            clipping: true,
+            stack_depth: StackDepth::Sixteen,
+            stack_in_ram: false,
+            chip8x: false,
+        }
+    }
+
+    // Octo's XO-CHIP
+    pub fn xochip() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory: true,
+            memory_by_x: false,
+            shifting: false,
+            jumping: false,
+            display_wait: false,
+            clipping: false,
+            stack_depth: StackDepth::Sixteen,
+            stack_in_ram: false,
+            chip8x: false,
+        }
+    }
+
+    // CHIP-8X on the VIP with the VP-590 colour board
+    pub fn chip8x() -> Quirks {
+        Quirks {
+            chip8x: true,
+            ..Quirks::chip8()
+        }
+    }
+
+    pub fn from_profile(profile: QuirkProfile) -> Quirks {
+        match profile {
+            QuirkProfile::Chip8 | QuirkProfile::HiresChip8 | QuirkProfile::Chip10 => Quirks::chip8(),
+            QuirkProfile::SuperChip => Quirks::schip(),
+            QuirkProfile::XoChip => Quirks::xochip(),
+            QuirkProfile::Chip8X => Quirks::chip8x(),
+        }
+    }
+}
+
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::chip8()
+    }
+}
@@ -0,0 +1,346 @@
+//! Rollback netplay
+//!
+//! Unlike `Lockstep`, frames don't wait for the peer. The remote player is
+//! predicted to keep holding whatever they last held, and the machine is
+//! saved before every frame that ran on a guess. When the real input
+//! arrives and differs, the session loads the state from the first wrong
+//! frame and runs every frame since again. A save state is the cpu, RAM
+//! and VRAM, 4 KiB of RAM or 64 KiB on XO-CHIP. The decode cache isn't
+//! saved, loading a state keeps the machine's own and only drops the
+//! instructions whose bytes changed.
+//!
+//! Packets may be lost or arrive out of order, each one repeats every
+//! local input the peer hasn't acknowledged yet
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use wasm_bindgen::prelude::*;
+
+use crate::error::NetplayError;
+use crate::machine::{Console, SaveState};
+use crate::netplay::Checksums;
+
+
+// Frames that may run ahead of the peer's input before the session stalls
+pub const DEFAULT_MAX_ROLLBACK: u32 = 8;
+
+const HEADER_SIZE: usize = 22;
+const MAX_PACKET_INPUTS: usize = 255;
+
+
+// Local input from frame on, and how much of the peer's input we have
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RollbackPacket {
+    pub frame: u32,                    // Frame of the first input
+    pub inputs: Vec<u16>,
+    pub ack: u32,                      // Sender has the receiver's input before this frame
+    pub checksum: Option<(u32, u64)>,  // Frame and hash after it ran
+}
+
+
+impl RollbackPacket {
+
+    // Big endian frame, ack, checksum flag and checksum, then a count
+    // byte and the inputs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let inputs = &self.inputs[..self.inputs.len().min(MAX_PACKET_INPUTS)];
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + inputs.len() * 2);
+        bytes.extend_from_slice(&self.frame.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+
+        let (flag, (frame, hash)) = match self.checksum {
+            Some(checksum) => (1, checksum),
+            None => (0, (0, 0)),
+        };
+        bytes.push(flag);
+        bytes.extend_from_slice(&frame.to_be_bytes());
+        bytes.extend_from_slice(&hash.to_be_bytes());
+
+        bytes.push(inputs.len() as u8);
+        for keys in inputs {
+            bytes.extend_from_slice(&keys.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RollbackPacket, NetplayError> {
+        let count = bytes.get(HEADER_SIZE - 1).copied().unwrap_or(0) as usize;
+        let expected = HEADER_SIZE + count * 2;
+        if bytes.len() != expected {
+            return Err(NetplayError::WrongPacketSize { size: bytes.len(), expected });
+        }
+
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let checksum = if bytes[8] != 0 {
+            Some((u32_at(9), u64::from_be_bytes(bytes[13..21].try_into().unwrap())))
+        } else {
+            None
+        };
+
+        Ok(RollbackPacket {
+            frame: u32_at(0),
+            inputs: bytes[HEADER_SIZE..].chunks(2)
+                .map(|keys| u16::from_be_bytes([keys[0], keys[1]]))
+                .collect(),
+            ack: u32_at(4),
+            checksum,
+        })
+    }
+}
+
+
+#[wasm_bindgen]
+pub struct Rollback {
+    input_delay: u32,
+    max_rollback: u32,
+    frame: u32,                        // Next frame to run
+    next_local: u32,                   // Next frame to schedule local input for
+    local: BTreeMap<u32, u16>,         // Kept until the peer acknowledges it
+    remote: BTreeMap<u32, u16>,
+    confirmed: u32,                    // Remote input is in for every frame before this
+    last_remote: u16,                  // Keys of the last confirmed frame, the prediction
+    predicted: BTreeMap<u32, u16>,     // Remote keys guessed for frames already run
+    peer_ack: u32,                     // Peer has our input for every frame before this
+    states: BTreeMap<u32, SaveState>,  // Machine before each frame that may be wrong
+    checksums: Checksums,
+    rolled_back: u64,                  // Frames run again after a misprediction
+}
+
+
+#[wasm_bindgen]
+impl Rollback {
+
+    pub fn new(input_delay: u32, max_rollback: u32) -> Rollback {
+        Rollback {
+            input_delay,
+            max_rollback: max_rollback.max(1),
+            frame: 0,
+            next_local: input_delay,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            confirmed: input_delay,
+            last_remote: 0,
+            predicted: BTreeMap::new(),
+            peer_ack: input_delay,
+            states: BTreeMap::new(),
+            checksums: Checksums::new(),
+            rolled_back: 0,
+        }
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    // Frames before this have the peer's real input
+    pub fn confirmed_frame(&self) -> u32 {
+        self.confirmed
+    }
+
+    pub fn rolled_back_frames(&self) -> u64 {
+        self.rolled_back
+    }
+
+    pub fn desync_frame(&self) -> Option<u32> {
+        self.checksums.desync_frame()
+    }
+
+    // Schedule local keys input_delay frames ahead, false when input is
+    // already queued that far
+    pub fn local_input(&mut self, keys: u16) -> bool {
+        self.add_local_input(keys)
+    }
+
+    // Bytes to send, once a frame is plenty
+    pub fn packet(&self) -> Vec<u8> {
+        self.outgoing().to_bytes()
+    }
+
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<(), NetplayError> {
+        self.receive_packet(RollbackPacket::from_bytes(bytes)?);
+        Ok(())
+    }
+
+    // Correct any mispredicted frames, then run the next one. Returns
+    // false when local input for it is missing or the session is
+    // max_rollback frames ahead of the peer
+    pub fn advance(&mut self, console: &mut Console) -> bool {
+        self.reconcile(console);
+
+        let has_local = self.frame < self.input_delay || self.local.contains_key(&self.frame);
+        if !has_local || self.frame >= self.confirmed + self.max_rollback {
+            return false;
+        }
+
+        self.run_frame(console, self.frame);
+        self.frame += 1;
+        true
+    }
+
+    // Run the frames since the first misprediction again with the input
+    // that has arrived, without running a new frame
+    pub fn reconcile(&mut self, console: &mut Console) {
+        let confirmed = self.confirmed;
+        let mispredicted = self.predicted.range(..confirmed)
+            .find(|&(frame, &keys)| self.remote[frame] != keys)
+            .map(|(&frame, _)| frame);
+
+        self.predicted = self.predicted.split_off(&confirmed);
+
+        if let Some(first) = mispredicted {
+            console.load_state(&self.states[&first]);
+            for frame in first..self.frame {
+                self.run_frame(console, frame);
+            }
+            self.rolled_back += (self.frame - first) as u64;
+        }
+
+        // Frames run on confirmed input can't be wrong any more
+        let settled = confirmed.min(self.frame);
+        self.states = self.states.split_off(&settled);
+        self.remote = self.remote.split_off(&settled);
+        self.local = self.local.split_off(&settled.min(self.peer_ack));
+    }
+}
+
+
+// Not exported to JS
+impl Rollback {
+
+    pub fn add_local_input(&mut self, keys: u16) -> bool {
+        if self.next_local > self.frame + self.input_delay {
+            return false;
+        }
+
+        self.local.insert(self.next_local, keys);
+        self.next_local += 1;
+        true
+    }
+
+    // Every local input the peer hasn't acknowledged
+    pub fn outgoing(&self) -> RollbackPacket {
+        RollbackPacket {
+            frame: self.peer_ack,
+            inputs: self.local.range(self.peer_ack..)
+                .take(MAX_PACKET_INPUTS)
+                .map(|(_, &keys)| keys)
+                .collect(),
+            ack: self.confirmed,
+            checksum: self.checksums.latest_local(),
+        }
+    }
+
+    pub fn receive_packet(&mut self, packet: RollbackPacket) {
+        for (frame, &keys) in (packet.frame..).zip(&packet.inputs) {
+            if frame >= self.confirmed {
+                self.remote.entry(frame).or_insert(keys);
+            }
+        }
+
+        while let Some(&keys) = self.remote.get(&self.confirmed) {
+            self.last_remote = keys;
+            self.confirmed += 1;
+        }
+
+        self.peer_ack = self.peer_ack.max(packet.ack);
+
+        if let Some((frame, hash)) = packet.checksum {
+            self.checksums.record_remote(frame, hash);
+        }
+    }
+
+    // Run a frame with the best input known, saving the machine first
+    // in case the remote keys turn out wrong
+    fn run_frame(&mut self, console: &mut Console, frame: u32) {
+        if frame < self.input_delay {
+            console.step_frame(0);
+            return;
+        }
+
+        let remote = match self.remote.get(&frame) {
+            Some(&keys) if frame < self.confirmed => keys,
+            _ => {
+                self.states.insert(frame, console.save_state());
+                self.predicted.insert(frame, self.last_remote);
+                self.last_remote
+            }
+        };
+
+        console.step_frame(self.local[&frame] | remote);
+
+        // Only frames on real input from both players are worth comparing
+        if frame < self.confirmed {
+            let keep_from = frame.saturating_sub(2 * (self.max_rollback + self.input_delay));
+            self.checksums.record_local(frame, console.state_hash(), keep_from);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = RollbackPacket {
+            frame: 300,
+            inputs: vec![0, 0x8001, 0xFFFF],
+            ack: 298,
+            checksum: Some((296, 0x0123_4567_89AB_CDEF)),
+        };
+        assert_eq!(RollbackPacket::from_bytes(&packet.to_bytes()), Ok(packet.clone()));
+
+        let empty = RollbackPacket { inputs: Vec::new(), checksum: None, ..packet };
+        assert_eq!(RollbackPacket::from_bytes(&empty.to_bytes()), Ok(empty));
+
+        assert!(RollbackPacket::from_bytes(&[0; 5]).is_err());
+    }
+
+    #[test]
+    fn test_misprediction_resimulates_to_the_right_state() {
+        // A counter that adds V1 each frame key 5 is held
+        let rom = [
+            0x60, 0x05,  // 200: v0 = 5
+            0xE0, 0xA1,  // 202: skip if key v0 not pressed
+            0x71, 0x01,  // 204: v1 += 1
+            0xA3, 0x00,  // 206: i = 300
+            0xF1, 0x55,  // 208: save v0 - v1
+            0x12, 0x02,  // 20A: jump 202
+        ];
+        let mut console = Console::new();
+        console.load_rom(&rom).unwrap();
+        console.set_target_ips(60);  // One instruction a frame
+        let start = console.save_state();
+
+        let mut session = Rollback::new(0, 8);
+        for _ in 0..6 {
+            assert!(session.add_local_input(0));
+            assert!(session.advance(&mut console));
+        }
+        assert_eq!(session.confirmed_frame(), 0);
+
+        // The peer held key 5 from frame 1
+        let packet = RollbackPacket { frame: 0, inputs: vec![0, 1 << 5, 1 << 5], ack: 0, checksum: None };
+        session.receive_packet(packet);
+        session.reconcile(&mut console);
+        assert_eq!(session.rolled_back_frames(), 5);
+
+        let mut expected = Console::new();
+        expected.set_target_ips(60);
+        expected.load_state(&start);
+        for keys in [0, 1 << 5, 1 << 5, 1 << 5, 1 << 5, 1 << 5].iter() {
+            expected.step_frame(*keys);
+        }
+        assert_eq!(console.state_hash(), expected.state_hash());
+
+        // Stalls max_rollback frames past the last confirmed one
+        for _ in 0..5 {
+            session.add_local_input(0);
+            assert!(session.advance(&mut console));
+        }
+        session.add_local_input(0);
+        assert!(!session.advance(&mut console));
+    }
+}
@@ -0,0 +1,283 @@
+//! ROM metadata database
+//!
+//! Reads the JSON layout of the community chip-8-database: a map of
+//! SHA-1 hashes to indices into a list of programs, each program listing
+//! the ROM files it shipped as with their platform, quirks and settings.
+//! The bundled entries for the frontend's ROMs are copied from it (MIT),
+//! without descriptions and images
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::quirks::{QuirkProfile, Quirks};
+
+
+const BUNDLED_HASHES: &str = include_str!("../data/sha1-hashes.json");
+const BUNDLED_PROGRAMS: &str = include_str!("../data/programs.json");
+
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: BTreeMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+}
+
+
+// Per platform deviations, unset fields keep the platform's behaviour
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuirkOverrides {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+}
+
+
+impl QuirkOverrides {
+
+    pub fn apply(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift { quirks.shifting = shift; }
+        if let Some(by_x) = self.memory_increment_by_x {
+            quirks.memory |= by_x;
+            quirks.memory_by_x = by_x;
+        }
+        if let Some(leave) = self.memory_leave_i_unchanged { quirks.memory = !leave; }
+        if let Some(wrap) = self.wrap { quirks.clipping = !wrap; }
+        if let Some(jump) = self.jump { quirks.jumping = jump; }
+        if let Some(vblank) = self.vblank { quirks.display_wait = vblank; }
+        if let Some(logic) = self.logic { quirks.vf_reset = logic; }
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Colors {
+    #[serde(default)]
+    pub pixels: Vec<String>,  // Background first, then foreground
+    pub buzzer: Option<String>,
+    pub silence: Option<String>,
+}
+
+
+// Everything known about one ROM image
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RomInfo {
+    pub hash: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub platform: Option<String>,
+    pub tickrate: Option<u32>,     // Instructions per frame
+    pub colors: Option<Colors>,
+    pub keys: HashMap<String, u8>, // Action name to CHIP-8 key
+    #[serde(skip)]
+    pub quirk_overrides: QuirkOverrides,
+}
+
+
+impl RomInfo {
+
+    // Closest platform profile, None for platforms this emulator can't run
+    pub fn profile(&self) -> Option<QuirkProfile> {
+        match self.platform.as_deref()? {
+            "originalChip8" | "hybridVIP" | "modernChip8" => Some(QuirkProfile::Chip8),
+            "chip48" | "superchip1" | "superchip" => Some(QuirkProfile::SuperChip),
+            "xochip" => Some(QuirkProfile::XoChip),
+            "chip8x" => Some(QuirkProfile::Chip8X),
+            _ => None,
+        }
+    }
+
+    // Recommended quirks, None for platforms this emulator can't run
+    pub fn quirks(&self) -> Option<Quirks> {
+        let mut quirks = match self.platform.as_deref()? {
+            "originalChip8" | "hybridVIP" => Quirks::chip8(),
+            "modernChip8" => Quirks {
+                vf_reset: false,
+                display_wait: false,
+                ..Quirks::chip8()
+            },
+            "chip48" | "superchip1" | "superchip" => Quirks::schip(),
+            "xochip" => Quirks::xochip(),
+            "chip8x" => Quirks::chip8x(),
+            _ => return None,
+        };
+
+        self.quirk_overrides.apply(&mut quirks);
+        Some(quirks)
+    }
+}
+
+
+pub struct RomDatabase {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Program>,
+}
+
+
+impl RomDatabase {
+
+    // Parse the contents of sha1-hashes.json and programs.json
+    pub fn from_json(hashes: &str, programs: &str) -> Result<RomDatabase, serde_json::Error> {
+        Ok(RomDatabase {
+            hashes: serde_json::from_str(hashes)?,
+            programs: serde_json::from_str(programs)?,
+        })
+    }
+
+    // Entries for the ROMs shipped with the frontend
+    pub fn bundled() -> RomDatabase {
+        RomDatabase::from_json(BUNDLED_HASHES, BUNDLED_PROGRAMS)
+            .expect("bundled ROM database is valid")
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        self.lookup_hash(&sha1_hex(rom))
+    }
+
+    pub fn lookup_hash(&self, hash: &str) -> Option<RomInfo> {
+        let hash = hash.to_ascii_lowercase();
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let entry = program.roms.get(&hash).cloned().unwrap_or_default();
+        // ROMs that only run with quirks list their platforms there
+        let platform = entry.platforms.first()
+            .or_else(|| entry.quirky_platforms.keys().next())
+            .cloned();
+        let quirk_overrides = platform.as_ref()
+            .and_then(|p| entry.quirky_platforms.get(p).copied())
+            .unwrap_or_default();
+
+        Some(RomInfo {
+            hash,
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            platform,
+            tickrate: entry.tickrate,
+            colors: entry.colors,
+            keys: entry.keys,
+            quirk_overrides,
+        })
+    }
+}
+
+
+impl Default for RomDatabase {
+    fn default() -> RomDatabase {
+        RomDatabase::bundled()
+    }
+}
+
+
+// Lowercase hex SHA-1, the database key
+pub fn sha1_hex(rom: &[u8]) -> String {
+    Sha1::digest(rom).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_lookup() {
+        let db = RomDatabase::bundled();
+        let info = db.lookup_hash("A60611339661E3AB2D8AF024AD1DA5880A6F8665").unwrap();
+        assert_eq!(info.title, "Pong");
+        assert_eq!(info.keys.get("up"), Some(&1));
+        assert_eq!(info.quirks(), Some(Quirks::chip8()));
+        assert!(db.lookup(&[0x12, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_quirky_platform_overrides() {
+        let hashes = r#"{"da39a3ee5e6b4b0d3255bfef95601890afd80709": 0}"#;
+        let programs = r#"[{
+            "title": "Empty",
+            "authors": ["Nobody"],
+            "roms": {
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709": {
+                    "platforms": ["superchip"],
+                    "quirkyPlatforms": {"superchip": {"shift": false, "wrap": true, "memoryIncrementByX": true}},
+                    "tickrate": 30
+                }
+            }
+        }]"#;
+        let db = RomDatabase::from_json(hashes, programs).unwrap();
+        let info = db.lookup(&[]).unwrap();
+        let quirks = info.quirks().unwrap();
+        assert!(!quirks.shifting);
+        assert!(!quirks.clipping);
+        assert!(quirks.jumping);
+        assert!(quirks.memory && quirks.memory_by_x);
+        assert_eq!(info.tickrate, Some(30));
+        assert_eq!(info.authors, vec!["Nobody"]);
+    }
+
+    #[test]
+    fn test_database_entry_with_speed_and_colors() {
+        // Skyward's entry as chip-8-database has it
+        let hashes = r#"{"8ebf74e790e58a8d5a7beff598bb32ed7eeeabf7": 0}"#;
+        let programs = r##"[{
+            "title": "Skyward",
+            "authors": ["tann", "JackieKircher"],
+            "images": ["skyward.png"],
+            "release": "2016-11-01",
+            "description": "Climb as high as you can!",
+            "roms": {
+                "8ebf74e790e58a8d5a7beff598bb32ed7eeeabf7": {
+                    "file": "skyward.ch8",
+                    "platforms": [],
+                    "quirkyPlatforms": {"xochip": {"memoryLeaveIUnchanged": true}},
+                    "tickrate": 1000,
+                    "colors": {
+                        "pixels": ["#121212", "#4b636f", "#af2d3d", "#af2d3d"],
+                        "buzzer": "#000000",
+                        "silence": "#000000"
+                    }
+                }
+            },
+            "origin": {"type": "gamejam", "reference": "Octojam3"}
+        }]"##;
+        let db = RomDatabase::from_json(hashes, programs).unwrap();
+        let info = db.lookup_hash("8EBF74E790E58A8D5A7BEFF598BB32ED7EEEABF7").unwrap();
+        assert_eq!(info.profile(), Some(QuirkProfile::XoChip));
+        assert!(!info.quirks().unwrap().memory);
+        assert_eq!(info.tickrate, Some(1000));
+        assert_eq!(info.colors.unwrap().pixels[1], "#4b636f");
+    }
+
+    #[test]
+    fn test_bundled_entries_come_from_the_database() {
+        let db = RomDatabase::bundled();
+        let blinky = db.lookup_hash("d40abc54374e4343639f993e897e00904ddf85d9").unwrap();
+        assert_eq!(blinky.authors, vec!["Hans Christian Egeberg"]);
+        assert_eq!(blinky.profile(), Some(QuirkProfile::SuperChip));
+
+        let tetris = db.lookup_hash("5f518084744bf3cb8733f6e5454dfd1634320563").unwrap();
+        assert_eq!(tetris.authors, vec!["Fran Dachille"]);
+        assert_eq!(tetris.keys.get("left"), Some(&5));
+        assert_eq!(tetris.profile(), Some(QuirkProfile::SuperChip));
+    }
+}
@@ -0,0 +1,234 @@
+//! Rhai scripting
+//!
+//! ROM test scenarios written as Rhai scripts that drive a `Console`,
+//! built with the `scripting` feature and run with `chip8-script`:
+//!
+//! ```text
+//! press(0x1);
+//! frames(30);
+//! release_all();
+//! assert(peek(0x2F3) == 0, "no points yet");
+//! assert(pixel(0, 14), "left paddle drawn");
+//! ```
+//!
+//! Registers and memory: reg(x), set_reg(x, v), pc(), peek(addr), poke(addr, v)
+//! Keys: press(key), release(key), release_all()
+//! Time: frames(n), frame()
+//! Display: pixel(x, y), lit_pixels(), width(), height()
+//! Checks: assert(condition), assert(condition, message), status()
+//! Callbacks: on_frame(|frame| ..), on_pc(addr, |pc| ..), on_write(addr, |addr, value| ..)
+//!
+//! Callbacks sit on top of the console's hooks, the hooks collect events
+//! while a frame runs and the script's functions are called once it ends
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rhai::{Engine, EvalAltResult, FnPtr, NativeCallContext};
+
+use crate::machine::Console;
+
+
+type Shared<T> = Rc<RefCell<T>>;
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+
+// Script callbacks and the hook events waiting for them
+#[derive(Default)]
+struct Callbacks {
+    frame: Vec<FnPtr>,
+    pc: Vec<(u16, FnPtr)>,
+    write: Vec<(u16, FnPtr)>,
+    pcs: Vec<u16>,
+    writes: Vec<(u16, u8)>,
+}
+
+
+pub struct Script {
+    engine: Engine,
+    console: Shared<Console>,
+}
+
+
+impl Script {
+
+    pub fn new(console: Console) -> Script {
+        let console = Rc::new(RefCell::new(console));
+        let callbacks: Shared<Callbacks> = Default::default();
+        let mut engine = Engine::new();
+
+        let c = console.clone();
+        engine.register_fn("reg", move |x: i64| c.borrow().register(x as usize & 0xF) as i64);
+        let c = console.clone();
+        engine.register_fn("set_reg", move |x: i64, value: i64| {
+            c.borrow_mut().set_register(x as usize & 0xF, value as u8);
+        });
+        let c = console.clone();
+        engine.register_fn("pc", move || c.borrow().pc() as i64);
+        let c = console.clone();
+        engine.register_fn("peek", move |addr: i64| c.borrow().memread(addr as u16) as i64);
+        let c = console.clone();
+        engine.register_fn("poke", move |addr: i64, value: i64| {
+            c.borrow_mut().memwrite(addr as u16, value as u8);
+        });
+
+        let c = console.clone();
+        engine.register_fn("press", move |key: i64| c.borrow_mut().press_key(key as u8));
+        let c = console.clone();
+        engine.register_fn("release", move |key: i64| c.borrow_mut().release_key(key as u8));
+        let c = console.clone();
+        engine.register_fn("release_all", move || {
+            let mut console = c.borrow_mut();
+            (0..16).for_each(|key| console.release_key(key));
+        });
+
+        let c = console.clone();
+        engine.register_fn("pixel", move |x: i64, y: i64| {
+            let console = c.borrow();
+            let (width, height) = (console.width() as i64, console.height() as i64);
+            (0..width).contains(&x) && (0..height).contains(&y)
+                && console.vram()[(y * width + x) as usize] == 1
+        });
+        let c = console.clone();
+        engine.register_fn("lit_pixels", move || {
+            c.borrow().vram().iter().filter(|&&px| px == 1).count() as i64
+        });
+        let c = console.clone();
+        engine.register_fn("width", move || c.borrow().width() as i64);
+        let c = console.clone();
+        engine.register_fn("height", move || c.borrow().height() as i64);
+        let c = console.clone();
+        engine.register_fn("status", move || format!("{:?}", c.borrow().status()));
+
+        engine.register_fn("assert", |condition: bool| -> ScriptResult<()> {
+            if condition { Ok(()) } else { Err("assertion failed".into()) }
+        });
+        engine.register_fn("assert", |condition: bool, message: &str| -> ScriptResult<()> {
+            if condition { Ok(()) } else { Err(format!("assertion failed: {}", message).into()) }
+        });
+
+        let c = console.clone();
+        engine.register_fn("frame", move || c.borrow().frame() as i64);
+
+        let cb = callbacks.clone();
+        engine.register_fn("on_frame", move |callback: FnPtr| cb.borrow_mut().frame.push(callback));
+        let (c, cb) = (console.clone(), callbacks.clone());
+        engine.register_fn("on_pc", move |addr: i64, callback: FnPtr| {
+            let events = cb.clone();
+            c.borrow_mut().hooks().on_pc(addr as u16, move |ctx| events.borrow_mut().pcs.push(ctx.pc()));
+            cb.borrow_mut().pc.push((addr as u16, callback));
+        });
+        let (c, cb) = (console.clone(), callbacks.clone());
+        engine.register_fn("on_write", move |addr: i64, callback: FnPtr| {
+            let events = cb.clone();
+            c.borrow_mut().hooks().on_write(addr as u16, move |_, addr, value| {
+                events.borrow_mut().writes.push((addr, value));
+            });
+            cb.borrow_mut().write.push((addr as u16, callback));
+        });
+
+        let c = console.clone();
+        engine.register_fn("frames", move |context: NativeCallContext, count: i64| -> ScriptResult<()> {
+            for _ in 0..count {
+                let number = {
+                    let mut console = c.borrow_mut();
+                    console.advance_frame();
+                    console.frame() as i64 - 1
+                };
+                dispatch(&context, &callbacks, number)?;
+            }
+            Ok(())
+        });
+
+        Script { engine, console }
+    }
+
+    pub fn run(&self, source: &str) -> ScriptResult<()> {
+        self.engine.run(source)
+    }
+
+    pub fn console(&self) -> std::cell::Ref<'_, Console> {
+        self.console.borrow()
+    }
+}
+
+
+// Hand the frame's hook events to the script, no console borrow may be
+// held as the callbacks call back into it
+fn dispatch(context: &NativeCallContext, callbacks: &Shared<Callbacks>, frame: i64) -> ScriptResult<()> {
+    let (pcs, writes, on_pc, on_write, on_frame) = {
+        let mut callbacks = callbacks.borrow_mut();
+        let pcs = std::mem::take(&mut callbacks.pcs);
+        let writes = std::mem::take(&mut callbacks.writes);
+        (pcs, writes, callbacks.pc.clone(), callbacks.write.clone(), callbacks.frame.clone())
+    };
+
+    for pc in pcs {
+        for (_, callback) in on_pc.iter().filter(|(addr, _)| *addr == pc) {
+            callback.call_within_context::<()>(context, (pc as i64,))?;
+        }
+    }
+    for (addr, value) in writes {
+        for (_, callback) in on_write.iter().filter(|(watched, _)| *watched == addr) {
+            callback.call_within_context::<()>(context, (addr as i64, value as i64))?;
+        }
+    }
+    for callback in on_frame.iter() {
+        callback.call_within_context::<()>(context, (frame,))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(rom: &[u8]) -> Script {
+        let mut console = Console::new();
+        console.load_rom(rom).unwrap();
+        Script::new(console)
+    }
+
+    #[test]
+    fn test_script_drives_the_machine() {
+        let rom = [
+            0x60, 0x05,  // 200: v0 = 5
+            0xE0, 0x9E,  // 202: skip if key 5
+            0x12, 0x02,  // 204: jump 202
+            0xA3, 0x00,  // 206: i = 300
+            0x80, 0x0E,  // 208: v0 <<= 1
+            0xF0, 0x55,  // 20A: save v0
+            0xA3, 0x00,  // 20C: i = 300
+            0xD0, 0x01,  // 20E: draw the saved byte at (10, 10)
+            0x12, 0x10,  // 210: halt
+        ];
+        let script = script(&rom);
+        script.run(r#"
+            let writes = [];
+            on_write(0x300, |addr, value| writes.push(value));
+            frames(2);
+            assert(pc() == 0x202 || pc() == 0x204, "waiting for key 5");
+            assert(lit_pixels() == 0);
+
+            press(5);
+            frames(2);
+            assert(reg(0) == 10);
+            assert(peek(0x300) == 10, "saved");
+            assert(writes == [10], "hook saw the write");
+            assert(pixel(14, 10) && pixel(16, 10) && !pixel(15, 10) && !pixel(99, 99));
+            assert(lit_pixels() == 2);
+            assert(status() == "Halted");
+        "#).unwrap();
+
+        assert_eq!(script.console().memread(0x300), 10);
+    }
+
+    #[test]
+    fn test_failed_assertion_reports_the_message() {
+        let script = script(&[0x12, 0x00]);
+        let error = script.run(r#"frames(1); poke(0x400, 3); assert(peek(0x400) == 4, "lives")"#)
+            .unwrap_err();
+        assert!(error.to_string().contains("assertion failed: lives"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Speed governor, converts host time into emulated frames
+//!
+//! An emulated frame is 1/60th of a second of machine time: a batch of
+//! instructions followed by one delay/sound timer tick, so timers stay
+//! at 60 Hz relative to emulated time whatever the speed setting
+
+
+// Instructions per second of a typical CHIP-8 interpreter
+pub const DEFAULT_IPS: u32 = 700;
+
+pub const TIMER_HZ: f64 = 60.0;
+
+
+// Instructions in one 60Hz frame at ips, at least one
+pub fn instructions_per_frame(ips: u32) -> u32 {
+    ((ips as f64 / TIMER_HZ).round() as u32).max(1)
+}
+
+
+// Longest stretch of host time emulated in one update, anything beyond
+// is dropped so a slow host skips frames instead of spiralling behind
+const MAX_ELAPSED_MS: f64 = 100.0;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeedMode {
+    InstructionsPerSecond(u32),  // Real time at the given clock speed
+    Multiplier(f64),             // N x real time at the base clock
+    FrameAdvance,                // Paused, frames run one at a time
+}
+
+
+pub struct Governor {
+    mode: SpeedMode,
+    base_ips: u32,        // Clock speed the multiplier and frame advance run at
+    pending_ms: f64,      // Host time not yet turned into frames
+    skipped_frames: u32,  // Frames dropped because the host fell behind
+}
+
+
+impl Governor {
+
+    pub fn new() -> Governor {
+        Governor {
+            mode: SpeedMode::InstructionsPerSecond(DEFAULT_IPS),
+            base_ips: DEFAULT_IPS,
+            pending_ms: 0.0,
+            skipped_frames: 0,
+        }
+    }
+
+    pub fn mode(&self) -> SpeedMode {
+        self.mode
+    }
+
+    // A clock speed also becomes the base that later multipliers scale
+    pub fn set_mode(&mut self, mode: SpeedMode) {
+        if let SpeedMode::InstructionsPerSecond(ips) = mode {
+            self.base_ips = ips;
+        }
+        self.mode = mode;
+        self.pending_ms = 0.0;
+    }
+
+    // The program's own clock speed, e.g. a ROM's tick rate. Keeps any
+    // multiplier or frame advance the user picked
+    pub fn set_base_ips(&mut self, ips: u32) {
+        self.base_ips = ips;
+        if let SpeedMode::InstructionsPerSecond(_) = self.mode {
+            self.mode = SpeedMode::InstructionsPerSecond(ips);
+        }
+    }
+
+    pub fn base_ips(&self) -> u32 {
+        self.base_ips
+    }
+
+    // Instructions executed in each emulated frame
+    pub fn instructions_per_frame(&self) -> u32 {
+        let ips = match self.mode {
+            SpeedMode::InstructionsPerSecond(ips) => ips,
+            SpeedMode::Multiplier(_) | SpeedMode::FrameAdvance => self.base_ips,
+        };
+
+        instructions_per_frame(ips)
+    }
+
+    // Emulated frames per second of host time
+    fn frame_rate(&self) -> f64 {
+        match self.mode {
+            SpeedMode::InstructionsPerSecond(_) => TIMER_HZ,
+            SpeedMode::Multiplier(n) => TIMER_HZ * n.max(0.0),
+            SpeedMode::FrameAdvance => 0.0,
+        }
+    }
+
+    // Number of frames to emulate after elapsed_ms of host time
+    pub fn frames_due(&mut self, elapsed_ms: f64) -> u32 {
+        let rate = self.frame_rate();
+
+        if rate == 0.0 {
+            self.pending_ms = 0.0;
+            return 0;
+        }
+
+        let elapsed = elapsed_ms.max(0.0);
+        if elapsed > MAX_ELAPSED_MS {
+            let dropped = (elapsed - MAX_ELAPSED_MS) * rate / 1000.0;
+            self.skipped_frames += dropped as u32;
+        }
+
+        self.pending_ms += elapsed.min(MAX_ELAPSED_MS);
+        let frame_ms = 1000.0 / rate;
+        let frames = (self.pending_ms / frame_ms).floor();
+        self.pending_ms -= frames * frame_ms;
+        frames as u32
+    }
+
+    pub fn skipped_frames(&self) -> u32 {
+        self.skipped_frames
+    }
+
+    pub fn reset(&mut self) {
+        self.pending_ms = 0.0;
+        self.skipped_frames = 0;
+    }
+}
+
+
+impl Default for Governor {
+    fn default() -> Governor {
+        Governor::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_realtime_runs_sixty_frames_a_second() {
+        let mut governor = Governor::new();
+        let frames: u32 = (0..60).map(|_| governor.frames_due(1000.0 / 60.0)).sum();
+        assert!((59..=60).contains(&frames));
+        assert_eq!(governor.instructions_per_frame(), 12);
+    }
+
+    #[test]
+    fn test_multiplier_scales_frames_not_instructions() {
+        let mut governor = Governor::new();
+        governor.set_mode(SpeedMode::Multiplier(4.0));
+        assert_eq!(governor.frames_due(50.0), 12);
+        assert_eq!(governor.instructions_per_frame(), 12);
+    }
+
+    #[test]
+    fn test_multiplier_scales_the_base_clock() {
+        let mut governor = Governor::new();
+        governor.set_mode(SpeedMode::Multiplier(2.0));
+        governor.set_base_ips(1200);
+        assert_eq!(governor.mode(), SpeedMode::Multiplier(2.0));
+        assert_eq!(governor.instructions_per_frame(), 20);
+        assert_eq!(governor.frames_due(50.0), 6);
+
+        governor.set_mode(SpeedMode::InstructionsPerSecond(300));
+        governor.set_mode(SpeedMode::Multiplier(0.5));
+        assert_eq!(governor.instructions_per_frame(), 5);
+    }
+
+    #[test]
+    fn test_slow_host_skips_frames() {
+        let mut governor = Governor::new();
+        assert_eq!(governor.frames_due(1000.0), 6);
+        assert_eq!(governor.skipped_frames(), 54);
+    }
+
+    #[test]
+    fn test_frame_advance_never_runs_frames() {
+        let mut governor = Governor::new();
+        governor.set_mode(SpeedMode::FrameAdvance);
+        assert_eq!(governor.frames_due(1000.0), 0);
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(target_arch = "wasm32")]
 extern crate web_sys;
 
 
@@ -13,28 +14,50 @@ pub fn set_panic_hook() {
 }
 
 
+// Console logging is only available in the browser; native builds
+// (tests, headless runners) discard log output
+#[cfg(target_arch = "wasm32")]
+fn log(message: String) {
+    web_sys::console::log_1(&message.into())
+}
+
+
+#[cfg(not(target_arch = "wasm32"))]
+fn log(_message: String) {}
+
+
+// Per instruction logging, only compiled in with the `trace` feature so
+// the hot path stays free of formatting and allocation
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        $crate::utils::log_str(&format!($($arg)*));
+    };
+}
+
+
 pub fn log_str(message: &str) {
-    web_sys::console::log_1(&format!("{}", message).into())
+    log(message.to_string())
 }
 
 
 pub fn log_u8(message: u8) {
-    web_sys::console::log_1(&format!("{:0x}", message).into())
+    log(format!("{:0x}", message))
 }
 
 
 pub fn log_u16(message: u16) {
-    web_sys::console::log_1(&format!("{:0x}", message).into())
+    log(format!("{:0x}", message))
 }
 
 
 pub fn log_u8_array(message: &[u8]) {
-    web_sys::console::log_1(&format!("{:?}", message).into())
+    log(format!("{:?}", message))
 }
 
 
 pub fn log_u16_array(message: &[u16]) {
-    web_sys::console::log_1(&format!("{:?}", message).into())
+    log(format!("{:?}", message))
 }
 
 
@@ -45,9 +68,56 @@ pub fn log_u16_array(message: &[u16]) {
 // }
 
 
+#[cfg(target_arch = "wasm32")]
 pub fn get_random_u8() -> u8 {
     let mut rand_arry = [0u8; 128];
     let crypto = web_sys::window().unwrap().crypto().unwrap();
     crypto.get_random_values_with_u8_array(&mut rand_arry).unwrap();
     rand_arry[0]
 }
+
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_random_u8() -> u8 {
+    rand::random::<u8>()
+}
+
+
+pub const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+
+
+// 64 bit FNV-1a continuing from hash, the same on every host unlike
+// std's hasher. Start from FNV_OFFSET
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3))
+}
+
+
+// Seeded xorshift64* generator, for runs that must be reproducible
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeededRng { state: u64 }
+
+
+impl SeededRng {
+
+    pub fn new(seed: u64) -> SeededRng {
+        // Zero is xorshift's fixed point
+        SeededRng { state: seed ^ 0x9E37_79B9_7F4A_7C15 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,83 @@
+//! Gym style environment driving the bundled PONG ROM
+
+use chip_8_wasm::env::{Env, EnvConfig, Task};
+use chip_8_wasm::error::RomError;
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::quirks::{QuirkProfile, Quirks};
+
+
+const PONG: &[u8] = include_bytes!("../frontend/roms/PONG");
+
+
+fn pong_env() -> Env {
+    let task = Task::for_rom(PONG).expect("PONG has a built in task");
+    Env::new(PONG, task, EnvConfig { seed: 7, ..EnvConfig::default() }).unwrap()
+}
+
+
+#[test]
+fn test_pong_rewards_points_until_game_over() {
+    let config = EnvConfig { seed: 7, ..EnvConfig::default() };
+    let mut env = Env::new(PONG, Task::pong(3), config).unwrap();
+    let observation = env.reset();
+    assert_eq!(observation.len(), 64 * 32);
+
+    let mut total = 0.0;
+    let mut done = false;
+    while !done && env.frame() < 60 * 60 {
+        let (_, reward, finished) = env.step(&[]);
+        total += reward;
+        done = finished;
+    }
+
+    // Both paddles stand still, with this seed the ball keeps slipping
+    // past the right one
+    assert!(done);
+    assert_eq!(total, 3.0);
+    assert!(env.step(&[]).2);
+}
+
+
+#[test]
+fn test_cloned_envs_replay_identically() {
+    let mut env = pong_env();
+    env.reset();
+    for _ in 0..50 {
+        env.step(&[1]);
+    }
+
+    let mut branch = env.clone();
+    for step in 0..200 {
+        let keys = if step % 3 == 0 { vec![4] } else { vec![1] };
+        assert_eq!(env.step(&keys), branch.step(&keys));
+    }
+}
+
+
+#[test]
+fn test_roms_load_like_the_console() {
+    assert_eq!(EnvConfig::default().instructions_per_frame, Console::new().instructions_per_frame());
+
+    assert_eq!(
+        Env::new(&[0; 3585], Task::none(), EnvConfig::default()).err(),
+        Some(RomError::TooLarge { size: 3585, max: 3584 })
+    );
+
+    // CHIP-8X programs start at 0x300
+    let config = EnvConfig {
+        profile: QuirkProfile::Chip8X,
+        quirks: Quirks::chip8(),
+        ..EnvConfig::default()
+    };
+    let mut env = Env::new(&[0x13, 0x00], Task::none(), config).unwrap();
+    env.reset();
+    assert!(env.step(&[]).2, "the self jump at 0x300 halts");
+
+    // The HIRES patch gets the 64x64 display
+    let mut rom = vec![0; 0xC2];
+    rom[..2].copy_from_slice(&[0x12, 0x60]);
+    rom[0xC0..].copy_from_slice(&[0x12, 0xC0]);
+    let mut env = Env::new(&rom, Task::none(), EnvConfig::default()).unwrap();
+    assert_eq!(env.reset().len(), 64 * 64);
+    assert!(env.step(&[]).2);
+}
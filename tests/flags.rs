@@ -0,0 +1,102 @@
+//! Headless ROM checks modelled on Timendus' chip8-test-suite flags test:
+//! the ROM draws a pass glyph when every flag matches and a fail glyph
+//! otherwise, and the screen is compared for each quirk profile
+
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::quirks::QuirkProfile;
+
+const PASS_GLYPH: u16 = 0xA;
+const FAIL_GLYPH: u16 = 0xE;
+
+// The VIP font's A
+const PASS_SCREEN: [&str; 5] = [
+    "####",
+    "#..#",
+    "####",
+    "#..#",
+    "#..#",
+];
+
+const PROFILES: [QuirkProfile; 3] = [
+    QuirkProfile::Chip8,
+    QuirkProfile::SuperChip,
+    QuirkProfile::XoChip,
+];
+
+// (setup, operation, expected VF)
+const CHECKS: [([u16; 2], u16, u8); 8] = [
+    ([0x60FF, 0x6101], 0x8014, 1),  // ADD carry
+    ([0x6010, 0x6101], 0x8014, 0),  // ADD no carry
+    ([0x6005, 0x6106], 0x8015, 0),  // SUB borrow
+    ([0x6006, 0x6105], 0x8015, 1),  // SUB no borrow
+    ([0x6006, 0x6105], 0x8017, 0),  // SUBN borrow
+    ([0x6005, 0x6106], 0x8017, 1),  // SUBN no borrow
+    ([0x6003, 0x6100], 0x8006, 1),  // SHR lsb
+    ([0x6081, 0x6100], 0x800E, 1),  // SHL msb
+];
+
+
+// Emit code that draws a font glyph at (0, 0) and halts
+fn draw_glyph(program: &mut Vec<u16>, glyph: u16) {
+    let halt = 0x200 + (program.len() as u16 + 4) * 2;
+    program.extend_from_slice(&[0x6000 | glyph, 0xF029, 0x6000, 0xD005, 0x1000 | halt]);
+}
+
+
+fn flags_rom() -> Vec<u8> {
+    let mut program = Vec::new();
+    let mut fail_jumps = Vec::new();
+
+    for (setup, op, vf) in CHECKS.iter() {
+        program.extend_from_slice(setup);
+        program.push(*op);
+        program.push(0x3F00 | *vf as u16);
+        fail_jumps.push(program.len());
+        program.push(0x1000);
+    }
+
+    draw_glyph(&mut program, PASS_GLYPH);
+
+    let fail = 0x200 + program.len() as u16 * 2;
+    for idx in fail_jumps {
+        program[idx] |= fail;
+    }
+    draw_glyph(&mut program, FAIL_GLYPH);
+
+    program.iter().flat_map(|op| op.to_be_bytes().to_vec()).collect()
+}
+
+
+// The screen with a glyph drawn at (0, 0), spelt out rather than
+// rendered so a broken font or DXYN shows up as a mismatch
+fn expected_screen(glyph: [&str; 5]) -> Vec<u8> {
+    let mut vram = vec![0; 64 * 32];
+    for (y, row) in glyph.iter().enumerate() {
+        for (x, pixel) in row.chars().enumerate() {
+            vram[y * 64 + x] = (pixel == '#') as u8;
+        }
+    }
+    vram
+}
+
+
+#[test]
+fn test_flags_rom_passes_on_every_profile() {
+    let rom = flags_rom();
+
+    for profile in PROFILES.iter() {
+        let mut console = Console::new();
+        console.set_quirk_profile(*profile);
+        console.load_rom(&rom).unwrap();
+
+        for _ in 0..200 {
+            console.tick();
+        }
+
+        assert_eq!(
+            console.vram(),
+            &expected_screen(PASS_SCREEN)[..],
+            "flags failed for {:?}", profile
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! A debugger session with the GDB stub over TCP
+
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use chip_8_wasm::gdb::{self, GdbStub};
+use chip_8_wasm::machine::Console;
+
+
+struct Debugger {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+
+impl Debugger {
+
+    fn connect(address: &str) -> Debugger {
+        let writer = TcpStream::connect(address).unwrap();
+        writer.set_nodelay(true).unwrap();
+        Debugger { reader: BufReader::new(writer.try_clone().unwrap()), writer }
+    }
+
+    // Send a packet and return the stub's reply, acknowledging it
+    fn send(&mut self, packet: &str) -> String {
+        gdb::write_packet(&mut self.writer, packet).unwrap();
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        let mut acks = Vec::new();
+        let reply = gdb::read_packet(&mut self.reader, &mut acks).unwrap().unwrap();
+        self.writer.write_all(&acks).unwrap();
+        reply
+    }
+}
+
+
+#[test]
+fn test_debugger_session() {
+    let rom = [
+        0x60, 0x00,  // 200: v0 = 0
+        0x70, 0x01,  // 202: v0 += 1
+        0x30, 0x05,  // 204: skip if v0 == 5
+        0x12, 0x02,  // 206: jump 202
+        0x71, 0x01,  // 208: v1 += 1
+        0x12, 0x08,  // 20A: jump 208, forever
+    ];
+    let mut console = Console::new();
+    console.load_rom(&rom).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let debugger = thread::spawn(move || debug(&address));
+
+    let mut stub = GdbStub::new(console);
+    let (stream, _) = listener.accept().unwrap();
+    gdb::serve(stream, &mut stub).unwrap();
+    debugger.join().unwrap();
+
+    assert_eq!(stub.console().cpu().register(0), 5);
+    assert!(stub.console().cpu().register(1) > 0);
+    assert_eq!(stub.console().memread(0x300), 0xBE);
+}
+
+
+fn debug(address: &str) {
+    let mut debugger = Debugger::connect(address);
+    assert!(debugger.send("qSupported:multiprocess+").contains("qXfer:features:read+"));
+    assert!(debugger.send("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    assert_eq!(debugger.send("?"), "S05");
+
+    // Stop on the loop's third pass
+    assert_eq!(debugger.send("Z0,204,2"), "OK");
+    for count in 1..=3 {
+        assert_eq!(debugger.send("c"), "S05");
+        assert_eq!(debugger.send("p0"), format!("{:02x}", count));
+    }
+    assert_eq!(debugger.send("p11"), "0402");
+    assert_eq!(debugger.send("z0,204,2"), "OK");
+
+    // Cut the loop short, then step out of it
+    assert_eq!(debugger.send("P0=04"), "OK");
+    for _ in 0..4 {
+        assert_eq!(debugger.send("s"), "S05");
+    }
+    assert_eq!(debugger.send("p11"), "0802");
+
+    assert_eq!(debugger.send("M300,2:beef"), "OK");
+    assert_eq!(debugger.send("m300,2"), "beef");
+
+    // The last loop never ends, only Ctrl-C stops it
+    gdb::write_packet(&mut debugger.writer, "c").unwrap();
+    debugger.writer.write_all(&[0x03]).unwrap();
+    assert_eq!(debugger.reply(), "S02");
+
+    assert_eq!(debugger.send("D"), "OK");
+}
@@ -0,0 +1,36 @@
+//! Hooks registered on a running Console
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use chip_8_wasm::machine::Console;
+
+
+#[test]
+fn test_frame_hooks_count_every_frame() {
+    let mut console = Console::new();
+    console.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+    // Frames run before any hook is registered still count
+    for _ in 0..5 {
+        console.advance_frame();
+    }
+    console.step_frame(0);
+    assert_eq!(console.frame(), 6);
+
+    let frames = Rc::new(RefCell::new(Vec::new()));
+    let f = frames.clone();
+    console.hooks().on_frame(move |_, frame| f.borrow_mut().push(frame));
+    console.advance_frame();
+    console.advance_frame();
+    assert_eq!(*frames.borrow(), vec![6, 7]);
+
+    let state = console.save_state();
+    console.advance_frame();
+    console.load_state(&state);
+    assert_eq!(console.frame(), 8);
+
+    console.load_rom(&[0x12, 0x00]).unwrap();
+    console.advance_frame();
+    assert_eq!(frames.borrow().last(), Some(&0));
+}
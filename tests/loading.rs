@@ -0,0 +1,215 @@
+//! ROM loading through the public Console API
+
+use chip_8_wasm::cpu::MachineStatus;
+use chip_8_wasm::error::{FontError, RomError};
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::quirks::QuirkProfile;
+use chip_8_wasm::romdb::sha1_hex;
+use chip_8_wasm::speed::SpeedMode;
+
+
+#[test]
+fn test_rejects_rom_larger_than_platform() {
+    let mut console = Console::new();
+    assert_eq!(console.max_rom_size(), 3584);
+    assert!(console.load_rom(&[0; 3584]).is_ok());
+    assert_eq!(
+        console.load_rom(&[0; 3585]),
+        Err(RomError::TooLarge { size: 3585, max: 3584 })
+    );
+
+    console.set_quirk_profile(QuirkProfile::XoChip);
+    assert!(console.load_rom(&[0; 3585]).is_ok());
+}
+
+
+#[test]
+fn test_rom_checked_against_its_database_platform() {
+    let small = [0u8; 3585];
+    let mut large = vec![0u8; 3600];
+    large[0] = 1;
+    let hashes = format!(r#"{{"{}": 0, "{}": 1}}"#, sha1_hex(&small), sha1_hex(&large));
+    let programs = format!(r#"[
+        {{"title": "Small", "roms": {{"{}": {{"platforms": ["originalChip8"], "tickrate": 30}}}}}},
+        {{"title": "Large", "roms": {{"{}": {{"platforms": ["xochip"]}}}}}}
+    ]"#, sha1_hex(&small), sha1_hex(&large));
+
+    // Too large for the CHIP-8 the database asks for, nothing changes
+    let mut console = Console::new();
+    console.load_rom_database(&hashes, &programs).unwrap();
+    console.set_quirk_profile(QuirkProfile::XoChip);
+    let speed = console.speed_mode();
+    assert_eq!(
+        console.load_rom(&small),
+        Err(RomError::TooLarge { size: 3585, max: 3584 })
+    );
+    assert_eq!(console.memory_size(), 65536);
+    assert_eq!(console.speed_mode(), speed);
+    assert!(console.rom_info().is_none());
+
+    // Fits the XO-CHIP it is meant for, whatever the current platform
+    console.set_quirk_profile(QuirkProfile::Chip8);
+    assert!(console.load_rom(&large).is_ok());
+    assert_eq!(console.memory_size(), 65536);
+    assert_eq!(console.speed_mode(), speed);
+}
+
+
+#[test]
+fn test_unknown_rom_gets_the_user_settings() {
+    let known = [0x12, 0x00];
+    let hashes = format!(r#"{{"{}": 0}}"#, sha1_hex(&known));
+    let programs = format!(
+        r#"[{{"title": "Known", "roms": {{"{}": {{"platforms": ["xochip"], "tickrate": 100}}}}}}]"#,
+        sha1_hex(&known)
+    );
+
+    let mut console = Console::new();
+    console.load_rom_database(&hashes, &programs).unwrap();
+    console.set_target_ips(900);
+    console.set_clipping(false);
+    let quirks = console.cpu().quirks();
+
+    console.load_rom(&known).unwrap();
+    assert_eq!(console.memory_size(), 65536);
+    assert_eq!(console.speed_mode(), SpeedMode::InstructionsPerSecond(6000));
+
+    // Nothing of the XO-CHIP ROM's setup carries over
+    console.load_rom(&[0x12, 0x02]).unwrap();
+    assert_eq!(console.memory_size(), 4096);
+    assert_eq!(console.max_rom_size(), 3584);
+    assert_eq!(console.cpu().quirks(), quirks);
+    assert!(!console.cpu().quirks().clipping);
+    assert_eq!(console.speed_mode(), SpeedMode::InstructionsPerSecond(900));
+}
+
+
+#[test]
+fn test_new_rom_replaces_leftovers() {
+    let mut console = Console::new();
+    console.load_rom(&[0x60, 0x01, 0x61, 0x02]).unwrap();
+    console.load_rom(&[0x62, 0x03]).unwrap();
+
+    // The second ROM ends at 0x202, the old 6102 must be gone
+    assert_eq!((console.memread(0x202), console.memread(0x203)), (0, 0));
+    console.tick();
+    console.tick();
+    assert_eq!(console.register(1), 0);
+}
+
+
+#[test]
+fn test_custom_load_address() {
+    let mut console = Console::new();
+    console.set_load_address(0x600);
+    let loaded = console.load_rom(&[0x16, 0x00]).unwrap();
+
+    assert_eq!(loaded.load_address(), 0x600);
+    assert_eq!(loaded.size(), 2);
+    assert_eq!(loaded.hash().len(), 40);
+    assert_eq!(console.max_rom_size(), 4096 - 0x600);
+
+    console.tick();
+    assert_eq!(console.status(), MachineStatus::Halted);
+}
+
+
+#[test]
+fn test_custom_font_survives_rom_load() {
+    let mut console = Console::new();
+    let mut font = [0u8; 80];
+    font[0] = 0xFF;  // Glyph 0's top row is a full byte wide
+    console.load_font(&font).unwrap();
+    console.set_font_address(0x050).unwrap();
+    assert!(console.set_font_address(0x1000).is_err());
+    assert!(console.set_font_address(0x1B1).is_err());
+    assert_eq!(
+        console.set_font_address(0x300),
+        Err(FontError::OverlapsProgram { addr: 0x300, load_address: 0x200 }),
+    );
+    console.set_font_address(0x1B0).unwrap();
+    console.set_font_address(0x050).unwrap();
+    assert!(console.load_font(&[0; 79]).is_err());
+
+    // V0 = 0x10, I = sprite 0, draw one row, halt
+    console.load_rom(&[0x60, 0x10, 0xF0, 0x29, 0xD1, 0x11, 0x12, 0x06]).unwrap();
+    for _ in 0..4 {
+        console.tick();
+    }
+
+    assert_eq!(console.font_address(), 0x050);
+    assert_eq!(&console.vram()[..8], &[1; 8]);
+    assert_eq!(console.vram()[8], 0);
+}
+
+
+#[test]
+fn test_hires_patch_selects_64x64() {
+    let mut console = Console::new();
+    assert_eq!((console.width(), console.height()), (64, 32));
+
+    // 1260 then the patch area, the program proper starts at 0x2C0:
+    // V0 = 40, I = font 0, draw at (0, 40), halt
+    let mut rom = vec![0x12, 0x60];
+    rom.resize(0xC0, 0);
+    rom.extend_from_slice(&[0x60, 0x28, 0xA0, 0x00, 0xD1, 0x05, 0x12, 0xC6]);
+    console.load_rom(&rom).unwrap();
+    assert_eq!((console.width(), console.height()), (64, 64));
+
+    for _ in 0..4 {
+        console.tick();
+    }
+    assert_eq!(console.status(), MachineStatus::Halted);
+    assert_eq!(console.vram().len(), 64 * 64);
+    assert_eq!(console.vram()[40 * 64], 1);
+
+    // A plain ROM goes back to the platform's resolution
+    console.load_rom(&[0x12, 0x00]).unwrap();
+    assert_eq!((console.width(), console.height()), (64, 32));
+}
+
+
+#[test]
+fn test_hires_patch_clears_with_0230() {
+    // Draw font 0 at (0, 40), clear with 0230, halt
+    let program = [0x60, 0x28, 0xA0, 0x00, 0xD1, 0x05, 0x02, 0x30, 0x12, 0xC8];
+    let mut rom = vec![0x12, 0x60];
+    rom.resize(0xC0, 0);
+    rom.extend_from_slice(&program);
+
+    let mut console = Console::new();
+    console.load_rom(&rom).unwrap();
+    for _ in 0..3 {
+        console.tick();
+    }
+    assert_eq!(console.vram()[40 * 64], 1);
+
+    console.tick();
+    console.tick();
+    assert_eq!(console.status(), MachineStatus::Halted);
+    assert!(console.vram().iter().all(|&px| px == 0));
+
+    // Without the patch 0230 is a machine code call and skipped, the
+    // same program, drawing at (0, 0), keeps its sprite
+    let mut console = Console::new();
+    console.load_rom(&[0x60, 0x00, 0xA0, 0x00, 0xD0, 0x05, 0x02, 0x30, 0x12, 0x08]).unwrap();
+    for _ in 0..5 {
+        console.tick();
+    }
+    assert_eq!(console.status(), MachineStatus::Halted);
+    assert_eq!(console.vram()[0], 1);
+}
+
+
+#[test]
+fn test_chip10_display() {
+    let mut console = Console::new();
+    console.set_quirk_profile(QuirkProfile::Chip10);
+    console.load_rom(&[0x60, 0x78, 0xD0, 0x15, 0x12, 0x04]).unwrap();
+    assert_eq!((console.width(), console.height()), (128, 64));
+
+    for _ in 0..3 {
+        console.tick();
+    }
+    assert_eq!(&console.vram()[120..124], &[1, 1, 1, 1]);
+}
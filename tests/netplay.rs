@@ -0,0 +1,143 @@
+//! Two lockstep peers playing PONG through the relay, native peers over
+//! TCP and browser ones over WebSocket
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::netplay::relay::websocket;
+use chip_8_wasm::netplay::{self, relay, InputPacket, Lockstep, DEFAULT_INPUT_DELAY};
+
+
+const PONG: &[u8] = include_bytes!("../frontend/roms/PONG");
+
+const FRAMES: u32 = 600;
+
+
+// Left paddle on keys 1 and 4, right paddle on C and D
+fn keys_for(player: usize, frame: u32) -> u16 {
+    let (up, down) = if player == 0 { (0x1, 0x4) } else { (0xC, 0xD) };
+    match (frame / 40 + player as u32) % 3 {
+        0 => 1 << up,
+        1 => 1 << down,
+        _ => 0,
+    }
+}
+
+
+// How a peer talks to the relay
+#[derive(Clone, Copy)]
+enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+
+fn connect(transport: Transport, address: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+    if let Transport::WebSocket = transport {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            address, key
+        ).unwrap();
+
+        // Byte at a time, the peer's packets may follow right behind
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        let accept = format!("Sec-WebSocket-Accept: {}\r\n", websocket::accept_key(key));
+        assert!(response.contains(&accept), "{}", response);
+    }
+    stream
+}
+
+
+fn send(transport: Transport, stream: &mut TcpStream, packet: &InputPacket) {
+    match transport {
+        Transport::Tcp => netplay::write_packet(stream, packet).unwrap(),
+        Transport::WebSocket => websocket::write_message(stream, &packet.to_bytes(), Some([7, 1, 9, 3])).unwrap(),
+    }
+}
+
+
+fn receive(transport: Transport, stream: &mut TcpStream) -> InputPacket {
+    match transport {
+        Transport::Tcp => netplay::read_packet(stream).unwrap(),
+        Transport::WebSocket => {
+            let bytes = websocket::read_message(stream).unwrap().unwrap();
+            InputPacket::from_bytes(&bytes).unwrap()
+        }
+    }
+}
+
+
+fn play(player: usize, transport: Transport, address: String) -> (u64, Option<u32>) {
+    let mut stream = connect(transport, &address);
+    let mut console = Console::new();
+    console.load_rom(PONG).unwrap();
+    console.seed_rng(42);
+
+    let mut session = Lockstep::new(DEFAULT_INPUT_DELAY);
+    let mut scheduled = DEFAULT_INPUT_DELAY;
+    while session.frame() < FRAMES {
+        if let Some(packet) = session.add_local_input(keys_for(player, scheduled)) {
+            send(transport, &mut stream, &packet);
+            scheduled += 1;
+        }
+        if !session.advance(&mut console) {
+            session.receive_packet(receive(transport, &mut stream));
+        }
+    }
+
+    // Closing with packets unread resets the connection, which can lose
+    // ours on the way to the peer
+    if let Transport::WebSocket = transport {
+        websocket::write_close(&mut stream).unwrap();
+    }
+    stream.shutdown(Shutdown::Write).unwrap();
+    io::copy(&mut stream, &mut io::sink()).unwrap();
+
+    (console.state_hash(), session.desync_frame())
+}
+
+
+fn play_pair(transports: [Transport; 2]) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || relay::serve(&listener));
+
+    let peers: Vec<_> = (0..2)
+        .map(|player| {
+            let address = address.clone();
+            let transport = transports[player];
+            thread::spawn(move || play(player, transport, address))
+        })
+        .collect();
+    let results: Vec<_> = peers.into_iter().map(|peer| peer.join().unwrap()).collect();
+
+    assert_eq!(results[0].1, None);
+    assert_eq!(results[1].1, None);
+    assert_eq!(results[0].0, results[1].0);
+}
+
+
+#[test]
+fn test_peers_stay_in_sync_through_relay() {
+    play_pair([Transport::Tcp, Transport::Tcp]);
+}
+
+
+#[test]
+fn test_browser_peers_play_over_websocket() {
+    play_pair([Transport::WebSocket, Transport::WebSocket]);
+    play_pair([Transport::WebSocket, Transport::Tcp]);
+}
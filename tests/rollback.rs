@@ -0,0 +1,143 @@
+//! Rollback sessions playing PONG over a fake network that delays,
+//! reorders and drops packets
+
+use std::collections::VecDeque;
+
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::rollback::{Rollback, DEFAULT_MAX_ROLLBACK};
+use chip_8_wasm::utils::SeededRng;
+
+
+const PONG: &[u8] = include_bytes!("../frontend/roms/PONG");
+
+const FRAMES: u32 = 600;
+const INPUT_DELAY: u32 = 1;
+const SEED: u64 = 42;
+
+
+// One direction of an unreliable link, time is counted in frames
+struct FakeLink {
+    latency: u32,
+    jitter: u32,
+    loss: f64,
+    rng: SeededRng,
+    in_flight: VecDeque<(u32, Vec<u8>)>,
+}
+
+
+impl FakeLink {
+
+    fn new(latency: u32, jitter: u32, loss: f64, seed: u64) -> FakeLink {
+        FakeLink { latency, jitter, loss, rng: SeededRng::new(seed), in_flight: VecDeque::new() }
+    }
+
+    fn send(&mut self, now: u32, packet: Vec<u8>) {
+        if self.rng.next_f64() < self.loss {
+            return;
+        }
+        let jitter = (self.rng.next_u64() % (self.jitter as u64 + 1)) as u32;
+        self.in_flight.push_back((now + self.latency + jitter, packet));
+    }
+
+    // Packets due by now, in whatever order jitter left them
+    fn deliver(&mut self, now: u32) -> Vec<Vec<u8>> {
+        let (due, later) = self.in_flight.drain(..).partition(|&(at, _)| at <= now);
+        self.in_flight = later;
+        due.into_iter().map(|(_, packet)| packet).collect()
+    }
+}
+
+
+// Left paddle on keys 1 and 4, right paddle on C and D
+fn keys_for(player: usize, frame: u32) -> u16 {
+    let (up, down) = if player == 0 { (0x1, 0x4) } else { (0xC, 0xD) };
+    match (frame / 25 + player as u32) % 3 {
+        0 => 1 << up,
+        1 => 1 << down,
+        _ => 0,
+    }
+}
+
+
+fn pong() -> Console {
+    let mut console = Console::new();
+    console.load_rom(PONG).unwrap();
+    console.seed_rng(SEED);
+    console
+}
+
+
+// The same game on one machine with nobody guessing
+fn expected_hash() -> u64 {
+    let mut console = pong();
+    for frame in 0..FRAMES {
+        let keys = if frame < INPUT_DELAY { 0 } else { keys_for(0, frame) | keys_for(1, frame) };
+        console.step_frame(keys);
+    }
+    console.state_hash()
+}
+
+
+fn play(links: &mut [FakeLink; 2]) -> [(u64, Rollback); 2] {
+    let mut consoles = [pong(), pong()];
+    let mut sessions = [
+        Rollback::new(INPUT_DELAY, DEFAULT_MAX_ROLLBACK),
+        Rollback::new(INPUT_DELAY, DEFAULT_MAX_ROLLBACK),
+    ];
+    let mut scheduled = [INPUT_DELAY; 2];
+
+    for now in 0.. {
+        for player in 0..2 {
+            for packet in links[1 - player].deliver(now) {
+                sessions[player].receive(&packet).unwrap();
+            }
+
+            let session = &mut sessions[player];
+            if session.frame() < FRAMES {
+                if session.add_local_input(keys_for(player, scheduled[player])) {
+                    scheduled[player] += 1;
+                }
+                session.advance(&mut consoles[player]);
+            } else {
+                session.reconcile(&mut consoles[player]);
+            }
+
+            links[player].send(now, session.packet());
+        }
+
+        let finished = sessions.iter()
+            .all(|s| s.frame() == FRAMES && s.confirmed_frame() >= FRAMES);
+        if finished || now > FRAMES * 4 {
+            break;
+        }
+    }
+
+    let [first, second] = sessions;
+    [(consoles[0].state_hash(), first), (consoles[1].state_hash(), second)]
+}
+
+
+#[test]
+fn test_rollback_matches_a_local_game_despite_latency_and_loss() {
+    let mut links = [FakeLink::new(3, 2, 0.2, 1), FakeLink::new(3, 2, 0.2, 2)];
+    let expected = expected_hash();
+
+    for (hash, session) in play(&mut links).iter() {
+        assert!(session.confirmed_frame() >= FRAMES);
+        assert_eq!(*hash, expected);
+        assert_eq!(session.desync_frame(), None);
+        assert!(session.rolled_back_frames() > 0);
+    }
+}
+
+
+#[test]
+fn test_perfect_link_never_rolls_back() {
+    let mut links = [FakeLink::new(0, 0, 0.0, 1), FakeLink::new(0, 0, 0.0, 2)];
+    let expected = expected_hash();
+
+    for (hash, session) in play(&mut links).iter() {
+        assert_eq!(*hash, expected);
+        assert_eq!(session.rolled_back_frames(), 0);
+    }
+}
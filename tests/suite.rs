@@ -0,0 +1,141 @@
+//! Timendus' chip8-test-suite ROMs run headless on each quirk profile.
+//! Every ROM draws a checkmark or a cross per check, the final screen is
+//! compared with a pass screen in tests/roms/screens taken from a
+//! reference interpreter, never from this one.
+//!
+//! The ROMs and screens live in tests/roms, see the README there. Until
+//! they're in place these tests are ignored, run them with
+//! `cargo test --test suite -- --ignored`
+
+use std::fs;
+use std::path::PathBuf;
+
+use chip_8_wasm::cpu::MachineStatus;
+use chip_8_wasm::machine::Console;
+use chip_8_wasm::quirks::QuirkProfile;
+
+
+const PROFILES: [(QuirkProfile, &str, u8); 3] = [
+    (QuirkProfile::Chip8, "chip8", 1),
+    (QuirkProfile::SuperChip, "schip", 2),
+    (QuirkProfile::XoChip, "xochip", 3),
+];
+
+// The suite reads its menu choice from here when it's set, so no keys
+// need pressing to pick a platform or keypad test
+const MENU_CHOICE: u16 = 0x1FF;
+
+// Long enough for every test to finish drawing its results
+const MAX_FRAMES: u32 = 600;
+
+
+fn roms_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms")
+}
+
+
+fn load(rom: &str, profile: QuirkProfile) -> Console {
+    let path = roms_dir().join(format!("{}.ch8", rom));
+    let bytes = fs::read(&path)
+        .unwrap_or_else(|e| panic!("{}: {}, see tests/roms/README.md", path.display(), e));
+
+    let mut console = Console::new();
+    console.set_auto_configure(false);
+    console.set_quirk_profile(profile);
+    console.load_rom(&bytes).unwrap();
+    console
+}
+
+
+// Run until the ROM stops, or up to frames
+fn run(console: &mut Console, frames: u32) {
+    for _ in 0..frames {
+        console.advance_frame();
+        if let MachineStatus::Halted | MachineStatus::Errored = console.status() {
+            break;
+        }
+    }
+}
+
+
+// One line per row, "." for unlit pixels and the colour otherwise
+fn screen(console: &Console) -> String {
+    console.vram()
+        .chunks(console.width())
+        .map(|row| {
+            let mut line: String = row.iter()
+                .map(|&px| if px == 0 { '.' } else { std::char::from_digit(px as u32, 16).unwrap_or('#') })
+                .collect();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+
+// Compare with the reference pass screen
+fn assert_pass_screen(console: &Console, name: &str) {
+    assert_ne!(console.status(), MachineStatus::Errored, "{} stopped: {:?}", name, console.error_message());
+
+    let path = roms_dir().join("screens").join(format!("{}.txt", name));
+    let actual = screen(console);
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {}, see tests/roms/README.md", path.display(), e));
+    assert!(actual == expected, "{} doesn't match its pass screen:\n{}", name, actual);
+}
+
+
+fn check_each_profile(rom: &str) {
+    for (profile, name, _) in PROFILES.iter() {
+        let mut console = load(rom, *profile);
+        run(&mut console, MAX_FRAMES);
+        assert_pass_screen(&console, &format!("{}-{}", rom, name));
+    }
+}
+
+
+#[test]
+#[ignore = "needs the suite ROMs in tests/roms"]
+fn test_corax_plus() {
+    check_each_profile("3-corax+");
+}
+
+
+#[test]
+#[ignore = "needs the suite ROMs in tests/roms"]
+fn test_flags() {
+    check_each_profile("4-flags");
+}
+
+
+#[test]
+#[ignore = "needs the suite ROMs in tests/roms"]
+fn test_quirks() {
+    for (profile, name, choice) in PROFILES.iter() {
+        let mut console = load("5-quirks", *profile);
+        console.memwrite(MENU_CHOICE, *choice);
+        run(&mut console, MAX_FRAMES);
+        assert_pass_screen(&console, &format!("5-quirks-{}", name));
+    }
+}
+
+
+// FX0A should take the key on release, not on press
+#[test]
+#[ignore = "needs the suite ROMs in tests/roms"]
+fn test_keypad_fx0a() {
+    const FX0A_TEST: u8 = 3;
+
+    for (profile, name, _) in PROFILES.iter() {
+        let mut console = load("6-keypad", *profile);
+        console.memwrite(MENU_CHOICE, FX0A_TEST);
+        run(&mut console, 60);
+        assert_eq!(console.status(), MachineStatus::WaitingForKey, "{}", name);
+
+        console.press_key(0x5);
+        run(&mut console, 10);
+        console.release_key(0x5);
+        run(&mut console, MAX_FRAMES);
+        assert_pass_screen(&console, &format!("6-keypad-{}", name));
+    }
+}